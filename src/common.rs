@@ -1,15 +1,19 @@
 use std::cmp::min;
+use std::collections::HashMap;
 use std::ffi::{CStr, CString, OsString};
 use std::fs::{read_to_string, OpenOptions};
-use std::io::Write;
+use std::io::{self, Write};
 use std::mem::MaybeUninit;
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::ExitStatusExt;
 use std::path::{Path, PathBuf};
-use std::process::{Command, ExitStatus, Stdio};
+use std::process::ExitStatus;
+use std::sync::Mutex;
+use std::time::Duration;
 
-use log::{debug, error, trace, warn};
+use lazy_static::lazy_static;
 
-use regex::Regex;
+use log::{debug, error, trace, warn};
 
 pub(crate) mod stage2_config;
 
@@ -24,16 +28,23 @@ pub mod error;
 pub use error::{Error, ErrorKind, Result, ToError};
 
 pub mod options;
-use crate::common::defs::{OLD_ROOT_MP, PIDOF_CMD, WHEREIS_CMD};
+use crate::common::defs::{
+    DEFAULT_CALL_TIMEOUT, OLD_ROOT_MP, PIDOF_CMD, TAKEOVER_EXTRA_PATH_ENV, WHEREIS_CMD,
+};
 
+use nix::sys::statvfs::statvfs;
 use nix::unistd::sync;
 pub use options::Options;
 
+pub(crate) mod command_runner;
+use command_runner::run_command;
 pub(crate) mod debug;
+pub(crate) mod device_lock;
 pub(crate) mod disk_util;
+pub(crate) mod image_writer;
+pub(crate) mod partition;
 pub(crate) mod stream_progress;
 
-const OS_NAME_REGEX: &str = r#"^PRETTY_NAME="([^"]+)"$"#;
 const OS_RELEASE_FILE: &str = "/etc/os-release";
 
 #[derive(Debug)]
@@ -43,15 +54,54 @@ pub(crate) struct CmdRes {
     pub status: ExitStatus,
 }
 
+impl CmdRes {
+    /// The signal number if the process was terminated by a signal
+    pub fn killed_by_signal(&self) -> Option<i32> {
+        self.status.signal()
+    }
+
+    /// Describe how the process terminated, eg. 'exit code 1' or 'killed by signal 9'
+    pub fn exit_info(&self) -> String {
+        if let Some(signal) = self.killed_by_signal() {
+            format!("killed by signal {}", signal)
+        } else if let Some(code) = self.status.code() {
+            format!("exit code {}", code)
+        } else {
+            String::from("unknown exit status")
+        }
+    }
+}
+
 pub(crate) fn call(cmd: &str, args: &[&str], trim_stdout: bool) -> Result<CmdRes> {
     trace!("call: '{}' called with {:?}, {}", cmd, args, trim_stdout);
+    call_with_timeout(cmd, args, trim_stdout, None)
+}
+
+/// Like call but kill the command and fail with ErrorKind::ExecProcess if it does not
+/// terminate within timeout
+pub(crate) fn call_timeout(
+    cmd: &str,
+    args: &[&str],
+    trim_stdout: bool,
+    timeout: Duration,
+) -> Result<CmdRes> {
+    trace!(
+        "call_timeout: '{}' called with {:?}, {}, {:?}",
+        cmd,
+        args,
+        trim_stdout,
+        timeout
+    );
+    call_with_timeout(cmd, args, trim_stdout, Some(timeout))
+}
 
-    match Command::new(cmd)
-        .args(args)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .output()
-    {
+fn call_with_timeout(
+    cmd: &str,
+    args: &[&str],
+    trim_stdout: bool,
+    timeout: Option<Duration>,
+) -> Result<CmdRes> {
+    match run_command(cmd, args, timeout) {
         Ok(output) => {
             debug!("call: output: {:?}", output);
             Ok(CmdRes {
@@ -64,6 +114,16 @@ pub(crate) fn call(cmd: &str, args: &[&str], trim_stdout: bool) -> Result<CmdRes
                 status: output.status,
             })
         }
+        Err(why) if why.kind() == io::ErrorKind::TimedOut => Err(Error::with_context(
+            ErrorKind::ExecProcess,
+            &format!(
+                "call: command {} '{:?}' timed out after {} seconds: {}",
+                cmd,
+                args,
+                timeout.unwrap_or_default().as_secs(),
+                why
+            ),
+        )),
         Err(why) => {
             error!("call: output failed for command: '{}': {:?}", cmd, why);
             Err(Error::with_context(
@@ -74,19 +134,97 @@ pub(crate) fn call(cmd: &str, args: &[&str], trim_stdout: bool) -> Result<CmdRes
     }
 }
 
+/// Resolve cmd to a path, caching results for the lifetime of the process.
+/// Cached entries are discarded if the file no longer exists.
 pub(crate) fn whereis(cmd: &str) -> Result<String> {
+    lazy_static! {
+        static ref WHEREIS_CACHE: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+    }
+
+    if let Ok(mut cache) = WHEREIS_CACHE.lock() {
+        if let Some(path) = cache.get(cmd) {
+            if is_executable(path) {
+                trace!("whereis: using cached path '{}' for '{}'", path, cmd);
+                return Ok(path.clone());
+            }
+            debug!("whereis: cached path '{}' for '{}' is gone", path, cmd);
+            cache.remove(cmd);
+        }
+    }
+
+    let path = whereis_uncached(cmd)?;
+    if let Ok(mut cache) = WHEREIS_CACHE.lock() {
+        cache.insert(cmd.to_string(), path.clone());
+    }
+    Ok(path)
+}
+
+/// Build the list of directories to search for executables, the extra directories come first,
+/// followed by the directories in PATH or the usual suspects if PATH is empty
+fn bin_dirs<'a>(extra_path: &'a str, env_path: &'a str) -> Vec<&'a str> {
     const BIN_DIRS: &[&str] = &["./", "/bin", "/usr/bin", "/sbin", "/usr/sbin"];
-    // try manually first
-    for path in BIN_DIRS {
+    let mut bin_dirs: Vec<&str> = extra_path
+        .split(':')
+        .filter(|dir| !dir.is_empty())
+        .collect();
+    if env_path.is_empty() {
+        bin_dirs.extend_from_slice(BIN_DIRS);
+    } else {
+        bin_dirs.extend(env_path.split(':').filter(|dir| !dir.is_empty()));
+    }
+    bin_dirs
+}
+
+/// Scan the bin directories for cmd, returns None if no file of that name was found at all
+fn search_bin_dirs(cmd: &str) -> Result<Option<String>> {
+    let extra_path = std::env::var(TAKEOVER_EXTRA_PATH_ENV).unwrap_or_default();
+    let env_path = std::env::var("PATH").unwrap_or_default();
+    let bin_dirs = bin_dirs(&extra_path, &env_path);
+
+    let mut not_executable = false;
+    for path in bin_dirs {
         let path = format!("{}/{}", &path, cmd);
         if file_exists(&path) {
-            return Ok(path);
+            if is_executable(&path) {
+                return Ok(Some(path));
+            }
+            debug!("whereis: ignoring non executable file '{}'", path);
+            not_executable = true;
         }
     }
 
+    if not_executable {
+        return Err(Error::with_context(
+            ErrorKind::NotFound,
+            &format!("whereis: no executable found for command: '{}'", cmd),
+        ));
+    }
+
+    Ok(None)
+}
+
+/// Like whereis_uncached but only scans the bin directories and never executes the external
+/// whereis command, for systems where whereis is known to be missing
+#[allow(dead_code)]
+pub(crate) fn whereis_no_fallback(cmd: &str) -> Result<String> {
+    if let Some(path) = search_bin_dirs(cmd)? {
+        Ok(path)
+    } else {
+        Err(Error::with_context(
+            ErrorKind::NotFound,
+            &format!("whereis: command not found: '{}'", cmd),
+        ))
+    }
+}
+
+pub(crate) fn whereis_uncached(cmd: &str) -> Result<String> {
+    if let Some(path) = search_bin_dirs(cmd)? {
+        return Ok(path);
+    }
+
     // else try whereis command
     let args: [&str; 2] = ["-b", cmd];
-    let cmd_res = match call(WHEREIS_CMD, &args, true) {
+    let cmd_res = match call_timeout(WHEREIS_CMD, &args, true, DEFAULT_CALL_TIMEOUT) {
         Ok(cmd_res) => cmd_res,
         Err(why) => {
             // manually try the usual suspects
@@ -123,7 +261,7 @@ pub(crate) fn whereis(cmd: &str) -> Result<String> {
             &format!(
                 "whereis: command failed for {}: {}",
                 cmd,
-                cmd_res.status.code().unwrap_or(0)
+                cmd_res.exit_info()
             ),
         ))
     }
@@ -155,34 +293,145 @@ pub(crate) fn get_mem_info() -> Result<(u64, u64)> {
     }
 }
 
-/******************************************************************
- * Get OS name from /etc/os-release
- ******************************************************************/
+const PROC_MEMINFO: &str = "/proc/meminfo";
 
-pub(crate) fn get_os_name() -> Result<String> {
-    trace!("get_os_name: entered");
+/// Parse the MemAvailable line of meminfo, the value is given in kB
+fn parse_mem_available(meminfo: &str) -> Option<u64> {
+    meminfo.lines().find_map(|line| {
+        let value = line.strip_prefix("MemAvailable:")?;
+        let kbytes = value.trim().trim_end_matches("kB").trim();
+        kbytes.parse::<u64>().ok().map(|kbytes| kbytes * 1024)
+    })
+}
 
-    // TODO: implement other source as fallback
+/// Get the memory in bytes available for new allocations without swapping.
+/// Unlike free memory this includes reclaimable page cache.
+pub(crate) fn available_memory() -> Result<u64> {
+    trace!("available_memory: entered");
+    let meminfo = read_to_string(PROC_MEMINFO)
+        .upstream_with_context(&format!("Failed to read '{}'", PROC_MEMINFO))?;
+    parse_mem_available(&meminfo).ok_or_else(|| {
+        Error::with_context(
+            ErrorKind::NotFound,
+            &format!("No MemAvailable entry found in '{}'", PROC_MEMINFO),
+        )
+    })
+}
+
+/// Get the free space in bytes available to unprivileged users on the file system containing path
+pub(crate) fn available_space<P: AsRef<Path>>(path: P) -> Result<u64> {
+    let path = path.as_ref();
+    trace!("available_space: entered with '{}'", path.display());
+    let fs_stat = statvfs(path).upstream_with_context(&format!(
+        "Failed to retrieve file system statistics for '{}'",
+        path.display()
+    ))?;
+    Ok(fs_stat.blocks_available() as u64 * fs_stat.fragment_size() as u64)
+}
 
-    if file_exists(OS_RELEASE_FILE) {
-        // TODO: ensure availabilty of method / file exists
-        if let Some(os_name) = parse_file(OS_RELEASE_FILE, &Regex::new(OS_NAME_REGEX).unwrap())? {
-            Ok(os_name[1].clone())
+/// The identification of the running OS from /etc/os-release
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct OsRelease {
+    pub id: String,
+    pub id_like: Vec<String>,
+    pub name: Option<String>,
+    pub version_id: Option<String>,
+    pub version_codename: Option<String>,
+    pub pretty_name: Option<String>,
+}
+
+/// Strip the shell style quotes and escapes from an os-release value
+fn unquote_os_release_value(value: &str) -> String {
+    let value = value.trim();
+    let quote = match value.chars().next() {
+        Some(quote @ '"') | Some(quote @ '\'') => Some(quote),
+        _ => None,
+    };
+    let inner = match quote {
+        Some(quote) if value.len() > 1 && value.ends_with(quote) => &value[1..value.len() - 1],
+        Some(_) => &value[1..],
+        None => value,
+    };
+
+    if quote == Some('\'') {
+        return String::from(inner);
+    }
+    let mut unquoted = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(escaped) = chars.next() {
+                unquoted.push(escaped);
+            }
         } else {
-            Err(Error::with_context(
-                ErrorKind::NotFound,
-                &format!(
-                    "get_os_name: could not be located in file {}",
-                    OS_RELEASE_FILE
-                ),
-            ))
+            unquoted.push(c);
         }
-    } else {
-        Err(Error::with_context(
+    }
+    unquoted
+}
+
+/// Parse the content of an os-release file, unknown keys, comments and malformed lines are
+/// ignored and the id defaults to 'linux' as specified in os-release(5)
+pub(crate) fn parse_os_release(os_release: &str) -> OsRelease {
+    let mut release = OsRelease {
+        id: String::from("linux"),
+        ..OsRelease::default()
+    };
+    for line in os_release.lines().map(|line| line.trim()) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = match line.find('=') {
+            Some(idx) => (&line[..idx], unquote_os_release_value(&line[idx + 1..])),
+            None => {
+                debug!("parse_os_release: ignoring malformed line '{}'", line);
+                continue;
+            }
+        };
+        match key {
+            "ID" => release.id = value,
+            "ID_LIKE" => release.id_like = value.split_whitespace().map(String::from).collect(),
+            "NAME" => release.name = Some(value),
+            "VERSION_ID" => release.version_id = Some(value),
+            "VERSION_CODENAME" => release.version_codename = Some(value),
+            "PRETTY_NAME" => release.pretty_name = Some(value),
+            _ => (),
+        }
+    }
+    release
+}
+
+/// Read and parse /etc/os-release, fails with ErrorKind::NotFound if the file does not exist
+pub(crate) fn read_os_release() -> Result<OsRelease> {
+    trace!("read_os_release: entered");
+    if !file_exists(OS_RELEASE_FILE) {
+        return Err(Error::with_context(
             ErrorKind::NotFound,
-            &format!("get_os_name: could not locate file {}", OS_RELEASE_FILE),
-        ))
+            &format!("read_os_release: could not locate file {}", OS_RELEASE_FILE),
+        ));
     }
+    let os_release = read_to_string(OS_RELEASE_FILE)
+        .upstream_with_context(&format!("Failed to read '{}'", OS_RELEASE_FILE))?;
+    let release = parse_os_release(&os_release);
+    debug!("read_os_release: {:?}", release);
+    Ok(release)
+}
+
+/// Get the OS name, the PRETTY_NAME in /etc/os-release
+pub(crate) fn get_os_name() -> Result<String> {
+    trace!("get_os_name: entered");
+
+    // TODO: implement other source as fallback
+
+    read_os_release()?.pretty_name.ok_or_else(|| {
+        Error::with_context(
+            ErrorKind::NotFound,
+            &format!(
+                "get_os_name: could not be located in file {}",
+                OS_RELEASE_FILE
+            ),
+        )
+    })
 }
 
 pub(crate) fn is_admin() -> Result<bool> {
@@ -195,6 +444,15 @@ pub fn file_exists<P: AsRef<Path>>(file: P) -> bool {
     file.as_ref().exists()
 }
 
+/// Check that path is a file (following symlinks) with at least one execute bit set
+pub fn is_executable<P: AsRef<Path>>(path: P) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    match path.as_ref().metadata() {
+        Ok(metadata) => metadata.is_file() && (metadata.permissions().mode() & 0o111) != 0,
+        Err(_) => false,
+    }
+}
+
 pub fn dir_exists<P: AsRef<Path>>(name: P) -> Result<bool> {
     match stat(name) {
         Ok(stat_info) => Ok(is_dir(&stat_info)),
@@ -208,30 +466,6 @@ pub fn dir_exists<P: AsRef<Path>>(name: P) -> Result<bool> {
     }
 }
 
-pub(crate) fn parse_file<P: AsRef<Path>>(fname: P, regex: &Regex) -> Result<Option<Vec<String>>> {
-    let path = fname.as_ref();
-    let os_info =
-        read_to_string(path).upstream_with_context(&format!("File read '{}'", path.display()))?;
-
-    for line in os_info.lines() {
-        debug!("parse_file: line: '{}'", line);
-
-        if let Some(ref captures) = regex.captures(line) {
-            let mut results: Vec<String> = Vec::new();
-            for cap in captures.iter() {
-                if let Some(cap) = cap {
-                    results.push(String::from(cap.as_str()));
-                } else {
-                    results.push(String::from(""));
-                }
-            }
-            return Ok(Some(results));
-        };
-    }
-
-    Ok(None)
-}
-
 const GIB_SIZE: u64 = 1024 * 1024 * 1024;
 const MIB_SIZE: u64 = 1024 * 1024;
 const KIB_SIZE: u64 = 1024;
@@ -394,10 +628,66 @@ pub(crate) fn log(text: &str) {
 #[cfg(test)]
 mod tests {
     use super::*;
+    #[test]
+    fn test_parse_mem_available() {
+        const MEMINFO: &str = "MemTotal:         501212 kB\n\
+                               MemFree:           21844 kB\n\
+                               MemAvailable:     310548 kB\n\
+                               Buffers:           12840 kB\n";
+        assert_eq!(parse_mem_available(MEMINFO), Some(310548 * 1024));
+        assert_eq!(parse_mem_available("MemTotal: 501212 kB\n"), None);
+    }
+
+    #[test]
+    fn test_parse_os_release() {
+        const OS_RELEASE: &str = "PRETTY_NAME=\"Raspbian GNU/Linux 10 (buster)\"\n\
+                                  NAME='Raspbian GNU/Linux'\n\
+                                  # comment\n\
+                                  VERSION_ID=\"10\"\n\
+                                  VERSION_CODENAME=buster\n\
+                                  ID=raspbian\n\
+                                  ID_LIKE=\"debian ubuntu\"\n\
+                                  HOME_URL=\"http://www.raspbian.org/\"\n";
+        let release = parse_os_release(OS_RELEASE);
+        assert_eq!(release.id, "raspbian");
+        assert_eq!(release.id_like, vec!["debian", "ubuntu"]);
+        assert_eq!(release.name.as_deref(), Some("Raspbian GNU/Linux"));
+        assert_eq!(release.version_id.as_deref(), Some("10"));
+        assert_eq!(release.version_codename.as_deref(), Some("buster"));
+        assert_eq!(
+            release.pretty_name.as_deref(),
+            Some("Raspbian GNU/Linux 10 (buster)")
+        );
+
+        let release = parse_os_release("NAME=\"Say \\\"hi\\\"\"\nbroken line\n");
+        assert_eq!(release.id, "linux");
+        assert_eq!(release.name.as_deref(), Some("Say \"hi\""));
+        assert_eq!(release.version_id, None);
+    }
+
     #[test]
     fn test_path_to_cstring() {
         const PATH: &str = "/bla/blub";
         let c_path = path_to_cstring(PATH).unwrap();
         assert_eq!(&*c_path.to_string_lossy(), PATH);
     }
+
+    #[test]
+    fn test_bin_dirs() {
+        assert_eq!(bin_dirs("", "/usr/bin:/bin"), vec!["/usr/bin", "/bin"]);
+        assert_eq!(
+            bin_dirs("/opt/bin::/opt/sbin", "/usr/bin"),
+            vec!["/opt/bin", "/opt/sbin", "/usr/bin"]
+        );
+        assert_eq!(
+            bin_dirs("/opt/bin", ""),
+            vec!["/opt/bin", "./", "/bin", "/usr/bin", "/sbin", "/usr/sbin"]
+        );
+    }
+
+    #[test]
+    fn test_whereis_no_fallback_not_found() {
+        let err = whereis_no_fallback("no-such-command-for-takeover").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
 }
@@ -0,0 +1,286 @@
+use std::cell::RefCell;
+use std::io::{self, Read};
+use std::process::{Child, Command, Output, Stdio};
+use std::rc::Rc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+// how long to wait for a killed child to terminate before leaving it to a reaper thread
+const KILL_WAIT: Duration = Duration::from_secs(1);
+
+/// Executes external commands on behalf of call(), replaceable for tests
+pub(crate) trait CommandRunner {
+    /// Run cmd and collect its output. If timeout is given and expires before cmd
+    /// terminates, cmd is killed and an error of kind io::ErrorKind::TimedOut is returned
+    fn run(&self, cmd: &str, args: &[&str], timeout: Option<Duration>) -> io::Result<Output>;
+}
+
+/// Runs commands on the host system
+pub(crate) struct SystemRunner;
+
+/// Read the pipe in a separate thread so the child can not block on a full pipe
+fn read_pipe<R: Read + Send + 'static>(pipe: Option<R>) -> JoinHandle<io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut buffer = Vec::new();
+        if let Some(mut pipe) = pipe {
+            pipe.read_to_end(&mut buffer)?;
+        }
+        Ok(buffer)
+    })
+}
+
+/// Kill child and reap it. A child in uninterruptible sleep can not be reaped before it wakes
+/// up, it is waited for in a detached thread then instead of blocking the caller
+fn kill_child(mut child: Child) {
+    let _res = child.kill();
+    let deadline = Instant::now() + KILL_WAIT;
+    while Instant::now() < deadline {
+        match child.try_wait() {
+            Ok(None) => thread::sleep(POLL_INTERVAL),
+            _ => return,
+        }
+    }
+    thread::spawn(move || {
+        let _res = child.wait();
+    });
+}
+
+fn join_pipe(reader: JoinHandle<io::Result<Vec<u8>>>) -> io::Result<Vec<u8>> {
+    reader
+        .join()
+        .map_err(|_| io::Error::other("pipe reader thread panicked"))?
+}
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, cmd: &str, args: &[&str], timeout: Option<Duration>) -> io::Result<Output> {
+        let mut command = Command::new(cmd);
+        command
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        let timeout = if let Some(timeout) = timeout {
+            timeout
+        } else {
+            return command.output();
+        };
+
+        let mut child = command.spawn()?;
+        let stdout_reader = read_pipe(child.stdout.take());
+        let stderr_reader = read_pipe(child.stderr.take());
+
+        let deadline = Instant::now() + timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+
+            if Instant::now() >= deadline {
+                kill_child(child);
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "command '{}' did not terminate within {} seconds",
+                        cmd,
+                        timeout.as_secs_f32()
+                    ),
+                ));
+            }
+            thread::sleep(POLL_INTERVAL);
+        };
+
+        Ok(Output {
+            status,
+            stdout: join_pipe(stdout_reader)?,
+            stderr: join_pipe(stderr_reader)?,
+        })
+    }
+}
+
+thread_local! {
+    static RUNNER: RefCell<Rc<dyn CommandRunner>> = RefCell::new(Rc::new(SystemRunner));
+}
+
+/// Run cmd using the command runner installed for the current thread
+pub(crate) fn run_command(
+    cmd: &str,
+    args: &[&str],
+    timeout: Option<Duration>,
+) -> io::Result<Output> {
+    let runner = RUNNER.with(|runner| runner.borrow().clone());
+    runner.run(cmd, args, timeout)
+}
+
+/// Execute f with runner installed as command runner for the current thread, the previous
+/// runner is restored afterwards
+#[allow(dead_code)]
+pub(crate) fn with_runner<T, F: FnOnce() -> T>(runner: Rc<dyn CommandRunner>, f: F) -> T {
+    struct Restore(Option<Rc<dyn CommandRunner>>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            if let Some(runner) = self.0.take() {
+                RUNNER.with(|current| *current.borrow_mut() = runner);
+            }
+        }
+    }
+
+    let _restore = Restore(Some(RUNNER.with(|current| current.replace(runner))));
+    f()
+}
+
+#[cfg(test)]
+pub(crate) mod mock {
+    use super::CommandRunner;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::io;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::{ExitStatus, Output};
+    use std::time::Duration;
+
+    /// Returns canned output for known commands and records all invocations
+    #[derive(Default)]
+    pub(crate) struct MockRunner {
+        responses: HashMap<String, (String, String, i32)>,
+        hanging: Vec<String>,
+        calls: RefCell<Vec<Vec<String>>>,
+    }
+
+    impl MockRunner {
+        pub fn respond(mut self, cmd: &str, stdout: &str, stderr: &str, exit_code: i32) -> Self {
+            self.responses.insert(
+                cmd.to_string(),
+                (stdout.to_string(), stderr.to_string(), exit_code),
+            );
+            self
+        }
+
+        /// Simulate cmd not terminating, it times out if a timeout is given
+        pub fn hang(mut self, cmd: &str) -> Self {
+            self.hanging.push(cmd.to_string());
+            self
+        }
+
+        pub fn calls(&self) -> Vec<Vec<String>> {
+            self.calls.borrow().clone()
+        }
+    }
+
+    impl CommandRunner for MockRunner {
+        fn run(&self, cmd: &str, args: &[&str], timeout: Option<Duration>) -> io::Result<Output> {
+            let mut call = vec![cmd.to_string()];
+            call.extend(args.iter().map(|arg| arg.to_string()));
+            self.calls.borrow_mut().push(call);
+
+            if self.hanging.iter().any(|hanging| hanging == cmd) {
+                assert!(
+                    timeout.is_some(),
+                    "MockRunner: '{}' would hang forever",
+                    cmd
+                );
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("MockRunner: '{}' timed out", cmd),
+                ));
+            }
+
+            match self.responses.get(cmd) {
+                Some((stdout, stderr, exit_code)) => Ok(Output {
+                    status: ExitStatus::from_raw(exit_code << 8),
+                    stdout: stdout.as_bytes().to_vec(),
+                    stderr: stderr.as_bytes().to_vec(),
+                }),
+                None => Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("MockRunner: no response for command '{}'", cmd),
+                )),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::MockRunner;
+    use super::{with_runner, CommandRunner, SystemRunner};
+    use crate::common::{call, call_timeout, pidof, whereis_uncached, ErrorKind};
+    use std::rc::Rc;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn call_uses_installed_runner() {
+        let runner =
+            Rc::new(MockRunner::default().respond("mokutil", "SecureBoot disabled\n", "", 0));
+        let cmd_res =
+            with_runner(runner.clone(), || call("mokutil", &["--sb-state"], true)).unwrap();
+        assert!(cmd_res.status.success());
+        assert_eq!(cmd_res.stdout, "SecureBoot disabled");
+        assert_eq!(
+            runner.calls(),
+            vec![vec![String::from("mokutil"), String::from("--sb-state")]]
+        );
+    }
+
+    #[test]
+    fn call_reports_exit_code() {
+        let runner = Rc::new(MockRunner::default().respond("false", "", "failed", 1));
+        let cmd_res = with_runner(runner, || call("false", &[], true)).unwrap();
+        assert!(!cmd_res.status.success());
+        assert_eq!(cmd_res.exit_info(), "exit code 1");
+        assert_eq!(cmd_res.stderr, "failed");
+    }
+
+    #[test]
+    fn call_timeout_reports_hung_command() {
+        let runner = Rc::new(MockRunner::default().hang("mokutil"));
+        let err = with_runner(runner, || {
+            call_timeout("mokutil", &["--sb-state"], true, Duration::from_secs(1))
+        })
+        .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ExecProcess);
+        assert!(err.to_string().contains("timed out after 1 seconds"));
+    }
+
+    #[test]
+    fn system_runner_kills_on_timeout() {
+        let start = Instant::now();
+        let err = SystemRunner
+            .run("sleep", &["10"], Some(Duration::from_millis(100)))
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn system_runner_collects_output_with_timeout() {
+        let output = SystemRunner
+            .run("echo", &["hello"], Some(Duration::from_secs(10)))
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"hello\n");
+    }
+
+    #[test]
+    fn pidof_parses_pids() {
+        let runner = Rc::new(MockRunner::default().respond("pidof", "1234 56\n", "", 0));
+        assert_eq!(
+            with_runner(runner, || pidof("init")).unwrap(),
+            vec![1234, 56]
+        );
+    }
+
+    #[test]
+    fn whereis_falls_back_to_command() {
+        let runner = Rc::new(MockRunner::default().respond(
+            "whereis",
+            "no-such-tool: /opt/tools/no-such-tool\n",
+            "",
+            0,
+        ));
+        assert_eq!(
+            with_runner(runner, || whereis_uncached("no-such-tool")).unwrap(),
+            "/opt/tools/no-such-tool"
+        );
+    }
+}
@@ -1,4 +1,10 @@
+use std::time::Duration;
+
+// upper limit for external commands that are not expected to take long, eg. mokutil
+pub(crate) const DEFAULT_CALL_TIMEOUT: Duration = Duration::from_secs(30);
+
 pub(crate) const SWAPOFF_CMD: &str = "swapoff";
+pub(crate) const SWAPON_CMD: &str = "swapon";
 pub(crate) const TELINIT_CMD: &str = "telinit";
 
 pub(crate) const MOKUTIL_CMD: &str = "mokutil";
@@ -7,12 +13,16 @@ pub(crate) const PIDOF_CMD: &str = "pidof";
 pub(crate) const PIVOT_ROOT_CMD: &str = "pivot_root";
 pub(crate) const MOUNT_CMD: &str = "mount";
 pub(crate) const BLKID_CMD: &str = "blkid";
+pub(crate) const MODPROBE_CMD: &str = "modprobe";
 
 pub(crate) const EFIBOOTMGR_CMD: &str = "efibootmgr";
 pub(crate) const DD_CMD: &str = "dd";
 
 pub(crate) const TAR_CMD: &str = "tar";
 
+// colon separated list of directories searched for executables before PATH
+pub(crate) const TAKEOVER_EXTRA_PATH_ENV: &str = "TAKEOVER_EXTRA_PATH";
+
 pub(crate) const TAKEOVER_DIR: &str = "/balena-takeover";
 pub(crate) const STAGE2_CONFIG_NAME: &str = "stage2-config.yml";
 
@@ -22,6 +32,8 @@ pub(crate) const BALENA_IMAGE_PATH: &str = "/balena.img.gz";
 pub(crate) const BALENA_CONFIG_PATH: &str = "/config.json";
 
 pub const DISK_BY_LABEL_PATH: &str = "/dev/disk/by-label";
+pub const DISK_BY_UUID_PATH: &str = "/dev/disk/by-uuid";
+pub const DISK_BY_PARTUUID_PATH: &str = "/dev/disk/by-partuuid";
 
 pub const BALENA_BOOT_PART: &str = "resin-boot";
 pub const BALENA_BOOT_FSTYPE: &str = "vfat";
@@ -35,8 +47,17 @@ pub const BALENA_PART_MP: &str = "/mnt/balena-part";
 
 pub const SYSTEM_CONNECTIONS_DIR: &str = "system-connections";
 
+pub const SYS_CLASS_BLOCK: &str = "/sys/class/block";
+
+pub const SYSTEMD_RUN_DIR: &str = "/run/systemd/system";
+pub const OPENRC_BIN: &str = "/sbin/openrc";
+pub const PROC_INIT_COMM: &str = "/proc/1/comm";
+pub const PROC_INIT_CMDLINE: &str = "/proc/1/cmdline";
+
 pub const SYS_EFI_DIR: &str = "/sys/firmware/efi";
 pub const SYS_EFIVARS_DIR: &str = "/sys/firmware/efi/efivars";
+pub const EFI_SECURE_BOOT_VAR: &str = "SecureBoot-8be4df61-93ca-11d2-aa0d-00e098032b8c";
+pub const EFI_SETUP_MODE_VAR: &str = "SetupMode-8be4df61-93ca-11d2-aa0d-00e098032b8c";
 
 pub const BACKUP_ARCH_NAME: &str = "backup.tgz";
 
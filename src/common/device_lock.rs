@@ -0,0 +1,78 @@
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use log::{debug, trace};
+use nix::errno::Errno;
+use nix::fcntl::{flock, FlockArg};
+
+use crate::common::{Error, ErrorKind, Result, ToError};
+
+/// Exclusive advisory lock on a block device, held until dropped.
+/// Guards against concurrent takeover invocations flashing the same device.
+#[derive(Debug)]
+pub(crate) struct DeviceLock {
+    device: PathBuf,
+    // open file description the lock is attached to, closing it releases the lock
+    _file: File,
+}
+
+impl DeviceLock {
+    /// Acquire the lock without blocking, fails with ErrorKind::InvState if it is held elsewhere
+    pub fn acquire(device: &Path) -> Result<DeviceLock> {
+        trace!("DeviceLock::acquire: entered with '{}'", device.display());
+        let file = File::open(device).upstream_with_context(&format!(
+            "Failed to open device '{}' for locking",
+            device.display()
+        ))?;
+
+        match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            Ok(_) => {
+                debug!("DeviceLock: locked '{}'", device.display());
+                Ok(DeviceLock {
+                    device: device.to_path_buf(),
+                    _file: file,
+                })
+            }
+            Err(nix::Error::Sys(Errno::EAGAIN)) => Err(Error::with_context(
+                ErrorKind::InvState,
+                &format!(
+                    "Device '{}' is locked by another process, is another takeover running?",
+                    device.display()
+                ),
+            )),
+            Err(why) => Err(Error::with_all(
+                ErrorKind::Upstream,
+                &format!("Failed to lock device '{}'", device.display()),
+                Box::new(why),
+            )),
+        }
+    }
+}
+
+impl Drop for DeviceLock {
+    fn drop(&mut self) {
+        debug!("DeviceLock: releasing '{}'", self.device.display());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DeviceLock;
+    use crate::common::ErrorKind;
+
+    #[test]
+    fn second_lock_fails() {
+        let path = std::env::temp_dir().join(format!("takeover_lock_{}", std::process::id()));
+        std::fs::write(&path, b"").unwrap();
+
+        let lock = DeviceLock::acquire(&path).unwrap();
+        let res = DeviceLock::acquire(&path);
+        drop(lock);
+        let relock = DeviceLock::acquire(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::InvState);
+        assert!(relock.is_ok());
+    }
+}
@@ -61,7 +61,7 @@ impl PartitionType {
             0x00 => PartitionType::Empty,
             0x05 | 0x0f => PartitionType::Container,
             0xee => PartitionType::GPT,
-            0x0c | 0x0e => PartitionType::Fat,
+            0x0b | 0x0c | 0x0e => PartitionType::Fat,
             0x83 => PartitionType::Linux,
             _ => PartitionType::Other,
         }
@@ -12,12 +12,14 @@ pub enum ErrorKind {
     InvParam,
     InvState,
     NotImpl,
-    ImageDownloaded,
     ExecProcess,
     CmdIo,
     Permission,
     FileExists,
     NotPermitted,
+    UnsupportedArch,
+    UnsupportedDevice,
+    Interrupted,
     Displayed,
 }
 
@@ -31,12 +33,14 @@ impl Display for ErrorKind {
             Self::InvParam => "An invalid parameter was encountered",
             Self::InvState => "An invalid state was encountered",
             Self::NotImpl => "A required function has not been implemented yet",
-            Self::ImageDownloaded => "The image was downloaded successfully",
             Self::ExecProcess => "A spawned process returned an error code",
             Self::CmdIo => "A command IO stream operation failed",
             Self::Permission => "Permission was denied",
             Self::NotPermitted => "Operation is not permitted",
             Self::FileExists => "The file exists",
+            Self::UnsupportedArch => "The architecture is not supported",
+            Self::UnsupportedDevice => "The device is not supported",
+            Self::Interrupted => "The operation was interrupted",
             Self::Displayed => "The error was displayed upstream",
         };
         write!(f, "{}", output)
@@ -120,21 +124,31 @@ impl Error {
     pub fn kind(&self) -> ErrorKind {
         self.kind
     }
+
+    /// Write kind and context of this error only, without its causes
+    fn fmt_level(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if let Some(context) = &self.context {
+            write!(f, ", context: {}", context)?;
+        }
+        Ok(())
+    }
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.kind.to_string())?;
-        match &self.context {
-            Some(context) => {
-                write!(f, ", context: {}", context)?;
-            }
-            None => (),
-        }
-        let mut curr_err: &dyn error::Error = self;
+        self.fmt_level(f)?;
 
+        // nested errors of this type would render their own causes again, so only their own
+        // level is written while walking the chain
+        let mut curr_err: &dyn error::Error = self;
         while let Some(cause) = curr_err.source() {
-            write!(f, "\n  caused by: {}", cause)?;
+            write!(f, "\n  caused by: ")?;
+            if let Some(cause) = cause.downcast_ref::<Error>() {
+                cause.fmt_level(f)?;
+            } else {
+                write!(f, "{}", cause)?;
+            }
             curr_err = cause;
         }
         Ok(())
@@ -204,3 +218,36 @@ where
 }
 
 pub type Result<T> = result::Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+    use super::{Error, ErrorKind, Result, ToError};
+    use std::io;
+
+    fn read_efi_var() -> Result<()> {
+        Err(io::Error::from_raw_os_error(libc::EACCES))
+            .upstream_with_context("Failed to read EFI variable 'SecureBoot'")
+    }
+
+    #[test]
+    fn renders_context_chain() {
+        let err = read_efi_var()
+            .error_with_all(ErrorKind::InvState, "Failed to determine secure boot state")
+            .unwrap_err();
+        let rendered = err.to_string();
+        assert_eq!(
+            rendered,
+            format!(
+                "{}, context: Failed to determine secure boot state\n  caused by: {}, context: Failed to read EFI variable 'SecureBoot'\n  caused by: {}",
+                ErrorKind::InvState,
+                ErrorKind::Upstream,
+                io::Error::from_raw_os_error(libc::EACCES)
+            )
+        );
+        assert_eq!(rendered.matches("Permission denied").count(), 1);
+        assert_eq!(
+            Error::with_context(ErrorKind::NotFound, "no such key").to_string(),
+            format!("{}, context: no such key", ErrorKind::NotFound)
+        );
+    }
+}
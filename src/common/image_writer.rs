@@ -0,0 +1,853 @@
+use std::cmp::min;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use flate2::read::GzDecoder;
+use libc::{fcntl, ioctl, posix_fadvise, F_GETFL, F_SETFL, O_DIRECT, POSIX_FADV_DONTNEED};
+use log::{debug, info, trace};
+use openssl::sha::Sha256;
+use xz2::read::XzDecoder;
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+use crate::common::{defs::IoctlReq, format_size_with_unit, Error, ErrorKind, Result, ToError};
+
+const GZIP_MAGIC: &[u8] = &[0x1F, 0x8B];
+const XZ_MAGIC: &[u8] = &[0xFD, 0x37, 0x7A, 0x58, 0x5A];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xB5, 0x2F, 0xFD];
+// boot signature at the end of the MBR identifying an uncompressed disk image
+const MBR_SIGNATURE: &[u8] = &[0x55, 0xAA];
+const MBR_SIGNATURE_OFFSET: usize = 510;
+const HEADER_SIZE: usize = 512;
+// flush the buffer cache of a block device
+const IOCTL_BLK_FLSBUF: IoctlReq = 0x1261;
+// invoke the progress callback every PROGRESS_INTERVAL bytes written
+const PROGRESS_INTERVAL: u64 = 4 * 1024 * 1024;
+// size of the regions at the start and end of a disk zeroed by wipe_signatures, covers MBR,
+// primary and backup GPT, mdraid, LVM and LUKS headers
+const WIPE_SIZE: u64 = 4 * 1024 * 1024;
+const WIPE_BLOCK_SIZE: usize = 64 * 1024;
+
+// O_DIRECT requires buffer, offset and length to be aligned to the logical block size of the
+// device, 4096 covers all common devices
+const DIRECT_IO_ALIGN: usize = 4096;
+const DIRECT_IO_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+
+const VERIFY_BUFFER_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ImageFormat {
+    Gzip,
+    Xz,
+    Zstd,
+    Raw,
+}
+
+impl ImageFormat {
+    fn from_header(header: &[u8]) -> Result<ImageFormat> {
+        if header.starts_with(GZIP_MAGIC) {
+            Ok(ImageFormat::Gzip)
+        } else if header.starts_with(XZ_MAGIC) {
+            Ok(ImageFormat::Xz)
+        } else if header.starts_with(ZSTD_MAGIC) {
+            Ok(ImageFormat::Zstd)
+        } else if header.len() == HEADER_SIZE && header[MBR_SIGNATURE_OFFSET..].eq(MBR_SIGNATURE) {
+            Ok(ImageFormat::Raw)
+        } else {
+            Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "Unrecognized image format, header: {:02x?}",
+                    &header[..min(header.len(), 8)]
+                ),
+            ))
+        }
+    }
+}
+
+/// Passes writes through to the inner writer invoking the progress callback every PROGRESS_INTERVAL bytes
+struct ProgressWriter<W, F> {
+    inner: W,
+    progress: F,
+    total: Option<u64>,
+    bytes_written: u64,
+    last_report: u64,
+}
+
+impl<W: Write, F: FnMut(u64, Option<u64>)> ProgressWriter<W, F> {
+    fn new(inner: W, total: Option<u64>, progress: F) -> ProgressWriter<W, F> {
+        ProgressWriter {
+            inner,
+            progress,
+            total,
+            bytes_written: 0,
+            last_report: 0,
+        }
+    }
+
+    fn report(&mut self) {
+        self.last_report = self.bytes_written;
+        (self.progress)(self.bytes_written, self.total);
+    }
+}
+
+impl<W: Write, F: FnMut(u64, Option<u64>)> Write for ProgressWriter<W, F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let bytes_written = self.inner.write(buf)?;
+        self.bytes_written += bytes_written as u64;
+        if self.bytes_written - self.last_report >= PROGRESS_INTERVAL {
+            self.report();
+        }
+        Ok(bytes_written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Progress callback for write_image_stream logging the progress at info level
+pub(crate) fn log_progress(bytes_written: u64, total: Option<u64>) {
+    match total {
+        Some(total) if total > 0 => info!(
+            "Wrote {} of {} ({}%)",
+            format_size_with_unit(bytes_written),
+            format_size_with_unit(total),
+            bytes_written * 100 / total
+        ),
+        _ => info!("Wrote {}", format_size_with_unit(bytes_written)),
+    }
+}
+
+/// Passes writes through to the inner writer while computing a SHA-256 over the written data
+pub(crate) struct HashWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> HashWriter<W> {
+    pub fn new(inner: W) -> HashWriter<W> {
+        HashWriter {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Returns the inner writer and the hex encoded SHA-256 of the data written
+    pub fn into_inner(self) -> (W, String) {
+        let digest = self
+            .hasher
+            .finish()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        (self.inner, digest)
+    }
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let bytes_written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..bytes_written]);
+        Ok(bytes_written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Passes reads through from the inner reader while writing a copy of the data read to copy
+struct TeeReader<'a, R, W> {
+    inner: R,
+    copy: &'a mut W,
+}
+
+impl<R: Read, W: Write> Read for TeeReader<'_, R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.inner.read(buf)?;
+        self.copy.write_all(&buf[..bytes_read])?;
+        Ok(bytes_read)
+    }
+}
+
+/// Read up to HEADER_SIZE bytes from the start of the stream for format detection
+fn read_header<R: Read>(src: &mut R) -> Result<Vec<u8>> {
+    let mut header = vec![0u8; HEADER_SIZE];
+    let mut fill: usize = 0;
+    while fill < HEADER_SIZE {
+        let bytes_read = src
+            .read(&mut header[fill..])
+            .upstream_with_context("Failed to read image header")?;
+        if bytes_read == 0 {
+            break;
+        }
+        fill += bytes_read;
+    }
+    header.truncate(fill);
+    Ok(header)
+}
+
+/// Decompress src to dst, the format is detected from the first bytes of src
+fn copy_decompressed<R: Read, W: Write>(mut src: R, dst: &mut W) -> Result<u64> {
+    let header = read_header(&mut src)?;
+    let format = ImageFormat::from_header(&header)?;
+    debug!("copy_decompressed: detected image format {:?}", format);
+
+    let mut stream = Cursor::new(header).chain(src);
+    match format {
+        ImageFormat::Gzip => io::copy(&mut GzDecoder::new(stream), dst),
+        ImageFormat::Xz => io::copy(&mut XzDecoder::new(stream), dst),
+        ImageFormat::Zstd => io::copy(
+            &mut ZstdDecoder::new(stream).upstream_with_context("Failed to create zstd decoder")?,
+            dst,
+        ),
+        ImageFormat::Raw => io::copy(&mut stream, dst),
+    }
+    .upstream_with_context("Failed to write image")
+}
+
+/// Write an image from src to dst, decompressing it on the fly if it is compressed.
+/// The format (gzip, xz, zstd or a raw disk image) is detected from the first bytes of the stream.
+/// If expected_sha256 is given the SHA-256 of the uncompressed image is verified against it.
+/// The progress callback is invoked with the bytes written so far and the total size, if known.
+/// Returns the number of (uncompressed) bytes written.
+pub(crate) fn write_image_stream<R: Read, W: Write, F: FnMut(u64, Option<u64>)>(
+    src: R,
+    dst: W,
+    expected_sha256: Option<&str>,
+    total_size: Option<u64>,
+    progress: F,
+) -> Result<u64> {
+    trace!(
+        "write_image_stream: entered with {:?}, {:?}",
+        expected_sha256,
+        total_size
+    );
+
+    let mut dst = HashWriter::new(ProgressWriter::new(dst, total_size, progress));
+    let bytes_written = copy_decompressed(src, &mut dst)?;
+
+    dst.flush()
+        .upstream_with_context("Failed to flush image output")?;
+
+    debug!("write_image_stream: wrote {} bytes", bytes_written);
+
+    let (mut progress_writer, digest) = dst.into_inner();
+    progress_writer.report();
+
+    if let Some(expected_sha256) = expected_sha256 {
+        if !digest.eq_ignore_ascii_case(expected_sha256.trim()) {
+            return Err(Error::with_context(
+                ErrorKind::InvState,
+                &format!(
+                    "Image checksum mismatch, expected SHA-256 '{}', found '{}'",
+                    expected_sha256, digest
+                ),
+            ));
+        }
+        debug!("write_image_stream: image checksum verified: '{}'", digest);
+    }
+
+    Ok(bytes_written)
+}
+
+/// Check that the image file can be opened and is in a supported format, so a broken image is
+/// detected before the device is modified
+pub(crate) fn check_image(image: &Path) -> Result<()> {
+    trace!("check_image: entered with '{}'", image.display());
+    let mut image_file = File::open(image)
+        .upstream_with_context(&format!("Failed to open image file '{}'", image.display()))?;
+    ImageFormat::from_header(&read_header(&mut image_file)?)?;
+    Ok(())
+}
+
+/// Compute the SHA-256 and size of the uncompressed content of the image file
+pub(crate) fn image_digest(image: &Path) -> Result<(String, u64)> {
+    trace!("image_digest: entered with '{}'", image.display());
+    let image_file = File::open(image)
+        .upstream_with_context(&format!("Failed to open image file '{}'", image.display()))?;
+    let mut hash_writer = HashWriter::new(io::sink());
+    let size = copy_decompressed(image_file, &mut hash_writer)?;
+    let (_sink, digest) = hash_writer.into_inner();
+    Ok((digest, size))
+}
+
+/// Copy the image from src to dst unchanged, computing the SHA-256 and size of its uncompressed
+/// content on the way, so a download is hashed as it is written without reading it again
+pub(crate) fn copy_image_digest<R: Read, W: Write>(src: R, dst: &mut W) -> Result<(String, u64)> {
+    trace!("copy_image_digest: entered");
+    let mut tee = TeeReader {
+        inner: src,
+        copy: dst,
+    };
+    let mut hash_writer = HashWriter::new(io::sink());
+    let size = copy_decompressed(&mut tee, &mut hash_writer)?;
+    // the decoder stops at the end of the compressed data, copy anything following it
+    io::copy(&mut tee, &mut io::sink()).upstream_with_context("Failed to copy image")?;
+    let (_sink, digest) = hash_writer.into_inner();
+    Ok((digest, size))
+}
+
+/// Check the uncompressed content of the image file against expected_sha256 before anything
+/// is written, fails with ErrorKind::InvState on mismatch. Returns the uncompressed size
+pub(crate) fn verify_image(image: &Path, expected_sha256: &str) -> Result<u64> {
+    trace!("verify_image: entered with '{}'", image.display());
+    let (digest, size) = image_digest(image)?;
+    if digest.eq_ignore_ascii_case(expected_sha256.trim()) {
+        info!("Verified image '{}', SHA-256 '{}'", image.display(), digest);
+        Ok(size)
+    } else {
+        Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!(
+                "Image '{}' is corrupt, expected SHA-256 '{}', found '{}'",
+                image.display(),
+                expected_sha256,
+                digest
+            ),
+        ))
+    }
+}
+
+/// Read back the first len bytes of the flashed device dev and compare their SHA-256 to
+/// expected_sha256, the digest of the uncompressed image. Fails with ErrorKind::InvState if
+/// the device content differs, eg. on media that silently drops writes.
+pub(crate) fn verify_flash(dev: &Path, expected_sha256: &str, len: u64) -> Result<()> {
+    trace!(
+        "verify_flash: entered with '{}', {} bytes",
+        dev.display(),
+        len
+    );
+    let dev_file = File::open(dev)
+        .upstream_with_context(&format!("Failed to open device '{}'", dev.display()))?;
+    // read from the device, not from pages cached while flashing
+    let _res = unsafe { posix_fadvise(dev_file.as_raw_fd(), 0, 0, POSIX_FADV_DONTNEED) };
+
+    let mut hash_writer = HashWriter::new(io::sink());
+    let bytes_read = io::copy(
+        &mut BufReader::with_capacity(VERIFY_BUFFER_SIZE, dev_file.take(len)),
+        &mut hash_writer,
+    )
+    .upstream_with_context(&format!("Failed to read back '{}'", dev.display()))?;
+    let (_sink, digest) = hash_writer.into_inner();
+
+    if bytes_read != len {
+        Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!(
+                "Could only read back {} of {} bytes from '{}'",
+                bytes_read,
+                len,
+                dev.display()
+            ),
+        ))
+    } else if !digest.eq_ignore_ascii_case(expected_sha256.trim()) {
+        Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!(
+                "The content of '{}' does not match the image, expected SHA-256 '{}', found '{}'",
+                dev.display(),
+                expected_sha256,
+                digest
+            ),
+        ))
+    } else {
+        info!(
+            "Verified {} written to '{}'",
+            format_size_with_unit(len),
+            dev.display()
+        );
+        Ok(())
+    }
+}
+
+/// Flush all data written to file to the device, for block devices also flush the buffer cache
+fn sync_file(file: &File, path: &Path) -> Result<()> {
+    debug!("sync_file: syncing '{}'", path.display());
+    file.sync_all()
+        .upstream_with_context(&format!("Failed to sync '{}'", path.display()))?;
+
+    let is_block_device = file
+        .metadata()
+        .upstream_with_context(&format!(
+            "Failed to retrieve metadata for '{}'",
+            path.display()
+        ))?
+        .file_type()
+        .is_block_device();
+
+    if is_block_device {
+        let ioctl_res = unsafe { ioctl(file.as_raw_fd(), IOCTL_BLK_FLSBUF) };
+        if ioctl_res != 0 {
+            return Err(Error::with_context(
+                ErrorKind::Upstream,
+                &format!(
+                    "Device BLKFLSBUF IOCTRL to '{}' failed with error: {}",
+                    path.display(),
+                    io::Error::last_os_error()
+                ),
+            ));
+        }
+    }
+
+    info!("Synced '{}'", path.display());
+    Ok(())
+}
+
+/// Make sure all data written to the device has reached the disk
+pub(crate) fn sync_device(device: &Path) -> Result<()> {
+    trace!("sync_device: entered with '{}'", device.display());
+    let device_file = OpenOptions::new()
+        .write(true)
+        .open(device)
+        .upstream_with_context(&format!("Failed to open device '{}'", device.display()))?;
+    sync_file(&device_file, device)
+}
+
+/// The regions of a device of size bytes to zero, as (offset, length) tuples
+fn wipe_regions(size: u64) -> Vec<(u64, u64)> {
+    if size <= 2 * WIPE_SIZE {
+        vec![(0, size)]
+    } else {
+        vec![(0, WIPE_SIZE), (size - WIPE_SIZE, WIPE_SIZE)]
+    }
+}
+
+fn zero_region<W: Write + Seek>(output: &mut W, offset: u64, length: u64) -> io::Result<()> {
+    let zeros = [0u8; WIPE_BLOCK_SIZE];
+    output.seek(SeekFrom::Start(offset))?;
+    let mut remaining = length;
+    while remaining > 0 {
+        let chunk = min(remaining, WIPE_BLOCK_SIZE as u64) as usize;
+        output.write_all(&zeros[..chunk])?;
+        remaining -= chunk as u64;
+    }
+    Ok(())
+}
+
+/// Zero the first and last few megabytes of dev to remove old partition tables and RAID, LVM
+/// or LUKS signatures that could otherwise be picked up after flashing. This is destructive.
+pub(crate) fn wipe_signatures(dev: &Path) -> Result<()> {
+    trace!("wipe_signatures: entered with '{}'", dev.display());
+    let mut dev_file = OpenOptions::new()
+        .write(true)
+        .open(dev)
+        .upstream_with_context(&format!("Failed to open device '{}'", dev.display()))?;
+    let size = dev_file
+        .seek(SeekFrom::End(0))
+        .upstream_with_context(&format!("Failed to determine size of '{}'", dev.display()))?;
+
+    for (offset, length) in wipe_regions(size) {
+        zero_region(&mut dev_file, offset, length).upstream_with_context(&format!(
+            "Failed to zero {} bytes at offset {} of '{}'",
+            length,
+            offset,
+            dev.display()
+        ))?;
+        info!(
+            "Zeroed bytes {}..{} ({}) of '{}'",
+            offset,
+            offset + length,
+            format_size_with_unit(length),
+            dev.display()
+        );
+    }
+
+    sync_file(&dev_file, dev)
+}
+
+/// Writes to a file opened with O_DIRECT, bypassing the page cache. Data is collected in an
+/// aligned buffer and written in multiples of DIRECT_IO_ALIGN, a trailing partial block is
+/// written with O_DIRECT cleared by finish, which has to be called to write all data
+struct DirectWriter {
+    file: File,
+    // over allocated by DIRECT_IO_ALIGN, the aligned buffer starts at offset
+    storage: Vec<u8>,
+    offset: usize,
+    filled: usize,
+}
+
+impl DirectWriter {
+    fn open(device: &Path) -> io::Result<DirectWriter> {
+        let file = OpenOptions::new()
+            .write(true)
+            .custom_flags(O_DIRECT)
+            .open(device)?;
+        let storage = vec![0u8; DIRECT_IO_BUFFER_SIZE + DIRECT_IO_ALIGN];
+        let offset = storage.as_ptr().align_offset(DIRECT_IO_ALIGN);
+        Ok(DirectWriter {
+            file,
+            storage,
+            offset,
+            filled: 0,
+        })
+    }
+
+    fn buffer(&mut self) -> &mut [u8] {
+        &mut self.storage[self.offset..self.offset + DIRECT_IO_BUFFER_SIZE]
+    }
+
+    /// Write all complete blocks and move the remainder to the start of the buffer
+    fn write_blocks(&mut self) -> io::Result<()> {
+        let filled = self.filled;
+        let aligned = filled - filled % DIRECT_IO_ALIGN;
+        if aligned > 0 {
+            let start = self.offset;
+            self.file.write_all(&self.storage[start..start + aligned])?;
+            self.buffer().copy_within(aligned..filled, 0);
+            self.filled -= aligned;
+        }
+        Ok(())
+    }
+
+    /// Write the buffered data including a trailing partial block and return the file
+    fn finish(mut self) -> io::Result<File> {
+        self.write_blocks()?;
+        if self.filled > 0 {
+            // a partial block can not be written with O_DIRECT, fall back to a cached write
+            let flags = unsafe { fcntl(self.file.as_raw_fd(), F_GETFL) };
+            if flags < 0 || unsafe { fcntl(self.file.as_raw_fd(), F_SETFL, flags & !O_DIRECT) } < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            let start = self.offset;
+            self.file
+                .write_all(&self.storage[start..start + self.filled])?;
+            self.filled = 0;
+        }
+        Ok(self.file)
+    }
+}
+
+impl Write for DirectWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let filled = self.filled;
+        let count = min(buf.len(), DIRECT_IO_BUFFER_SIZE - filled);
+        self.buffer()[filled..filled + count].copy_from_slice(&buf[..count]);
+        self.filled += count;
+        if self.filled == DIRECT_IO_BUFFER_SIZE {
+            self.write_blocks()?;
+        }
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_blocks()
+    }
+}
+
+/// Check that device can be opened for writing with O_DIRECT, so a failure is detected before
+/// anything is written
+pub(crate) fn check_direct_io(device: &Path) -> Result<()> {
+    DirectWriter::open(device)
+        .map(|_| ())
+        .upstream_with_context(&format!(
+            "Failed to open device '{}' for direct IO",
+            device.display()
+        ))
+}
+
+/// Write the image file to the device using write_image_stream and sync the device before
+/// returning. With direct_io the device is written with O_DIRECT, bypassing the page cache.
+/// The caller is expected to hold the DeviceLock of device.
+pub(crate) fn write_image_to_device<F: FnMut(u64, Option<u64>)>(
+    image: &Path,
+    device: &Path,
+    expected_sha256: Option<&str>,
+    total_size: Option<u64>,
+    direct_io: bool,
+    progress: F,
+) -> Result<u64> {
+    trace!(
+        "write_image_to_device: entered with '{}', '{}'",
+        image.display(),
+        device.display()
+    );
+
+    let image_file = File::open(image)
+        .upstream_with_context(&format!("Failed to open image file '{}'", image.display()))?;
+
+    let (bytes_written, device_file) = if direct_io {
+        let mut writer = DirectWriter::open(device).upstream_with_context(&format!(
+            "Failed to open device '{}' for direct IO",
+            device.display()
+        ))?;
+        let bytes_written = write_image_stream(
+            image_file,
+            &mut writer,
+            expected_sha256,
+            total_size,
+            progress,
+        )?;
+        let device_file = writer.finish().upstream_with_context(&format!(
+            "Failed to write final block to '{}'",
+            device.display()
+        ))?;
+        (bytes_written, device_file)
+    } else {
+        let mut device_file = OpenOptions::new()
+            .write(true)
+            .open(device)
+            .upstream_with_context(&format!("Failed to open device '{}'", device.display()))?;
+        let bytes_written = write_image_stream(
+            image_file,
+            &mut device_file,
+            expected_sha256,
+            total_size,
+            progress,
+        )?;
+        (bytes_written, device_file)
+    };
+
+    sync_file(&device_file, device)?;
+
+    Ok(bytes_written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_image, copy_image_digest, image_digest, verify_flash, verify_image, wipe_regions,
+        wipe_signatures, write_image_stream, write_image_to_device, DirectWriter, DIRECT_IO_ALIGN,
+        WIPE_SIZE,
+    };
+    use crate::common::ErrorKind;
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+    use xz2::write::XzEncoder;
+
+    fn test_image() -> Vec<u8> {
+        let mut image: Vec<u8> = (0..4096u32).map(|idx| (idx % 251) as u8).collect();
+        image[510] = 0x55;
+        image[511] = 0xAA;
+        image
+    }
+
+    fn check_output(compressed: &[u8], image: &[u8]) {
+        let mut output: Vec<u8> = Vec::new();
+        assert_eq!(
+            write_image_stream(compressed, &mut output, None, None, |_, _| {}).unwrap(),
+            image.len() as u64
+        );
+        assert_eq!(output, image);
+    }
+
+    #[test]
+    fn writes_raw_image() {
+        let image = test_image();
+        check_output(&image, &image);
+    }
+
+    #[test]
+    fn writes_gzip_image() {
+        let image = test_image();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&image).unwrap();
+        check_output(&encoder.finish().unwrap(), &image);
+    }
+
+    #[test]
+    fn writes_xz_image() {
+        let image = test_image();
+        let mut encoder = XzEncoder::new(Vec::new(), 6);
+        encoder.write_all(&image).unwrap();
+        check_output(&encoder.finish().unwrap(), &image);
+    }
+
+    #[test]
+    fn writes_zstd_image() {
+        let image = test_image();
+        check_output(&zstd::encode_all(image.as_slice(), 0).unwrap(), &image);
+    }
+
+    #[test]
+    fn rejects_unknown_format() {
+        let mut output: Vec<u8> = Vec::new();
+        let res = write_image_stream(&b"not an image"[..], &mut output, None, None, |_, _| {});
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::InvParam);
+    }
+
+    #[test]
+    fn verifies_checksum() {
+        let mut image = vec![0u8; 512];
+        image[510] = 0x55;
+        image[511] = 0xAA;
+        let digest = "b140168e270a194660d4ecb93bbd23892cfa96a2ccd38cfb9959fd0f0da91db6";
+
+        let mut output: Vec<u8> = Vec::new();
+        assert_eq!(
+            write_image_stream(image.as_slice(), &mut output, Some(digest), None, |_, _| {})
+                .unwrap(),
+            512
+        );
+
+        let mut output: Vec<u8> = Vec::new();
+        let res = write_image_stream(
+            image.as_slice(),
+            &mut output,
+            Some(&digest.replace('b', "c")),
+            None,
+            |_, _| {},
+        );
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::InvState);
+    }
+
+    #[test]
+    fn hashes_image_while_copying() {
+        let image = test_image();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&image).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut output: Vec<u8> = Vec::new();
+        let (digest, size) = copy_image_digest(compressed.as_slice(), &mut output).unwrap();
+        assert_eq!(output, compressed);
+        assert_eq!(size, image.len() as u64);
+
+        let image_path =
+            std::env::temp_dir().join(format!("takeover_copy_digest_{}", std::process::id()));
+        std::fs::write(&image_path, &compressed).unwrap();
+        let staged = image_digest(&image_path);
+        std::fs::remove_file(&image_path).unwrap();
+        assert_eq!(staged.unwrap(), (digest, size));
+    }
+
+    #[test]
+    fn reports_progress() {
+        let image = vec![0u8; 10 * 1024 * 1024];
+        let mut reports: Vec<(u64, Option<u64>)> = Vec::new();
+        let compressed = zstd::encode_all(image.as_slice(), 0).unwrap();
+        let mut output: Vec<u8> = Vec::new();
+        write_image_stream(
+            compressed.as_slice(),
+            &mut output,
+            None,
+            Some(image.len() as u64),
+            |written, total| reports.push((written, total)),
+        )
+        .unwrap();
+        assert!(reports.len() >= 3);
+        assert_eq!(
+            reports.last(),
+            Some(&(image.len() as u64, Some(image.len() as u64)))
+        );
+    }
+
+    #[test]
+    fn checks_image_before_flashing() {
+        let image_path =
+            std::env::temp_dir().join(format!("takeover_check_{}.img", std::process::id()));
+        std::fs::write(&image_path, test_image()).unwrap();
+        let valid = check_image(&image_path);
+        std::fs::write(&image_path, b"not an image").unwrap();
+        let invalid = check_image(&image_path);
+        std::fs::remove_file(&image_path).unwrap();
+
+        assert!(valid.is_ok());
+        assert_eq!(invalid.unwrap_err().kind(), ErrorKind::InvParam);
+        assert!(check_image(&image_path).is_err());
+    }
+
+    #[test]
+    fn writes_image_to_file() {
+        let image = test_image();
+        let dir = std::env::temp_dir();
+        let image_path = dir.join(format!("takeover_image_{}.zst", std::process::id()));
+        let output_path = dir.join(format!("takeover_output_{}", std::process::id()));
+        std::fs::write(&image_path, zstd::encode_all(image.as_slice(), 0).unwrap()).unwrap();
+        std::fs::write(&output_path, b"").unwrap();
+
+        let res = write_image_to_device(&image_path, &output_path, None, None, false, |_, _| {});
+        let output = std::fs::read(&output_path).unwrap();
+        std::fs::remove_file(&image_path).unwrap();
+        std::fs::remove_file(&output_path).unwrap();
+
+        assert_eq!(res.unwrap(), image.len() as u64);
+        assert_eq!(output, image);
+    }
+
+    #[test]
+    fn verifies_flashed_device() {
+        let image = test_image();
+        let dir = std::env::temp_dir();
+        let image_path = dir.join(format!("takeover_verify_{}.gz", std::process::id()));
+        let dev_path = dir.join(format!("takeover_verify_dev_{}", std::process::id()));
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&image).unwrap();
+        std::fs::write(&image_path, encoder.finish().unwrap()).unwrap();
+        // the device is larger than the image, only the image length is compared
+        let mut device = image.clone();
+        device.extend_from_slice(&[0xFFu8; 1024]);
+        std::fs::write(&dev_path, &device).unwrap();
+
+        let (digest, size) = image_digest(&image_path).unwrap();
+        let image_verified = verify_image(&image_path, &digest);
+        let image_mismatch = verify_image(&image_path, &"0".repeat(64));
+        let verified = verify_flash(&dev_path, &digest, size);
+        device[100] ^= 0xFF;
+        std::fs::write(&dev_path, &device).unwrap();
+        let corrupted = verify_flash(&dev_path, &digest, size);
+        let too_short = verify_flash(&dev_path, &digest, device.len() as u64 + 1);
+        std::fs::remove_file(&image_path).unwrap();
+        std::fs::remove_file(&dev_path).unwrap();
+
+        assert_eq!(size, image.len() as u64);
+        assert_eq!(image_verified.unwrap(), size);
+        assert_eq!(image_mismatch.unwrap_err().kind(), ErrorKind::InvState);
+        assert!(verified.is_ok());
+        assert_eq!(corrupted.unwrap_err().kind(), ErrorKind::InvState);
+        assert_eq!(too_short.unwrap_err().kind(), ErrorKind::InvState);
+    }
+
+    #[test]
+    fn direct_writer_writes_partial_block() {
+        let path = std::env::temp_dir().join(format!("takeover_direct_{}", std::process::id()));
+        std::fs::write(&path, b"").unwrap();
+        let data: Vec<u8> = (0..3 * DIRECT_IO_ALIGN + 100)
+            .map(|idx| (idx % 251) as u8)
+            .collect();
+
+        let res = DirectWriter::open(&path).and_then(|mut writer| {
+            writer.write_all(&data[..DIRECT_IO_ALIGN + 7])?;
+            writer.flush()?;
+            writer.write_all(&data[DIRECT_IO_ALIGN + 7..])?;
+            writer.finish()
+        });
+        let output = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        match res {
+            Ok(_) => assert_eq!(output, data),
+            // not all file systems support O_DIRECT
+            Err(why) => assert_eq!(why.raw_os_error(), Some(libc::EINVAL)),
+        }
+    }
+
+    #[test]
+    fn wipes_start_and_end_of_device() {
+        assert_eq!(wipe_regions(WIPE_SIZE), vec![(0, WIPE_SIZE)]);
+        let size = 3 * WIPE_SIZE;
+        assert_eq!(
+            wipe_regions(size),
+            vec![(0, WIPE_SIZE), (2 * WIPE_SIZE, WIPE_SIZE)]
+        );
+
+        let path = std::env::temp_dir().join(format!("takeover_wipe_{}", std::process::id()));
+        std::fs::write(&path, vec![0xFFu8; size as usize]).unwrap();
+        let res = wipe_signatures(&path);
+        let wiped = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        res.unwrap();
+        assert_eq!(wiped.len(), size as usize);
+        let wipe_size = WIPE_SIZE as usize;
+        assert!(wiped[..wipe_size].iter().all(|byte| *byte == 0));
+        assert!(wiped[wipe_size..2 * wipe_size]
+            .iter()
+            .all(|byte| *byte == 0xFF));
+        assert!(wiped[2 * wipe_size..].iter().all(|byte| *byte == 0));
+    }
+}
@@ -7,11 +7,12 @@ use std::time::Duration;
 use nix::errno::errno;
 use std::cmp::min;
 use std::ffi::{CString, OsStr};
+use std::fs::read_dir;
 use std::io;
 use std::os::unix::ffi::OsStrExt;
 
 use crate::common::{
-    defs::IoctlReq,
+    defs::{IoctlReq, SYS_CLASS_BLOCK},
     error::{Error, ErrorKind, Result, ToError},
     hex_dump, path_to_cstring,
     system::fd::Fd,
@@ -21,6 +22,10 @@ use log::{debug, trace};
 use libc::{self, ioctl, makedev, mknod, EAGAIN, ENXIO, O_CLOEXEC, O_RDWR, S_IFBLK};
 
 const MAX_LOOP: u32 = 1024;
+const LOOP_CONTROL: &str = "/dev/loop-control";
+const LOOP_NAME_PREFIX: &str = "loop";
+// only present in sysfs while a loop device is bound to a file
+const SYS_LOOP_BACKING_FILE: &str = "loop/backing_file";
 
 const IOCTL_LOOP_SET_FD: IoctlReq = 0x4c00;
 const IOCTL_LOOP_CLR_FD: IoctlReq = 0x4c01;
@@ -175,58 +180,9 @@ impl LoopDevice {
         Ok(loop_device)
     }
 
+    /// open a free loop device, see find_free_loop
     pub fn get_free(auto_unset: bool) -> Result<LoopDevice> {
-        // Find a free loop device, try use /dev/loop-control first
-        match Fd::open("/dev/loop-control", O_RDWR | O_CLOEXEC) {
-            Ok(file_fd) => {
-                let ioctl_res = unsafe { ioctl(file_fd.get_fd(), IOCTL_LOOP_CTL_GET_FREE) };
-                if ioctl_res < 0 {
-                    Err(Error::with_context(
-                        ErrorKind::Upstream,
-                        &format!(
-                            "get_free: ioctl IOCTL_LOOP_CTL_GET_FREE failed with error: {}",
-                            io::Error::last_os_error().to_string()
-                        ),
-                    ))
-                } else {
-                    // success, return loop number and open device fd
-                    Ok(LoopDevice::from_index(ioctl_res as u32, auto_unset)?)
-                }
-            }
-            Err(why) => {
-                debug!(
-                    "get_free: open /dev/loop-control returned error {:?}",
-                    why.kind()
-                );
-                if why.kind() == ErrorKind::FileNotFound {
-                    // if /dev/loop-control does not exist scan for free devices manually
-                    debug!("get_free: /dev/loop-control does not exist scanning for free devices manually");
-                    for loop_idx in 0..MAX_LOOP {
-                        if PathBuf::from(&format!("/dev/loop{}", loop_idx)).exists() {
-                            let mut loop_dev = LoopDevice::from_index(loop_idx, false)?;
-                            if loop_dev.file.is_some() {
-                                // device is in use
-                                continue;
-                            } else {
-                                loop_dev.unset = auto_unset;
-                                return Ok(loop_dev);
-                            }
-                        } else {
-                            return Ok(LoopDevice::from_index(loop_idx, auto_unset)?);
-                        }
-                    }
-                    Err(Error::with_context(
-                        ErrorKind::NotFound,
-                        "get_free: No free loop device was found",
-                    ))
-                } else {
-                    Err(Error::with_context(
-                        ErrorKind::NotFound,
-                        "get_free: Unable to open /dev/loop-control",
-                    ))
-                }
-            }
-        }
+        LoopDevice::from_index(free_loop_index()?, auto_unset)
     }
 
     /// retrieve the loop devices path
@@ -454,6 +410,97 @@ impl Drop for LoopDevice {
     }
 }
 
+/// Choose a free loop index from the (index, bound) state of the existing loop devices, the
+/// lowest unbound one or the next index after the existing devices
+fn choose_free_index(devices: &[(u32, bool)]) -> Option<u32> {
+    if let Some(free) = devices
+        .iter()
+        .filter(|(_, bound)| !bound)
+        .map(|(index, _)| *index)
+        .min()
+    {
+        return Some(free);
+    }
+    let next = devices
+        .iter()
+        .map(|(index, _)| index + 1)
+        .max()
+        .unwrap_or(0);
+    if next < MAX_LOOP {
+        Some(next)
+    } else {
+        None
+    }
+}
+
+/// Scan /sys/class/block for loop devices that are not bound to a file
+fn scan_sys_loop_devices() -> Result<u32> {
+    trace!("scan_sys_loop_devices: entered");
+    let mut devices: Vec<(u32, bool)> = Vec::new();
+    for entry in read_dir(SYS_CLASS_BLOCK)
+        .upstream_with_context(&format!("Failed to read directory '{}'", SYS_CLASS_BLOCK))?
+    {
+        let entry = entry.upstream_with_context(&format!(
+            "Failed to read entry of directory '{}'",
+            SYS_CLASS_BLOCK
+        ))?;
+        // partitions like loop0p1 are skipped as their index does not parse
+        let index = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_prefix(LOOP_NAME_PREFIX))
+            .and_then(|index| index.parse::<u32>().ok());
+        if let Some(index) = index {
+            devices.push((index, entry.path().join(SYS_LOOP_BACKING_FILE).exists()));
+        }
+    }
+    debug!("scan_sys_loop_devices: found {:?}", devices);
+    choose_free_index(&devices).ok_or_else(|| {
+        Error::with_context(
+            ErrorKind::NotFound,
+            "scan_sys_loop_devices: No free loop device was found",
+        )
+    })
+}
+
+/// Allocate the index of a free loop device, using the LOOP_CTL_GET_FREE ioctl on
+/// /dev/loop-control which allocates atomically, and scanning sysfs if that is unavailable
+fn free_loop_index() -> Result<u32> {
+    match Fd::open(LOOP_CONTROL, O_RDWR | O_CLOEXEC) {
+        Ok(file_fd) => {
+            let ioctl_res = unsafe { ioctl(file_fd.get_fd(), IOCTL_LOOP_CTL_GET_FREE) };
+            if ioctl_res < 0 {
+                Err(Error::with_context(
+                    ErrorKind::Upstream,
+                    &format!(
+                        "free_loop_index: ioctl IOCTL_LOOP_CTL_GET_FREE failed with error: {}",
+                        io::Error::last_os_error()
+                    ),
+                ))
+            } else {
+                Ok(ioctl_res as u32)
+            }
+        }
+        Err(why) => {
+            debug!(
+                "free_loop_index: open {} returned error {:?}, scanning for free devices",
+                LOOP_CONTROL,
+                why.kind()
+            );
+            scan_sys_loop_devices()
+        }
+    }
+}
+
+/// Find a free loop device and return its path
+#[allow(dead_code)]
+pub(crate) fn find_free_loop() -> Result<PathBuf> {
+    trace!("find_free_loop: entered");
+    let path = PathBuf::from(&format!("/dev/loop{}", free_loop_index()?));
+    debug!("find_free_loop: found '{}'", path.display());
+    Ok(path)
+}
+
 fn path_to_cbuffer<P: AsRef<Path>>(path: P, buffer: &mut [u8]) -> Result<()> {
     let c_string = path_to_cstring(path)?;
     let src = c_string.to_bytes_with_nul();
@@ -503,3 +550,19 @@ pub(crate) fn key_to_string(key: &[u8], max_len: Option<usize>) -> String {
     }
     res
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chooses_lowest_unbound_index() {
+        assert_eq!(choose_free_index(&[]), Some(0));
+        assert_eq!(
+            choose_free_index(&[(0, true), (3, false), (1, false)]),
+            Some(1)
+        );
+        assert_eq!(choose_free_index(&[(0, true), (1, true)]), Some(2));
+        assert_eq!(choose_free_index(&[(MAX_LOOP - 1, true)]), None);
+    }
+}
@@ -72,6 +72,32 @@ pub struct Options {
     no_ack: bool,
     #[structopt(long, help = "Pretend mode, do not flash device")]
     pretend: bool,
+    #[structopt(
+        long,
+        help = "Zero the start and end of the target disk before flashing to remove old RAID, LVM or LUKS signatures"
+    )]
+    wipe_signatures: bool,
+    #[structopt(
+        long,
+        help = "Write the image with O_DIRECT, bypassing the page cache on low memory devices"
+    )]
+    direct_io: bool,
+    #[structopt(
+        long,
+        help = "Read the flashed image back from the device and verify its checksum"
+    )]
+    verify_flash: bool,
+    #[structopt(
+        long,
+        help = "Provision the flashed device even if its partition table does not have the balena OS layout"
+    )]
+    ignore_partition_check: bool,
+    #[structopt(long, help = "Dry run - log mounts instead of performing them")]
+    dry_run: bool,
+    #[structopt(long, help = "Write stage1 progress events as JSON lines to stdout")]
+    json_events: bool,
+    #[structopt(long, help = "Run all checks, report the results and exit")]
+    preflight: bool,
     #[structopt(long, help = "Internal - stage2 invocation")]
     stage2: bool,
     #[structopt(long, help = "Use internal tar instead of external command")]
@@ -90,6 +116,13 @@ pub struct Options {
     no_nwmgr_check: bool,
     #[structopt(long, help = "Do not migrate host-name")]
     no_keep_name: bool,
+    #[structopt(long, help = "Refuse to flash to a removable device")]
+    refuse_removable: bool,
+    #[structopt(
+        long,
+        help = "Allow the device given with --flash-to to be the disk of the running root file system"
+    )]
+    allow_self: bool,
     #[structopt(
         short,
         long,
@@ -119,6 +152,13 @@ pub struct Options {
         help = "Use INSTALL_DEVICE to flash balena to"
     )]
     flash_to: Option<PathBuf>,
+    #[structopt(
+        long,
+        value_name = "DIRECTORY",
+        parse(from_os_str),
+        help = "Stage temporary files in DIRECTORY instead of the system temp directory"
+    )]
+    staging_dir: Option<PathBuf>,
     #[structopt(
         long,
         help = "Do not create network manager configurations for configured wifis"
@@ -137,6 +177,13 @@ pub struct Options {
         help = "Supply a network manager file to inject into balena-os"
     )]
     nwmgr_cfg: Option<Vec<PathBuf>>,
+    #[structopt(
+        long,
+        value_name = "PRESERVE_PATH",
+        parse(from_os_str),
+        help = "Preserve a file or directory by copying it to the balena-os data partition"
+    )]
+    preserve: Option<Vec<PathBuf>>,
 }
 
 impl Options {
@@ -192,6 +239,34 @@ impl Options {
         self.pretend
     }
 
+    pub fn wipe_signatures(&self) -> bool {
+        self.wipe_signatures
+    }
+
+    pub fn direct_io(&self) -> bool {
+        self.direct_io
+    }
+
+    pub fn verify_flash(&self) -> bool {
+        self.verify_flash
+    }
+
+    pub fn ignore_partition_check(&self) -> bool {
+        self.ignore_partition_check
+    }
+
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    pub fn json_events(&self) -> bool {
+        self.json_events
+    }
+
+    pub fn preflight(&self) -> bool {
+        self.preflight
+    }
+
     pub fn log_file(&self) -> &Option<PathBuf> {
         &self.log_file
     }
@@ -232,6 +307,10 @@ impl Options {
         &self.flash_to
     }
 
+    pub fn staging_dir(&self) -> &Option<PathBuf> {
+        &self.staging_dir
+    }
+
     pub fn check_timeout(&self) -> u64 {
         if let Some(timeout) = self.check_timeout {
             timeout
@@ -262,6 +341,15 @@ impl Options {
         }
     }
 
+    pub fn preserve(&self) -> &[PathBuf] {
+        if let Some(preserve) = &self.preserve {
+            preserve.as_slice()
+        } else {
+            const NO_PRESERVE: [PathBuf; 0] = [];
+            &NO_PRESERVE
+        }
+    }
+
     pub fn no_nwmgr_check(&self) -> bool {
         self.no_nwmgr_check
     }
@@ -270,6 +358,14 @@ impl Options {
         !self.no_keep_name
     }
 
+    pub fn refuse_removable(&self) -> bool {
+        self.refuse_removable
+    }
+
+    pub fn allow_self(&self) -> bool {
+        self.allow_self
+    }
+
     pub fn cleanup(&self) -> bool {
         !self.no_cleanup
     }
@@ -0,0 +1,360 @@
+use std::fmt::{self, Display, Formatter};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use log::{debug, trace};
+
+use crate::common::{Error, ErrorKind, Result, ToError};
+
+const SECTOR_SIZE: u64 = 512;
+// GPT header sector sizes to probe, 4K native disks place the header at byte 4096
+const GPT_SECTOR_SIZES: [u64; 2] = [512, 4096];
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+const GPT_HEADER_SIZE: usize = 92;
+const GPT_MAX_ENTRIES: u32 = 1024;
+const MBR_TABLE_OFFSET: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_ENTRIES: usize = 4;
+const MBR_PTYPE_GPT: u8 = 0xEE;
+const MBR_PTYPES_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+// FAT32 CHS, FAT32 LBA and FAT16 LBA
+const MBR_PTYPES_FAT: [u8; 3] = [0x0B, 0x0C, 0x0E];
+// EFI system partition and basic data partition
+const GPT_TYPES_FAT: [&str; 2] = [
+    "C12A7328-F81F-11D2-BA4B-00A0C93EC93B",
+    "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7",
+];
+// guard against loops in corrupt extended partition chains
+const MAX_LOGICAL_PARTITIONS: usize = 128;
+
+/// The type of a partition, the type byte for MBR or the type GUID for GPT partitions
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum PartitionTypeId {
+    Mbr(u8),
+    Gpt(String),
+}
+
+impl Display for PartitionTypeId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Mbr(ptype) => write!(f, "0x{:02x}", ptype),
+            Self::Gpt(guid) => write!(f, "{}", guid),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct PartitionEntry {
+    index: usize,
+    start: u64,
+    size: u64,
+    type_id: PartitionTypeId,
+}
+
+impl PartitionEntry {
+    /// The partition number as used in device names, eg. 5 for the first logical partition
+    pub fn get_index(&self) -> usize {
+        self.index
+    }
+
+    /// Offset of the partition in bytes
+    pub fn get_start(&self) -> u64 {
+        self.start
+    }
+
+    /// Size of the partition in bytes
+    pub fn get_size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn get_type_id(&self) -> &PartitionTypeId {
+        &self.type_id
+    }
+
+    /// Check whether the partition type is one used for FAT boot partitions
+    pub fn is_fat(&self) -> bool {
+        match &self.type_id {
+            PartitionTypeId::Mbr(ptype) => MBR_PTYPES_FAT.contains(ptype),
+            PartitionTypeId::Gpt(guid) => GPT_TYPES_FAT.contains(&guid.as_str()),
+        }
+    }
+}
+
+fn read_at<R: Read + Seek>(src: &mut R, offset: u64, buffer: &mut [u8]) -> Result<()> {
+    src.seek(SeekFrom::Start(offset))
+        .and_then(|_| src.read_exact(buffer))
+        .upstream_with_context(&format!(
+            "Failed to read {} bytes at offset {}",
+            buffer.len(),
+            offset
+        ))
+}
+
+fn le_u32(buffer: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buffer[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn le_u64(buffer: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buffer[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// Format a GUID in its mixed endian on disk representation as a string
+fn format_guid(guid: &[u8]) -> String {
+    format!(
+        "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+        le_u32(guid, 0),
+        u16::from_le_bytes([guid[4], guid[5]]),
+        u16::from_le_bytes([guid[6], guid[7]]),
+        guid[8],
+        guid[9],
+        guid[10],
+        guid[11],
+        guid[12],
+        guid[13],
+        guid[14],
+        guid[15]
+    )
+}
+
+/// Read a MBR / EBR sector, returns the (type, first lba, number of sectors) tuples of the
+/// partition table
+fn read_mbr<R: Read + Seek>(src: &mut R, lba: u64) -> Result<Vec<(u8, u64, u64)>> {
+    let mut sector = [0u8; SECTOR_SIZE as usize];
+    read_at(src, lba * SECTOR_SIZE, &mut sector)?;
+    if sector[510] != 0x55 || sector[511] != 0xAA {
+        return Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!("Invalid MBR signature in sector {}", lba),
+        ));
+    }
+
+    Ok((0..MBR_ENTRIES)
+        .map(|idx| {
+            let entry = &sector[MBR_TABLE_OFFSET + idx * MBR_ENTRY_SIZE..];
+            (
+                entry[4],
+                u64::from(le_u32(entry, 8)),
+                u64::from(le_u32(entry, 12)),
+            )
+        })
+        .collect())
+}
+
+fn read_logical_partitions<R: Read + Seek>(
+    src: &mut R,
+    ext_start: u64,
+    partitions: &mut Vec<PartitionEntry>,
+) -> Result<()> {
+    let mut ebr_lba = ext_start;
+    for index in 5..5 + MAX_LOGICAL_PARTITIONS {
+        let table = read_mbr(src, ebr_lba)?;
+        let (ptype, first_lba, num_sectors) = table[0];
+        if ptype != 0 && num_sectors > 0 {
+            // the logical partition is relative to its EBR
+            partitions.push(PartitionEntry {
+                index,
+                start: (ebr_lba + first_lba) * SECTOR_SIZE,
+                size: num_sectors * SECTOR_SIZE,
+                type_id: PartitionTypeId::Mbr(ptype),
+            });
+        }
+
+        // the link to the next EBR is relative to the start of the extended partition
+        let (next_type, next_lba, _) = table[1];
+        if MBR_PTYPES_EXTENDED.contains(&next_type) && next_lba > 0 {
+            ebr_lba = ext_start + next_lba;
+        } else {
+            return Ok(());
+        }
+    }
+
+    Err(Error::with_context(
+        ErrorKind::InvState,
+        &format!(
+            "More than {} logical partitions found, the extended partition table is likely corrupt",
+            MAX_LOGICAL_PARTITIONS
+        ),
+    ))
+}
+
+fn read_mbr_partitions<R: Read + Seek>(
+    src: &mut R,
+    table: &[(u8, u64, u64)],
+) -> Result<Vec<PartitionEntry>> {
+    let mut partitions: Vec<PartitionEntry> = Vec::new();
+    for (idx, (ptype, first_lba, num_sectors)) in table.iter().enumerate() {
+        if *ptype == 0 || *num_sectors == 0 {
+            continue;
+        }
+
+        if MBR_PTYPES_EXTENDED.contains(ptype) {
+            read_logical_partitions(src, *first_lba, &mut partitions)?;
+        } else {
+            partitions.push(PartitionEntry {
+                index: idx + 1,
+                start: first_lba * SECTOR_SIZE,
+                size: num_sectors * SECTOR_SIZE,
+                type_id: PartitionTypeId::Mbr(*ptype),
+            });
+        }
+    }
+    Ok(partitions)
+}
+
+fn read_gpt_partitions<R: Read + Seek>(src: &mut R) -> Result<Vec<PartitionEntry>> {
+    let mut header = [0u8; GPT_HEADER_SIZE];
+    for sector_size in GPT_SECTOR_SIZES.iter() {
+        read_at(src, *sector_size, &mut header)?;
+        if &header[0..GPT_SIGNATURE.len()] != GPT_SIGNATURE {
+            continue;
+        }
+
+        let entries_lba = le_u64(&header, 72);
+        let num_entries = le_u32(&header, 80);
+        let entry_size = le_u32(&header, 84) as usize;
+        debug!(
+            "read_gpt_partitions: sector size {}, {} entries of {} bytes at lba {}",
+            sector_size, num_entries, entry_size, entries_lba
+        );
+        if num_entries > GPT_MAX_ENTRIES || entry_size < 128 {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "Invalid GPT header, {} entries of {} bytes",
+                    num_entries, entry_size
+                ),
+            ));
+        }
+
+        let mut entries = vec![0u8; num_entries as usize * entry_size];
+        read_at(src, entries_lba * sector_size, &mut entries)?;
+
+        return Ok(entries
+            .chunks(entry_size)
+            .enumerate()
+            .filter(|(_, entry)| entry[0..16].iter().any(|byte| *byte != 0))
+            .map(|(idx, entry)| {
+                let first_lba = le_u64(entry, 32);
+                let last_lba = le_u64(entry, 40);
+                PartitionEntry {
+                    index: idx + 1,
+                    start: first_lba * sector_size,
+                    size: (last_lba + 1).saturating_sub(first_lba) * sector_size,
+                    type_id: PartitionTypeId::Gpt(format_guid(&entry[0..16])),
+                }
+            })
+            .collect());
+    }
+
+    Err(Error::with_context(
+        ErrorKind::InvParam,
+        "Protective MBR found but no GPT header",
+    ))
+}
+
+fn parse_partitions<R: Read + Seek>(src: &mut R) -> Result<Vec<PartitionEntry>> {
+    let table = read_mbr(src, 0)?;
+    if table.iter().any(|(ptype, _, _)| *ptype == MBR_PTYPE_GPT) {
+        read_gpt_partitions(src)
+    } else {
+        read_mbr_partitions(src, &table)
+    }
+}
+
+/// Read the partition table of the image file or device src, supports MBR including logical
+/// partitions and GPT
+pub(crate) fn read_partitions(src: &Path) -> Result<Vec<PartitionEntry>> {
+    trace!("read_partitions: entered with '{}'", src.display());
+    let mut file =
+        File::open(src).upstream_with_context(&format!("Failed to open '{}'", src.display()))?;
+    let partitions = parse_partitions(&mut file).error_with_all(
+        ErrorKind::InvParam,
+        &format!("Failed to read partition table from '{}'", src.display()),
+    )?;
+    debug!("read_partitions: found {:?}", partitions);
+    Ok(partitions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn set_mbr_entry(image: &mut [u8], sector: u64, idx: usize, ptype: u8, lba: u32, size: u32) {
+        let sector = (sector * SECTOR_SIZE) as usize;
+        let entry = sector + MBR_TABLE_OFFSET + idx * MBR_ENTRY_SIZE;
+        image[entry + 4] = ptype;
+        image[entry + 8..entry + 12].copy_from_slice(&lba.to_le_bytes());
+        image[entry + 12..entry + 16].copy_from_slice(&size.to_le_bytes());
+        image[sector + 510] = 0x55;
+        image[sector + 511] = 0xAA;
+    }
+
+    #[test]
+    fn parses_mbr_with_logical_partitions() {
+        let mut image = vec![0u8; 64 * SECTOR_SIZE as usize];
+        set_mbr_entry(&mut image, 0, 0, 0x0C, 2, 8);
+        set_mbr_entry(&mut image, 0, 1, 0x05, 16, 40);
+        // first EBR at sector 16, logical partition at 18, next EBR at 16 + 20
+        set_mbr_entry(&mut image, 16, 0, 0x83, 2, 10);
+        set_mbr_entry(&mut image, 16, 1, 0x05, 20, 20);
+        set_mbr_entry(&mut image, 36, 0, 0x83, 2, 6);
+
+        let partitions = parse_partitions(&mut Cursor::new(image)).unwrap();
+        let summary: Vec<(usize, u64, u64)> = partitions
+            .iter()
+            .map(|part| {
+                (
+                    part.get_index(),
+                    part.get_start() / SECTOR_SIZE,
+                    part.get_size() / SECTOR_SIZE,
+                )
+            })
+            .collect();
+        assert_eq!(summary, vec![(1, 2, 8), (5, 18, 10), (6, 38, 6)]);
+        assert_eq!(partitions[0].get_type_id(), &PartitionTypeId::Mbr(0x0C));
+        assert!(partitions[0].is_fat());
+        assert!(!partitions[1].is_fat());
+    }
+
+    #[test]
+    fn parses_gpt() {
+        const EFI_GUID: [u8; 16] = [
+            0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E,
+            0xC9, 0x3B,
+        ];
+        let mut image = vec![0u8; 64 * SECTOR_SIZE as usize];
+        set_mbr_entry(&mut image, 0, 0, MBR_PTYPE_GPT, 1, 63);
+        let header = SECTOR_SIZE as usize;
+        image[header..header + 8].copy_from_slice(GPT_SIGNATURE);
+        image[header + 72..header + 80].copy_from_slice(&2u64.to_le_bytes());
+        image[header + 80..header + 84].copy_from_slice(&4u32.to_le_bytes());
+        image[header + 84..header + 88].copy_from_slice(&128u32.to_le_bytes());
+        let entry = 2 * SECTOR_SIZE as usize + 128;
+        image[entry..entry + 16].copy_from_slice(&EFI_GUID);
+        image[entry + 32..entry + 40].copy_from_slice(&34u64.to_le_bytes());
+        image[entry + 40..entry + 48].copy_from_slice(&41u64.to_le_bytes());
+
+        let partitions = parse_partitions(&mut Cursor::new(image)).unwrap();
+        assert_eq!(partitions.len(), 1);
+        assert_eq!(partitions[0].get_index(), 2);
+        assert_eq!(partitions[0].get_start(), 34 * SECTOR_SIZE);
+        assert_eq!(partitions[0].get_size(), 8 * SECTOR_SIZE);
+        assert_eq!(
+            partitions[0].get_type_id().to_string(),
+            "C12A7328-F81F-11D2-BA4B-00A0C93EC93B"
+        );
+        assert!(partitions[0].is_fat());
+    }
+
+    #[test]
+    fn rejects_missing_signature() {
+        let image = vec![0u8; SECTOR_SIZE as usize];
+        assert!(parse_partitions(&mut Cursor::new(image)).is_err());
+    }
+}
@@ -23,11 +23,25 @@ pub(crate) struct Stage2Config {
     pub log_level: String,
     pub flash_dev: PathBuf,
     pub pretend: bool,
+    #[serde(default)]
+    pub wipe_signatures: bool,
+    #[serde(default)]
+    pub direct_io: bool,
+    #[serde(default)]
+    pub verify_flash: bool,
+    #[serde(default)]
+    pub ignore_partition_check: bool,
+    // SHA-256 and size of the uncompressed image, computed in stage1
+    #[serde(default)]
+    pub image_sha256: Option<String>,
+    #[serde(default)]
+    pub image_size: Option<u64>,
     pub umount_parts: Vec<UmountPart>,
     pub work_dir: PathBuf,
     pub image_path: PathBuf,
     pub config_path: PathBuf,
     pub backup_path: Option<PathBuf>,
+    pub preserve_dir: Option<PathBuf>,
     pub tty: PathBuf,
 }
 
@@ -48,6 +48,37 @@ pub(crate) fn is_sock(stat: &libc::stat) -> bool {
     (stat.st_mode & S_IFMT) == S_IFSOCK
 }
 
+/// Check whether the current process is init. As pid 1 the process has to reap orphaned
+/// processes and must not exit
+pub(crate) fn is_pid1() -> bool {
+    std::process::id() == 1
+}
+
+const SYS_CONSOLE_ACTIVE: &str = "/sys/class/tty/console/active";
+
+/// The kernel lists all consoles, the last one is the one /dev/console refers to
+fn parse_active_console(active: &str) -> Option<&str> {
+    active.split_whitespace().last()
+}
+
+/// Get the device of the console the kernel writes to, eg. /dev/ttyS0 on headless boards or
+/// /dev/tty1 with a display attached
+pub(crate) fn active_console() -> Result<PathBuf> {
+    trace!("active_console: entered");
+    let active = read_to_string(SYS_CONSOLE_ACTIVE)
+        .upstream_with_context(&format!("Failed to read '{}'", SYS_CONSOLE_ACTIVE))?;
+    if let Some(console) = parse_active_console(&active) {
+        let console = PathBuf::from("/dev").join(console);
+        debug!("active_console: found '{}'", console.display());
+        Ok(console)
+    } else {
+        Err(Error::with_context(
+            ErrorKind::NotFound,
+            &format!("No active console found in '{}'", SYS_CONSOLE_ACTIVE),
+        ))
+    }
+}
+
 fn sys_error(message: &str) -> Error {
     let error_kind = match errno() {
         EPERM => ErrorKind::NotPermitted,
@@ -414,6 +445,72 @@ pub(crate) fn fuser<P: AsRef<Path>>(
     }
 }
 
+/// Check if any of the mapped files listed in the content of /proc/<pid>/maps is below path
+fn maps_reference(maps: &str, path: &Path) -> bool {
+    maps.lines()
+        .filter_map(|line| line.split_whitespace().nth(5))
+        .any(|mapped| Path::new(mapped).starts_with(path))
+}
+
+/// Find the processes holding open files, mapped files or their working / root directory
+/// below path, processes that can not be inspected are skipped
+pub(crate) fn processes_using<P: AsRef<Path>>(path: P) -> Result<Vec<u32>> {
+    let path = path.as_ref();
+    trace!("processes_using: entered with '{}'", path.display());
+    let mut pids: Vec<u32> = Vec::new();
+
+    for proc_info in ProcessIterator::new()? {
+        let (pid, directory) = proc_info?;
+
+        let mut links: Vec<PathBuf> = ["cwd", "root", "exe"]
+            .iter()
+            .map(|link| path_append(&directory, link))
+            .collect();
+        if let Ok(read_dir) = read_dir(path_append(&directory, "fd")) {
+            links.extend(
+                read_dir
+                    .filter_map(|entry| entry.ok())
+                    .map(|entry| entry.path()),
+            );
+        }
+
+        let mut in_use = links.iter().any(|link| {
+            read_link(link)
+                .map(|target| target.starts_with(path))
+                .unwrap_or(false)
+        });
+
+        if !in_use {
+            if let Ok(maps) = read_to_string(path_append(&directory, "maps")) {
+                in_use = maps_reference(&maps, path);
+            }
+        }
+
+        if in_use {
+            debug!("processes_using: pid {} uses '{}'", pid, path.display());
+            pids.push(pid as u32);
+        }
+    }
+
+    Ok(pids)
+}
+
+/// Describe the processes using path, eg. 'pid 1234 (sshd), pid 5678 (bash)'
+pub(crate) fn describe_processes_using<P: AsRef<Path>>(path: P) -> String {
+    match processes_using(path) {
+        Ok(pids) if pids.is_empty() => String::from("no process found"),
+        Ok(pids) => pids
+            .iter()
+            .map(|pid| match read_to_string(format!("/proc/{}/comm", pid)) {
+                Ok(comm) => format!("pid {} ({})", pid, comm.trim()),
+                Err(_) => format!("pid {}", pid),
+            })
+            .collect::<Vec<String>>()
+            .join(", "),
+        Err(why) => format!("failed to determine processes, error: {}", why),
+    }
+}
+
 pub(crate) fn uname() -> Result<UtsName> {
     let mut uts_name: utsname = unsafe { MaybeUninit::zeroed().assume_init() };
 
@@ -792,3 +889,43 @@ pub(crate) fn copy_dir<P1: AsRef<Path>, P2: AsRef<Path>>(source: P1, dest: P2) -
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, remove_dir_all, File};
+
+    #[test]
+    fn test_is_pid1() {
+        // tests run in a child of the test harness, never as init
+        assert!(!is_pid1());
+    }
+
+    #[test]
+    fn test_parse_active_console() {
+        assert_eq!(parse_active_console("tty1 ttyS0\n"), Some("ttyS0"));
+        assert_eq!(parse_active_console("tty0\n"), Some("tty0"));
+        assert_eq!(parse_active_console("\n"), None);
+    }
+
+    #[test]
+    fn test_maps_reference() {
+        const MAPS: &str = "55d4c5a00000-55d4c5a28000 r--p 00000000 08:02 1048 /usr/bin/bash\n\
+                            7f2a3c000000-7f2a3c021000 rw-p 00000000 00:00 0 \n\
+                            7ffd1e5f0000-7ffd1e611000 rw-p 00000000 00:00 0 [stack]\n";
+        assert!(maps_reference(MAPS, Path::new("/usr")));
+        assert!(!maps_reference(MAPS, Path::new("/mnt")));
+        assert!(!maps_reference(MAPS, Path::new("/usr/bi")));
+    }
+
+    #[test]
+    fn test_processes_using_own_file() {
+        let dir = std::env::temp_dir().join(format!("takeover_busy_{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        let file = File::create(dir.join("open")).unwrap();
+        let pids = processes_using(&dir).unwrap();
+        drop(file);
+        remove_dir_all(&dir).unwrap();
+        assert!(pids.contains(&std::process::id()));
+    }
+}
@@ -2,7 +2,9 @@ use crate::{
     common::{
         call,
         defs::{MOUNT_CMD, NIX_NONE, PIVOT_ROOT_CMD, TAKEOVER_DIR},
-        get_mountpoint, path_append, whereis, Error, Result, ToError,
+        get_mountpoint, path_append,
+        system::is_pid1,
+        whereis, Error, Result, ToError,
     },
     stage2::{read_stage2_config, reboot},
     ErrorKind,
@@ -28,8 +30,8 @@ use std::time::Duration;
 
 use crate::common::stage2_config::LogDevice;
 use libc::{
-    close, dup2, getpid, open, pipe, sigfillset, sigprocmask, sigset_t, wait, O_CREAT, O_TRUNC,
-    O_WRONLY, SIG_BLOCK, STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO,
+    close, dup2, open, pipe, sigfillset, sigprocmask, sigset_t, wait, O_CREAT, O_TRUNC, O_WRONLY,
+    SIG_BLOCK, STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO,
 };
 
 const INITIAL_LOG_LEVEL: Level = Level::Trace;
@@ -214,7 +216,7 @@ pub fn init() -> ! {
 
     info!("Init entered");
 
-    if unsafe { getpid() } != 1 {
+    if !is_pid1() {
         error!("Process must be pid 1 to run init");
         reboot();
     }
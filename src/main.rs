@@ -12,21 +12,16 @@ use mod_logger::Logger;
 use structopt::StructOpt;
 
 use crate::{
-    common::{error::ErrorKind, Options},
+    common::{error::ErrorKind, system::is_pid1, Options},
     init::init,
     stage1::stage1,
     stage2::stage2,
 };
 
-fn is_init() -> bool {
-    let pid = unsafe { libc::getpid() };
-    pid == 1
-}
-
 fn main() {
     let mut exit_code = 0;
 
-    if is_init() {
+    if is_pid1() {
         init();
     } else {
         let opts = Options::from_args();
@@ -1,7 +1,9 @@
 mod backup;
 
 use std::env::set_current_dir;
-use std::fs::{copy, create_dir, create_dir_all, read_dir, read_link, remove_dir_all, OpenOptions};
+use std::fs::{
+    copy, create_dir, create_dir_all, metadata, read_dir, read_link, remove_dir_all, OpenOptions,
+};
 use std::io::Write;
 use std::os::unix::fs::symlink;
 use std::path::{Path, PathBuf};
@@ -21,34 +23,52 @@ use log::{debug, error, info, warn, Level};
 pub(crate) mod migrate_info;
 
 mod api_calls;
-mod block_device_info;
+pub(crate) mod block_device_info;
 mod defs;
 mod device;
 mod device_impl;
+mod events;
 
 mod exe_copy;
 
+mod image_config;
 mod image_retrieval;
+mod interrupt;
+mod mount_guard;
+mod preflight;
+mod proxy;
+mod steps;
 mod utils;
 mod wifi_config;
 
 use crate::{
     common::{
-        call,
+        available_memory, call,
         defs::{
-            NIX_NONE, OLD_ROOT_MP, STAGE2_CONFIG_NAME, SWAPOFF_CMD, SYSTEM_CONNECTIONS_DIR,
-            SYS_EFIVARS_DIR, SYS_EFI_DIR, TELINIT_CMD,
+            NIX_NONE, OLD_ROOT_MP, STAGE2_CONFIG_NAME, SYSTEM_CONNECTIONS_DIR, SYS_EFIVARS_DIR,
+            SYS_EFI_DIR, TELINIT_CMD,
         },
         error::{Error, ErrorKind, Result, ToError},
         file_exists, format_size_with_unit, get_mem_info, is_admin,
         options::Options,
         path_append,
         stage2_config::{Stage2Config, UmountPart},
-        system::copy_dir,
+        system::{active_console, copy_dir},
     },
     stage1::{
-        block_device_info::BlockDevice, block_device_info::BlockDeviceInfo, exe_copy::ExeCopy,
-        migrate_info::MigrateInfo, utils::mount_fs,
+        block_device_info::BlockDevice,
+        block_device_info::BlockDeviceInfo,
+        block_device_info::{check_flash_target, check_not_raid, is_removable},
+        events::Stage1Event,
+        exe_copy::ExeCopy,
+        interrupt::{check_interrupted, install_interrupt_handler},
+        migrate_info::MigrateInfo,
+        preflight::preflight,
+        steps::{run_steps, Stage1Step, StepRecord},
+        utils::{
+            ensure_modules, get_secure_boot_state, init_logging, is_already_balena, mktemp,
+            mount_fs, rollback, stage_preserve_files, swapoff_all, SecureBootState,
+        },
     },
 };
 
@@ -56,8 +76,10 @@ use crate::common::defs::{DD_CMD, EFIBOOTMGR_CMD, TAKEOVER_DIR};
 use crate::common::dir_exists;
 use crate::common::stage2_config::LogDevice;
 use crate::common::system::{is_dir, mkdir, stat};
-use mod_logger::{LogDestination, Logger, NO_STREAM};
+use mod_logger::Logger;
 
+// needed to mount the boot partition through a loop device
+const REQUIRED_MODULES: [&str; 2] = ["loop", "vfat"];
 const S1_XTRA_FS_SIZE: u64 = 10 * 1024 * 1024; // const XTRA_MEM_FREE: u64 = 10 * 1024 * 1024; // 10 MB
 
 fn prepare_configs<P1: AsRef<Path>>(
@@ -150,7 +172,7 @@ fn mount_sys_filesystems(
     // *********************************************************
     // mount tmpfs
 
-    mount_fs(&takeover_dir, "tmpfs", "tmpfs", None)?;
+    mount_fs(&takeover_dir, "tmpfs", "tmpfs", Some(mig_info))?;
 
     let curr_path = takeover_dir.join("etc");
     create_dir(&curr_path).upstream_with_context(&format!(
@@ -214,37 +236,46 @@ fn mount_sys_filesystems(
     Ok(())
 }
 
-fn prepare(opts: &Options, mig_info: &mut MigrateInfo) -> Result<()> {
-    info!("Preparing for takeover..");
-
-    // *********************************************************
-    // turn off swap
-    call_command!(SWAPOFF_CMD, &["-a"], "Failed to disable SWAP")?;
-
-    // *********************************************************
-    // calculate required memory
-
-    let mut req_space: u64 = 0;
+/// The commands stage2 needs in the takeover directory, efibootmgr is only needed to set up
+/// EFI boot on x86 systems
+fn copy_commands(opts: &Options, is_x86: bool) -> Result<Vec<&'static str>> {
     let mut copy_commands = vec![DD_CMD];
-    if mig_info.is_x86() && !opts.no_efi_setup() && dir_exists(SYS_EFI_DIR)? {
+    if is_x86 && !opts.no_efi_setup() && dir_exists(SYS_EFI_DIR)? {
         copy_commands.push(EFIBOOTMGR_CMD)
     }
+    Ok(copy_commands)
+}
 
-    let commands = match ExeCopy::new(copy_commands) {
-        Ok(commands) => {
-            let cmd_space = commands.get_req_space();
-            debug!(
-                "Space required for commands: {}",
-                format_size_with_unit(cmd_space)
-            );
-            req_space += cmd_space;
-            commands
-        }
-        Err(why) => {
-            return Err(Error::from_upstream_error(
-                Box::new(why),
-                "Failed to gather dependencies for copied commands",
-            ));
+/// Calculate the memory required in the takeover tmpfs and check it is available. Returns the
+/// commands to copy to the takeover directory, in dry run mode their dependencies are not
+/// resolved as that requires running ldd
+fn check_memory(opts: &Options, mig_info: &MigrateInfo) -> Result<Option<ExeCopy>> {
+    let mut req_space: u64 = 0;
+    let copy_commands = copy_commands(opts, mig_info.is_x86())?;
+
+    let commands = if mig_info.dry_run() {
+        info!(
+            "Dry run - not resolving the dependencies of {:?}, the memory check does not include them",
+            copy_commands
+        );
+        None
+    } else {
+        match ExeCopy::new(copy_commands) {
+            Ok(commands) => {
+                let cmd_space = commands.get_req_space();
+                debug!(
+                    "Space required for commands: {}",
+                    format_size_with_unit(cmd_space)
+                );
+                req_space += cmd_space;
+                Some(commands)
+            }
+            Err(why) => {
+                return Err(Error::from_upstream_error(
+                    Box::new(why),
+                    "Failed to gather dependencies for copied commands",
+                ));
+            }
         }
     };
 
@@ -264,8 +295,45 @@ fn prepare(opts: &Options, mig_info: &mut MigrateInfo) -> Result<()> {
         )));
     }
 
-    // *********************************************************
-    // make mountpoint for tmpfs
+    // stage2 copies the image to a tmpfs before flashing, make sure it does not run out of memory
+    let image_path = mig_info.image_path()?;
+    let image_size = metadata(image_path)
+        .upstream_with_context(&format!(
+            "Failed to read size of image '{}'",
+            image_path.display()
+        ))?
+        .len();
+    let mem_available = available_memory()?;
+    if mem_available < req_space + image_size + S1_XTRA_FS_SIZE {
+        return Err(Error::with_context(ErrorKind::InvState, &format!(
+            "Not enough memory available to stage the image in RAMFS, required size is {} (image {}) available memory is {}",
+            format_size_with_unit(req_space + image_size + S1_XTRA_FS_SIZE),
+            format_size_with_unit(image_size),
+            format_size_with_unit(mem_available)
+        )));
+    }
+
+    Ok(commands)
+}
+
+/// Create or reuse the empty takeover directory. In dry run mode a temporary directory in the
+/// staging or work directory is used, as no tmpfs is mounted on it
+fn create_takeover_dir(mig_info: &mut MigrateInfo) -> Result<()> {
+    if mig_info.dry_run() {
+        let parent = mig_info
+            .staging_dir()
+            .unwrap_or_else(|| mig_info.work_dir())
+            .to_path_buf();
+        let takeover_dir = mktemp(true, Some("takeover."), None, Some(parent))?;
+        info!(
+            "Dry run - using '{}' instead of '{}' as takeover directory",
+            takeover_dir.display(),
+            TAKEOVER_DIR
+        );
+        mig_info.set_to_dir(&takeover_dir);
+        return Ok(());
+    }
+
     let takeover_dir = PathBuf::from(TAKEOVER_DIR);
     match stat(&takeover_dir) {
         Ok(stat) => {
@@ -309,12 +377,11 @@ fn prepare(opts: &Options, mig_info: &mut MigrateInfo) -> Result<()> {
     mig_info.set_to_dir(&takeover_dir);
 
     info!("Using '{}' as takeover directory", takeover_dir.display());
+    Ok(())
+}
 
-    mount_sys_filesystems(&takeover_dir, mig_info, opts)?;
-
-    // *********************************************************
-    // create mountpoint for old root
-
+/// Create the mountpoint for the old root and copy the commands needed in stage2
+fn copy_files(takeover_dir: &Path, commands: &ExeCopy) -> Result<()> {
     let curr_path = path_append(&takeover_dir, OLD_ROOT_MP);
 
     create_dir_all(&curr_path).upstream_with_context(&format!(
@@ -325,23 +392,49 @@ fn prepare(opts: &Options, mig_info: &mut MigrateInfo) -> Result<()> {
     info!("Created directory '{}'", curr_path.display());
 
     commands.copy_files(&takeover_dir)?;
+    Ok(())
+}
 
+/// Stage the configuration files and the files to preserve, returns the directory holding the
+/// preserved files as seen from stage2
+fn stage_configs(
+    opts: &Options,
+    mig_info: &mut MigrateInfo,
+    takeover_dir: &Path,
+) -> Result<Option<PathBuf>> {
     prepare_configs(opts.work_dir(), mig_info)?;
 
-    // *********************************************************
-    // setup new init
-
-    let old_init_path =
-        read_link("/proc/1/exe").upstream_with_context("Failed to read link for /proc/1/exe")?;
-
-    // TODO: make new_init_path point to /$takeover_dir/bin/takeover directly
-    let new_init_path = path_append(&takeover_dir, &format!("/bin/{}", env!("CARGO_PKG_NAME")));
-    // Assets::write_stage2_script(&takeover_dir, &new_init_path, &tty, opts.get_s2_log_level())?;
+    // the takeover directory becomes the root file system in stage2, the hostname is preserved
+    // even if no files were given
+    let staging_dir = stage_preserve_files(opts.preserve(), mig_info)?;
+    let preserve_dir = path_append(
+        "/",
+        staging_dir
+            .strip_prefix(&takeover_dir)
+            .upstream_with_context(&format!(
+                "Failed to strip '{}' from '{}'",
+                takeover_dir.display(),
+                staging_dir.display()
+            ))?,
+    );
+    Ok(Some(preserve_dir))
+}
 
+fn write_stage2_config(
+    opts: &Options,
+    mig_info: &MigrateInfo,
+    takeover_dir: &Path,
+    preserve_dir: Option<PathBuf>,
+) -> Result<()> {
     let block_dev_info = BlockDeviceInfo::new()?;
 
     let flash_dev = if let Some(flash_dev) = opts.flash_to() {
         if let Some(flash_dev) = block_dev_info.get_devices().get(flash_dev) {
+            check_flash_target(
+                &flash_dev.get_dev_path(),
+                &block_dev_info.get_root_device().get_dev_path(),
+                opts.allow_self(),
+            )?;
             flash_dev
         } else {
             return Err(Error::with_context(
@@ -366,6 +459,36 @@ fn prepare(opts: &Options, mig_info: &mut MigrateInfo) -> Result<()> {
         ));
     }
 
+    check_not_raid(&flash_dev.get_dev_path())?;
+
+    let removable = match is_removable(&flash_dev.get_dev_path()) {
+        Ok(removable) => removable,
+        Err(why) => {
+            warn!(
+                "Failed to determine whether '{}' is removable, error: {}",
+                flash_dev.get_dev_path().display(),
+                why
+            );
+            false
+        }
+    };
+
+    if removable {
+        if opts.refuse_removable() {
+            return Err(Error::with_context(
+                ErrorKind::InvState,
+                &format!(
+                    "The device '{}' is removable, refusing to flash it due to the refuse-removable option",
+                    flash_dev.get_dev_path().display()
+                ),
+            ));
+        }
+        warn!(
+            "*** The device '{}' is removable, are you sure you want to flash it? ***",
+            flash_dev.get_dev_path().display()
+        );
+    }
+
     let log_device = if let Some(log_dev_path) = opts.log_to() {
         if let Some(log_dev) = block_dev_info.get_devices().get(log_dev_path) {
             if let Some(partition_info) = log_dev.get_partition_info() {
@@ -402,6 +525,11 @@ fn prepare(opts: &Options, mig_info: &mut MigrateInfo) -> Result<()> {
         None
     };
 
+    // stage2 checks the image against the digest taken while it was staged before anything
+    // is written
+    let image_path = mig_info.image_path()?;
+    let (image_sha256, image_size) = mig_info.image_digest()?;
+
     // collect partitions that need to be unmounted
 
     let s2_cfg = Stage2Config {
@@ -409,6 +537,12 @@ fn prepare(opts: &Options, mig_info: &mut MigrateInfo) -> Result<()> {
         log_level: opts.s2_log_level().to_string(),
         flash_dev: flash_dev.get_dev_path(),
         pretend: opts.pretend(),
+        wipe_signatures: opts.wipe_signatures(),
+        direct_io: opts.direct_io(),
+        verify_flash: opts.verify_flash(),
+        ignore_partition_check: opts.ignore_partition_check(),
+        image_sha256: Some(image_sha256.to_string()),
+        image_size: Some(image_size),
         umount_parts: get_umount_parts(flash_dev, &block_dev_info)?,
         work_dir: opts
             .work_dir()
@@ -417,15 +551,25 @@ fn prepare(opts: &Options, mig_info: &mut MigrateInfo) -> Result<()> {
                 "Failed to canonicalize work dir '{}'",
                 opts.work_dir().display()
             ))?,
-        image_path: mig_info.image_path().to_path_buf(),
+        image_path: image_path.to_path_buf(),
         config_path: mig_info.balena_cfg().get_path().to_path_buf(),
         backup_path: if let Some(backup_path) = mig_info.backup() {
             Some(backup_path.to_owned())
         } else {
             None
         },
-        tty: read_link("/proc/self/fd/1")
-            .upstream_with_context("Failed to read tty from '/proc/self/fd/1'")?,
+        preserve_dir,
+        tty: match active_console() {
+            Ok(console) => console,
+            Err(why) => {
+                warn!(
+                    "Failed to find the active console, stage2 output goes to the current tty, error: {}",
+                    why
+                );
+                read_link("/proc/self/fd/1")
+                    .upstream_with_context("Failed to read tty from '/proc/self/fd/1'")?
+            }
+        },
     };
 
     let s2_cfg_path = takeover_dir.join(STAGE2_CONFIG_NAME);
@@ -449,6 +593,25 @@ fn prepare(opts: &Options, mig_info: &mut MigrateInfo) -> Result<()> {
         ))?;
 
     info!("Wrote stage2 config to '{}'", s2_cfg_path.display());
+    Ok(())
+}
+
+fn replace_init(takeover_dir: &Path, dry_run: bool) -> Result<()> {
+    // TODO: make new_init_path point to /$takeover_dir/bin/takeover directly
+    let new_init_path = path_append(&takeover_dir, &format!("/bin/{}", env!("CARGO_PKG_NAME")));
+    // Assets::write_stage2_script(&takeover_dir, &new_init_path, &tty, opts.get_s2_log_level())?;
+
+    if dry_run {
+        info!(
+            "Dry run - would bind-mount '{}' on init and restart it with '{} u'",
+            new_init_path.display(),
+            TELINIT_CMD
+        );
+        return Ok(());
+    }
+
+    let old_init_path =
+        read_link("/proc/1/exe").upstream_with_context("Failed to read link for /proc/1/exe")?;
 
     set_current_dir(&takeover_dir).upstream_with_context(&format!(
         "Failed to change current dir to '{}'",
@@ -484,10 +647,122 @@ fn prepare(opts: &Options, mig_info: &mut MigrateInfo) -> Result<()> {
     Ok(())
 }
 
+/// Refuse to migrate x86 systems with secure boot enabled
+fn check_secure_boot(opts: &Options, mig_info: &MigrateInfo) -> Result<()> {
+    if !opts.migrate() || !mig_info.is_x86() {
+        return Ok(());
+    }
+
+    let secure_boot = get_secure_boot_state()?;
+    mig_info.emit(Stage1Event::SecureBootChecked {
+        enabled: secure_boot == SecureBootState::Enabled,
+    });
+
+    match secure_boot {
+        SecureBootState::Enabled => {
+            error!(
+                "{} does not currently support systems with secure boot enabled.",
+                env!("CARGO_PKG_NAME")
+            );
+            Err(Error::displayed())
+        }
+        SecureBootState::SetupMode => {
+            // no platform key is enrolled so secure boot is not enforced
+            warn!("Secure boot is in setup mode, proceeding");
+            Ok(())
+        }
+        SecureBootState::Disabled | SecureBootState::Unsupported => {
+            info!("Secure boot is not enabled");
+            Ok(())
+        }
+    }
+}
+
+/// Values computed by one stage1 step and used by later ones
+#[derive(Default)]
+struct StepState {
+    commands: Option<ExeCopy>,
+    preserve_dir: Option<PathBuf>,
+}
+
+fn run_step(
+    step: Stage1Step,
+    opts: &Options,
+    mig_info: &mut MigrateInfo,
+    state: &mut StepState,
+) -> Result<()> {
+    let takeover_dir = mig_info
+        .to_dir()
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(TAKEOVER_DIR));
+    match step {
+        Stage1Step::DetectDevice => mig_info.detect_device(opts),
+        Stage1Step::CheckSecureBoot => check_secure_boot(opts, mig_info),
+        Stage1Step::ResolveRoot => mig_info.resolve_root(opts),
+        Stage1Step::StageImage => mig_info.stage_image(opts),
+        Stage1Step::ScanNetworks => mig_info.scan_networks(opts),
+        Stage1Step::CreateBackup => mig_info.create_backup(opts),
+        Stage1Step::SwapOff => swapoff_all(mig_info),
+        Stage1Step::CheckMemory => {
+            state.commands = check_memory(opts, mig_info)?;
+            Ok(())
+        }
+        Stage1Step::CreateTakeoverDir => create_takeover_dir(mig_info),
+        Stage1Step::MountFilesystems => {
+            mount_sys_filesystems(&takeover_dir, mig_info, opts)?;
+            Ok(())
+        }
+        Stage1Step::CopyFiles => {
+            if mig_info.dry_run() {
+                info!(
+                    "Dry run - would copy the commands needed in stage2 to '{}'",
+                    takeover_dir.display()
+                );
+                Ok(())
+            } else if let Some(commands) = &state.commands {
+                copy_files(&takeover_dir, commands)
+            } else {
+                Err(Error::with_context(
+                    ErrorKind::InvState,
+                    "No commands to copy, the memory check did not run",
+                ))
+            }
+        }
+        Stage1Step::StageConfigs => {
+            state.preserve_dir = stage_configs(opts, mig_info, &takeover_dir)?;
+            Ok(())
+        }
+        Stage1Step::WriteStage2Config => {
+            if mig_info.dry_run() {
+                info!(
+                    "Dry run - would write the stage2 config to '{}'",
+                    takeover_dir.join(STAGE2_CONFIG_NAME).display()
+                );
+                Ok(())
+            } else {
+                write_stage2_config(opts, mig_info, &takeover_dir, state.preserve_dir.clone())
+            }
+        }
+        Stage1Step::ReplaceInit => replace_init(&takeover_dir, mig_info.dry_run()),
+    }
+}
+
+fn prepare(opts: &Options, mig_info: &mut MigrateInfo) -> Result<()> {
+    info!("Preparing for takeover..");
+
+    let mut state = StepState::default();
+    run_steps(&Stage1Step::PREPARE, mig_info, |step, mig_info| {
+        // interrupts are handled between steps, once init was replaced they can not be undone
+        check_interrupted()?;
+        run_step(step, opts, mig_info, &mut state)
+    })
+}
+
 pub fn stage1(opts: &Options) -> Result<()> {
-    Logger::set_default_level(opts.log_level());
-    Logger::set_brief_info(true);
-    Logger::set_color(true);
+    init_logging(
+        opts.log_level().to_level_filter(),
+        opts.log_file().as_deref(),
+    )?;
 
     /*
         if opts.config().is_none() {
@@ -497,36 +772,58 @@ pub fn stage1(opts: &Options) -> Result<()> {
         }
     */
 
-    if let Some(s1_log_path) = opts.log_file() {
-        Logger::set_log_file(&LogDestination::StreamStderr, &s1_log_path, true)
-            .upstream_with_context(&format!(
-                "Failed to set logging to '{}'",
-                s1_log_path.display(),
-            ))?;
-    } else {
-        Logger::set_log_dest(&LogDestination::Stderr, NO_STREAM)
-            .upstream_with_context("Failed to set up logging")?;
+    if !is_admin()? {
+        error!("please run this program as root");
+        return Err(Error::displayed());
     }
 
-    let mut mig_info = match MigrateInfo::new(&opts) {
-        Ok(mig_info) => mig_info,
-        Err(why) => {
-            if why.kind() == ErrorKind::ImageDownloaded {
-                return Ok(());
-            } else {
-                return Err(Error::from_upstream(
-                    Box::new(why),
-                    "Failed to create migrate info",
-                ));
-            }
-        }
-    };
+    if opts.preflight() {
+        let report = preflight(opts)?;
+        report.log();
+        return if report.has_failures() {
+            error!("Preflight checks failed, the device can not be migrated");
+            Err(Error::displayed())
+        } else {
+            info!("Preflight checks passed");
+            Ok(())
+        };
+    }
 
-    if !is_admin()? {
-        error!("please run this program as root");
+    // checked before MigrateInfo downloads the image and creates the backup
+    if opts.migrate() && is_already_balena()? {
+        info!("This device already runs balenaOS, there is nothing to migrate");
         return Err(Error::displayed());
     }
 
+    let mut mig_info = MigrateInfo::new(opts)
+        .map_err(|why| Error::from_upstream(Box::new(why), "Failed to create migrate info"))?;
+
+    // the setup steps do not modify the system, a failure needs no rollback
+    let setup_steps: &[Stage1Step] = if opts.migrate() {
+        &Stage1Step::SETUP
+    } else {
+        &Stage1Step::DOWNLOAD
+    };
+    let mut state = StepState::default();
+    if let Err(why) = run_steps(setup_steps, &mut mig_info, |step, mig_info| {
+        run_step(step, opts, mig_info, &mut state)
+    }) {
+        match mig_info.completed_step() {
+            Some(step) => error!("Setup failed after completing step {:?}", step),
+            None => error!("Setup failed before completing any step"),
+        }
+        return Err(why);
+    }
+
+    if !opts.migrate() {
+        info!("Image downloaded successfully");
+        return Ok(());
+    }
+
+    if opts.migrate() {
+        ensure_modules(&REQUIRED_MODULES)?;
+    }
+
     if !opts.no_ack() {
         println!("{} will prepare your device for migration. Are you sure you want to migrate this device: [Y/n]", env!("CARGO_PKG_NAME"));
         loop {
@@ -556,7 +853,14 @@ pub fn stage1(opts: &Options) -> Result<()> {
     }
 
     if opts.migrate() {
+        // interrupts are handled from here on so mounts made in prepare are rolled back
+        install_interrupt_handler()?;
         match prepare(&opts, &mut mig_info) {
+            Ok(_) if mig_info.dry_run() => {
+                info!("Dry run - takeover prepared, not handing over to stage2");
+                mig_info.remove_to_dir();
+                Ok(())
+            }
             Ok(_) => {
                 info!("Takeover initiated successfully, please wait for the device to be reflashed and reboot");
                 Logger::flush();
@@ -565,8 +869,14 @@ pub fn stage1(opts: &Options) -> Result<()> {
                 Ok(())
             }
             Err(why) => {
+                match mig_info.completed_step() {
+                    Some(step) => error!("Takeover failed after completing step {:?}", step),
+                    None => error!("Takeover failed before completing any step"),
+                }
                 if opts.cleanup() {
-                    mig_info.umount_all();
+                    if let Err(rb_why) = rollback(&mut mig_info) {
+                        error!("Failed to roll back takeover, error: {}", rb_why);
+                    }
                 }
                 Err(why)
             }
@@ -575,3 +885,59 @@ pub fn stage1(opts: &Options) -> Result<()> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::prepare;
+    use crate::common::command_runner::{mock::MockRunner, with_runner};
+    use crate::common::options::Options;
+    use crate::stage1::defs::DeviceType;
+    use crate::stage1::device::Device;
+    use crate::stage1::migrate_info::{balena_cfg_json::BalenaCfgJson, MigrateInfo};
+    use std::fs::{create_dir_all, remove_dir_all, write};
+    use std::rc::Rc;
+    use structopt::StructOpt;
+
+    struct TestDevice;
+
+    impl Device for TestDevice {
+        fn supports_device_type(&self, dev_type: &str) -> bool {
+            dev_type == "raspberrypi3"
+        }
+
+        fn get_device_type(&self) -> DeviceType {
+            DeviceType::RaspberryPi3
+        }
+    }
+
+    #[test]
+    fn dry_run_prepare_runs_no_commands() {
+        let work_dir =
+            std::env::temp_dir().join(format!("takeover_dry_run_{}", std::process::id()));
+        create_dir_all(&work_dir).unwrap();
+        let config_path = work_dir.join("config.json");
+        write(&config_path, r#"{"deviceType":"raspberrypi3"}"#).unwrap();
+        let image_path = work_dir.join("balena.img.gz");
+        write(&image_path, b"image").unwrap();
+
+        let opts = Options::from_iter(vec![
+            "takeover",
+            "--dry-run",
+            "--work-dir",
+            work_dir.to_str().unwrap(),
+        ]);
+        let mut mig_info = MigrateInfo::for_test(
+            &opts,
+            Box::new(TestDevice),
+            BalenaCfgJson::new(&config_path).unwrap(),
+            image_path,
+        );
+
+        let runner = Rc::new(MockRunner::default());
+        let res = with_runner(runner.clone(), || prepare(&opts, &mut mig_info));
+        mig_info.remove_to_dir();
+        remove_dir_all(&work_dir).unwrap();
+        res.unwrap();
+        assert!(runner.calls().is_empty(), "{:?}", runner.calls());
+    }
+}
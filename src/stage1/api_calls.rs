@@ -1,17 +1,23 @@
 use std::io::Read;
+use std::time::Duration;
 
 use log::debug;
 
-use reqwest::{blocking::Client, header};
+use reqwest::{blocking::Client, header, redirect::Policy};
 use serde::{Deserialize, Serialize};
 
-use crate::common::{Error, ErrorKind, Result, ToError};
+use crate::{
+    common::{Error, ErrorKind, Result, ToError},
+    stage1::proxy::with_env_proxy,
+};
 
 const OS_VERSION_URL_P1: &str = "/device-types/v1/";
 const OS_VERSION_URL_P2: &str = "/images";
 
 const OS_IMG_URL: &str = "/download";
 
+pub(crate) const API_PING_URL: &str = "/ping";
+
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct Versions {
     pub versions: Vec<String>,
@@ -27,6 +33,32 @@ struct ImageRequestData {
     file_type: String,
 }
 
+/// Send a GET request to url and check that it is answered with a 2xx or 3xx status
+pub(crate) fn check_https_reachable(url: &str, timeout: u64) -> Result<()> {
+    debug!("check_https_reachable: request_url: '{}'", url);
+
+    let res = with_env_proxy(Client::builder(), url)
+        .timeout(Duration::from_secs(timeout))
+        .redirect(Policy::none())
+        .build()
+        .upstream_with_context("Failed to create https client")?
+        .get(url)
+        .send()
+        .upstream_with_context(&format!("Failed to send https request url: '{}'", url))?;
+
+    debug!("Result = {:?}", res);
+
+    let status = res.status();
+    if status.is_success() || status.is_redirection() {
+        Ok(())
+    } else {
+        Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!("Request to '{}' failed with status: {}", url, status),
+        ))
+    }
+}
+
 pub(crate) fn get_os_versions(api_endpoint: &str, api_key: &str, device: &str) -> Result<Versions> {
     let mut headers = header::HeaderMap::new();
     headers.insert(
@@ -42,7 +74,7 @@ pub(crate) fn get_os_versions(api_endpoint: &str, api_key: &str, device: &str) -
 
     debug!("get_os_versions: request_url: '{}'", request_url);
 
-    let res = Client::builder()
+    let res = with_env_proxy(Client::builder(), &request_url)
         .default_headers(headers)
         .build()
         .upstream_with_context("Failed to create https client")?
@@ -68,12 +100,13 @@ pub(crate) fn get_os_versions(api_endpoint: &str, api_key: &str, device: &str) -
     }
 }
 
+/// Request the OS image, returns the image stream and its size if the server sent it
 pub(crate) fn get_os_image(
     api_endpoint: &str,
     api_key: &str,
     device: &str,
     version: &str,
-) -> Result<Box<dyn Read>> {
+) -> Result<(Box<dyn Read>, Option<u64>)> {
     let mut headers = header::HeaderMap::new();
     headers.insert(
         header::AUTHORIZATION,
@@ -92,7 +125,7 @@ pub(crate) fn get_os_image(
     debug!("get_os_image: request_url: '{}'", request_url);
     debug!("get_os_image: data: '{:?}'", post_data);
 
-    let res = Client::builder()
+    let res = with_env_proxy(Client::builder(), &request_url)
         .default_headers(headers)
         .build()
         .upstream_with_context("Failed to create https client")?
@@ -106,5 +139,6 @@ pub(crate) fn get_os_image(
 
     debug!("Result = {:?}", res);
 
-    Ok(Box::new(res))
+    let size = res.content_length();
+    Ok((Box::new(res), size))
 }
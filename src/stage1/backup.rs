@@ -110,10 +110,14 @@ fn archive_dir<'a>(
 }
 
 #[allow(dead_code)]
-pub(crate) fn create_ext(file: &Path, config: Vec<VolumeConfig>) -> Result<bool> {
+pub(crate) fn create_ext(
+    file: &Path,
+    config: Vec<VolumeConfig>,
+    staging_dir: Option<&Path>,
+) -> Result<bool> {
     if !config.is_empty() {
         info!("creating new backup in '{}", file.display());
-        let mut archiver = ExtTarArchiver::new(file)?;
+        let mut archiver = ExtTarArchiver::new(file, staging_dir)?;
         if create_int(&mut archiver, config)? {
             info!("The backup was created successfully");
             Ok(true)
@@ -26,10 +26,11 @@ pub(crate) struct ExtTarArchiver {
 
 #[cfg(target_os = "linux")]
 impl ExtTarArchiver {
-    pub fn new<P: AsRef<Path>>(file: P) -> Result<ExtTarArchiver> {
-        const NO_PATH: Option<&Path> = None;
+    /// Create an archiver for file, the links are collected in a temporary directory in
+    /// staging_dir if given
+    pub fn new<P: AsRef<Path>>(file: P, staging_dir: Option<&Path>) -> Result<ExtTarArchiver> {
         Ok(ExtTarArchiver {
-            tmp_dir: mktemp(true, None, None, NO_PATH)?,
+            tmp_dir: mktemp(true, None, None, staging_dir)?,
             archive: PathBuf::from(file.as_ref()),
         })
     }
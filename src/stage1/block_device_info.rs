@@ -1,17 +1,22 @@
-use crate::common::{path_append, Error, Result, ToError};
+use crate::common::{
+    defs::{DISK_BY_LABEL_PATH, DISK_BY_PARTUUID_PATH, DISK_BY_UUID_PATH, SYS_CLASS_BLOCK},
+    path_append, Error, Result, ToError,
+};
 
 use lazy_static::lazy_static;
-use log::{debug, trace};
+use libc::{EACCES, EROFS};
+use log::{debug, trace, warn};
 use nix::sys::stat::{major, minor, stat};
 use regex::Regex;
 use std::collections::HashMap;
 use std::fmt;
-use std::fs::{read_dir, read_to_string};
+use std::fs::{canonicalize, read_dir, read_to_string, File, OpenOptions};
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::result;
 
-mod mount;
+pub(crate) mod mount;
 use mount::{Mount, MountTab};
 
 pub(crate) mod block_device;
@@ -32,8 +37,675 @@ const BLOC_DEV_SUPP_MAJ_NUMBERS: [u64; 45] = [
     70, 71, 72, 73, 74, 75, 76, 77, 78, 79, 80, 81, 82, 83, 84, 85, 86, 87, 179, 180, 259,
 ];
 
+const PROC_CMDLINE: &str = "/proc/cmdline";
+const PROC_MDSTAT: &str = "/proc/mdstat";
+const SYS_SECTOR_SIZE: u64 = 512;
+// root file systems that span or subdivide devices and can not be handled by flashing the disk
+const UNSUPPORTED_ROOT_FS_TYPES: [&str; 3] = ["btrfs", "zfs", "bcachefs"];
+
 type DeviceMap = HashMap<PathBuf, Rc<dyn BlockDevice>>;
 
+/// Find the device mounted on '/'.
+/// Falls back to the root= kernel parameter if / is not backed by a device, eg. when running
+/// on overlay or rootfs
+#[allow(dead_code)]
+pub(crate) fn root_device(tab: &MountTab) -> Result<PathBuf> {
+    trace!("root_device: entered");
+    if let Some((device, _mount)) = tab
+        .iter()
+        .find(|(_, mount)| mount.get_mountpoint() == Path::new("/"))
+    {
+        debug!("root_device: found root device '{}'", device.display());
+        return Ok(device.clone());
+    }
+
+    debug!("root_device: no device mounted on /, trying kernel command line");
+    root_from_cmdline()
+}
+
+/// Resolve the root= kernel parameter to a device path. Handles PARTUUID=, UUID=, LABEL= and
+/// plain /dev/* values
+pub(crate) fn root_from_cmdline() -> Result<PathBuf> {
+    trace!("root_from_cmdline: entered");
+    let cmdline = read_to_string(PROC_CMDLINE)
+        .upstream_with_context(&format!("Failed to read from '{}'", PROC_CMDLINE))?;
+
+    let root = if let Some(root) = cmdline
+        .split_whitespace()
+        .find_map(|param| param.strip_prefix("root="))
+    {
+        root
+    } else {
+        return Err(Error::with_context(
+            ErrorKind::NotFound,
+            &format!("No root= parameter found in '{}'", PROC_CMDLINE),
+        ));
+    };
+
+    let dev_path = if let Some(part_uuid) = root.strip_prefix("PARTUUID=") {
+        path_append(DISK_BY_PARTUUID_PATH, part_uuid.to_lowercase())
+    } else if let Some(uuid) = root.strip_prefix("UUID=") {
+        path_append(DISK_BY_UUID_PATH, uuid.to_lowercase())
+    } else if let Some(label) = root.strip_prefix("LABEL=") {
+        path_append(DISK_BY_LABEL_PATH, label)
+    } else if root.starts_with("/dev/") {
+        PathBuf::from(root)
+    } else {
+        return Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!("Unsupported kernel parameter root={}", root),
+        ));
+    };
+
+    match canonicalize(&dev_path) {
+        Ok(device) => {
+            debug!(
+                "root_from_cmdline: resolved root={} to '{}'",
+                root,
+                device.display()
+            );
+            Ok(device)
+        }
+        Err(why) => Err(Error::with_all(
+            ErrorKind::NotFound,
+            &format!(
+                "Unable to resolve kernel parameter root={} using '{}'",
+                root,
+                dev_path.display()
+            ),
+            Box::new(why),
+        )),
+    }
+}
+
+/// Find the /sys/class/block directory of a disk or partition device
+fn sys_block_dir(dev: &Path) -> Result<PathBuf> {
+    let dev_path = match canonicalize(dev) {
+        Ok(dev_path) => dev_path,
+        Err(why) => {
+            return Err(Error::with_all(
+                ErrorKind::NotFound,
+                &format!("Failed to canonicalize path '{}'", dev.display()),
+                Box::new(why),
+            ))
+        }
+    };
+    let dev_name = BlockDeviceInfo::path_filename_as_string(&dev_path)?;
+    let sys_path = path_append(SYS_CLASS_BLOCK, &dev_name);
+    if sys_path.exists() {
+        Ok(sys_path)
+    } else {
+        Err(Error::with_context(
+            ErrorKind::NotFound,
+            &format!(
+                "No sysfs entry '{}' found for device '{}'",
+                sys_path.display(),
+                dev.display()
+            ),
+        ))
+    }
+}
+
+/// Get the size of a disk or partition device in bytes
+#[allow(dead_code)]
+pub(crate) fn get_device_size(dev: &Path) -> Result<u64> {
+    let size_path = path_append(sys_block_dir(dev)?, "size");
+    let size_str = read_to_string(&size_path)
+        .upstream_with_context(&format!("Failed to read from '{}'", size_path.display()))?;
+    let sectors = size_str
+        .trim()
+        .parse::<u64>()
+        .upstream_with_context(&format!(
+            "Failed to parse device size '{}' from '{}'",
+            size_str.trim(),
+            size_path.display()
+        ))?;
+    Ok(sectors * SYS_SECTOR_SIZE)
+}
+
+/// Check whether dev is a whole disk rather than a partition
+#[allow(dead_code)]
+pub(crate) fn is_whole_disk(dev: &Path) -> Result<bool> {
+    // the partition attribute only exists for partitions
+    Ok(!path_append(sys_block_dir(dev)?, "partition").exists())
+}
+
+// disks with names ending in a digit, their partition numbers are separated by a 'p'
+const DIGIT_SUFFIX_DISKS: [&str; 4] = ["nvme", "mmcblk", "loop", "nbd"];
+
+/// Derive the disk name from a partition name, eg. sda for sda2, nvme0n1 for nvme0n1p3 or
+/// mmcblk1 for mmcblk1p2. Returns None if the name does not look like a partition.
+fn disk_name_from_partition(partition: &str) -> Option<&str> {
+    let disk = partition.trim_end_matches(|c: char| c.is_ascii_digit());
+    if disk.is_empty() || disk.len() == partition.len() {
+        return None;
+    }
+
+    if DIGIT_SUFFIX_DISKS
+        .iter()
+        .any(|prefix| partition.starts_with(prefix))
+    {
+        // only strip the 'p<N>' suffix if the remaining disk name ends in a digit
+        disk.strip_suffix('p')
+            .filter(|disk| disk.ends_with(|c: char| c.is_ascii_digit()))
+    } else {
+        Some(disk)
+    }
+}
+
+/// Find the disk containing partition, eg. /dev/nvme0n1 for /dev/nvme0n1p1
+#[allow(dead_code)]
+pub(crate) fn parent_disk(partition: &Path) -> Result<PathBuf> {
+    let sys_dir = match sys_block_dir(partition) {
+        Ok(sys_dir) => sys_dir,
+        Err(why) => {
+            // no sysfs entry, fall back to the kernel naming scheme
+            let disk_name = partition
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(disk_name_from_partition);
+            if let Some(disk_name) = disk_name {
+                warn!(
+                    "Failed to find parent disk of '{}' in sysfs, assuming '{}' by name",
+                    partition.display(),
+                    disk_name
+                );
+                return Ok(path_append("/dev", disk_name));
+            }
+            return Err(why);
+        }
+    };
+    if !path_append(&sys_dir, "partition").exists() {
+        return Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!("Device '{}' is not a partition", partition.display()),
+        ));
+    }
+
+    // /sys/class/block/<partition> links to .../block/<disk>/<partition>
+    let sys_path = canonicalize(&sys_dir).upstream_with_context(&format!(
+        "Failed to canonicalize path '{}'",
+        sys_dir.display()
+    ))?;
+    if let Some(disk_path) = sys_path.parent() {
+        let disk_name = BlockDeviceInfo::path_filename_as_string(disk_path)?;
+        Ok(path_append("/dev", &disk_name))
+    } else {
+        Err(Error::with_context(
+            ErrorKind::NotFound,
+            &format!(
+                "Failed to determine parent disk of '{}' from '{}'",
+                partition.display(),
+                sys_path.display()
+            ),
+        ))
+    }
+}
+
+/// Check the removable attribute of dev or the disk containing it
+pub(crate) fn is_removable(dev: &Path) -> Result<bool> {
+    let disk = if is_whole_disk(dev)? {
+        dev.to_path_buf()
+    } else {
+        parent_disk(dev)?
+    };
+
+    let removable_path = path_append(sys_block_dir(&disk)?, "removable");
+    let removable = read_to_string(&removable_path).upstream_with_context(&format!(
+        "Failed to read from '{}'",
+        removable_path.display()
+    ))?;
+    Ok(removable.trim() == "1")
+}
+
+/// Check whether dev is write protected, either flagged read-only by the kernel or, for whole
+/// disks, refusing to be opened for writing
+pub(crate) fn is_write_protected(dev: &Path) -> Result<bool> {
+    trace!("is_write_protected: entered with '{}'", dev.display());
+    let ro_path = path_append(sys_block_dir(dev)?, "ro");
+    let read_only = read_to_string(&ro_path)
+        .upstream_with_context(&format!("Failed to read from '{}'", ro_path.display()))?;
+    if read_only.trim() == "1" {
+        debug!(
+            "is_write_protected: '{}' is flagged read-only",
+            dev.display()
+        );
+        return Ok(true);
+    }
+
+    if !is_whole_disk(dev)? {
+        return Ok(false);
+    }
+
+    // a zero length write does not modify the disk but fails on write protected media
+    match OpenOptions::new()
+        .write(true)
+        .open(dev)
+        .and_then(|mut file| file.write(&[]))
+    {
+        Ok(_) => Ok(false),
+        // recent kernels refuse opening read-only disks for writing with EACCES
+        Err(why) if why.raw_os_error() == Some(EROFS) || why.raw_os_error() == Some(EACCES) => {
+            debug!(
+                "is_write_protected: write probe on '{}' failed, error: {}",
+                dev.display(),
+                why
+            );
+            Ok(true)
+        }
+        Err(why) => Err(Error::with_all(
+            ErrorKind::Upstream,
+            &format!("Failed to probe '{}' for writing", dev.display()),
+            Box::new(why),
+        )),
+    }
+}
+
+/// Get the device paths of the slaves listed in the sysfs directory sys_path of a device
+fn slaves_of(sys_path: &Path) -> Result<Vec<PathBuf>> {
+    let slaves_dir = path_append(sys_path, "slaves");
+    let mut slaves: Vec<PathBuf> = Vec::new();
+    if slaves_dir.exists() {
+        for entry in read_dir(&slaves_dir).upstream_with_context(&format!(
+            "Failed to read directory '{}'",
+            slaves_dir.display()
+        ))? {
+            let entry = entry.upstream_with_context(&format!(
+                "Failed to read directory entry in '{}'",
+                slaves_dir.display()
+            ))?;
+            slaves.push(path_append("/dev", entry.file_name()));
+        }
+    }
+    Ok(slaves)
+}
+
+/// Resolve a device mapper device (LVM, dm-crypt) to the physical disks backing it by
+/// walking the slaves in sysfs. Other devices resolve to their disk
+pub(crate) fn resolve_to_physical(dev: &Path) -> Result<Vec<PathBuf>> {
+    trace!("resolve_to_physical: entered with '{}'", dev.display());
+    let sys_dir = sys_block_dir(dev)?;
+    let slaves = slaves_of(&sys_dir)?;
+
+    let mut disks: Vec<PathBuf> = Vec::new();
+    if slaves.is_empty() {
+        let dev_path = path_append("/dev", BlockDeviceInfo::path_filename_as_string(&sys_dir)?);
+        if is_whole_disk(&dev_path)? {
+            disks.push(dev_path);
+        } else {
+            disks.push(parent_disk(&dev_path)?);
+        }
+    } else {
+        for slave in slaves {
+            for disk in resolve_to_physical(&slave)? {
+                if !disks.contains(&disk) {
+                    disks.push(disk);
+                }
+            }
+        }
+    }
+
+    debug!(
+        "resolve_to_physical: '{}' resolved to {:?}",
+        dev.display(),
+        disks
+    );
+    Ok(disks)
+}
+
+/// Refuse root file systems of the types in UNSUPPORTED_ROOT_FS_TYPES
+fn check_root_fs_type(fs_type: &str) -> Result<()> {
+    if UNSUPPORTED_ROOT_FS_TYPES.contains(&fs_type) {
+        Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!(
+                "The root file system is of type {}, takeover can not reliably determine the device to flash for {:?} root file systems",
+                fs_type, UNSUPPORTED_ROOT_FS_TYPES
+            ),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+const LUKS_MAGIC: &[u8] = b"LUKS\xba\xbe";
+const LUKS_DM_UUID_PREFIX: &str = "CRYPT-LUKS";
+
+fn is_luks_dm_uuid(dm_uuid: &str) -> bool {
+    dm_uuid.trim().starts_with(LUKS_DM_UUID_PREFIX)
+}
+
+fn has_luks_magic(header: &[u8]) -> bool {
+    header.starts_with(LUKS_MAGIC)
+}
+
+/// Find the LUKS encrypted device backing the device mapper device in sys_path, detected by its
+/// dm uuid or the LUKS header magic of its slaves
+fn luks_backing_device(sys_path: &Path) -> Result<Option<PathBuf>> {
+    let slaves = slaves_of(sys_path)?;
+
+    let uuid_path = path_append(sys_path, "dm/uuid");
+    if let Ok(dm_uuid) = read_to_string(&uuid_path) {
+        if is_luks_dm_uuid(&dm_uuid) {
+            debug!(
+                "luks_backing_device: found LUKS dm uuid '{}' in '{}'",
+                dm_uuid.trim(),
+                uuid_path.display()
+            );
+            return Ok(Some(
+                slaves
+                    .into_iter()
+                    .next()
+                    .unwrap_or_else(|| sys_path.to_path_buf()),
+            ));
+        }
+    }
+
+    for slave in slaves {
+        let mut header = [0u8; 6];
+        let res = File::open(&slave).and_then(|mut file| file.read_exact(&mut header));
+        if res.is_ok() && has_luks_magic(&header) {
+            debug!(
+                "luks_backing_device: found LUKS header on '{}'",
+                slave.display()
+            );
+            return Ok(Some(slave));
+        }
+    }
+    Ok(None)
+}
+
+/// Describe why the device with device number dev_num can not be used as root device if it
+/// is a virtual device takeover can not flash over
+fn check_virtual_root(dev_num: &DeviceNum) -> Result<()> {
+    let sys_path = PathBuf::from(format!(
+        "/sys/dev/block/{}:{}",
+        dev_num.major(),
+        dev_num.minor()
+    ));
+    let sys_path = match canonicalize(&sys_path) {
+        Ok(sys_path) => sys_path,
+        Err(_) => return Ok(()),
+    };
+    let dev_name = BlockDeviceInfo::path_filename_as_string(&sys_path)?;
+    let dev_path = path_append("/dev", &dev_name);
+
+    if dev_name.starts_with("dm-") {
+        let disks = resolve_to_physical(&dev_path)?;
+        if let Some(luks_dev) = luks_backing_device(&sys_path)? {
+            return Err(Error::with_context(
+                ErrorKind::InvState,
+                &format!(
+                    "The root file system on '{}' is encrypted with LUKS on '{}' (disks {:?}), encrypted root file systems are not supported. Please migrate from an unencrypted installation or flash the device manually",
+                    dev_path.display(),
+                    luks_dev.display(),
+                    disks
+                ),
+            ));
+        }
+        Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!(
+                "The root file system is on device mapper device '{}' (LVM or dm-crypt) backed by {:?}, flashing over device mapper devices is not supported",
+                dev_path.display(),
+                disks
+            ),
+        ))
+    } else if dev_name.starts_with("md") {
+        check_not_raid(&dev_path)
+    } else {
+        Ok(())
+    }
+}
+
+/// Extract the names of the arrays listed in /proc/mdstat
+fn parse_mdstat(mdstat: &str) -> Vec<String> {
+    lazy_static! {
+        static ref MDSTAT_RE: Regex = Regex::new(r#"^(md\S*)\s*:"#).unwrap();
+    }
+
+    mdstat
+        .lines()
+        .filter_map(|line| MDSTAT_RE.captures(line))
+        .map(|captures| captures.get(1).unwrap().as_str().to_string())
+        .collect()
+}
+
+/// Check whether dev is a Linux md RAID array or a partition on one
+pub(crate) fn is_md_device(dev: &Path) -> Result<bool> {
+    let disk = if is_whole_disk(dev)? {
+        dev.to_path_buf()
+    } else {
+        parent_disk(dev)?
+    };
+
+    if path_append(sys_block_dir(&disk)?, "md").exists() {
+        return Ok(true);
+    }
+
+    let disk_name = BlockDeviceInfo::path_filename_as_string(
+        &canonicalize(&disk)
+            .upstream_with_context(&format!("Failed to canonicalize path '{}'", disk.display()))?,
+    )?;
+    match read_to_string(PROC_MDSTAT) {
+        Ok(mdstat) => Ok(parse_mdstat(&mdstat).contains(&disk_name)),
+        Err(_) => Ok(false),
+    }
+}
+
+/// List the component devices of an md RAID array from /sys/class/block/<md>/md/dev-*
+pub(crate) fn md_members(dev: &Path) -> Result<Vec<PathBuf>> {
+    let md_dir = path_append(sys_block_dir(dev)?, "md");
+    let mut members: Vec<PathBuf> = Vec::new();
+    for entry in read_dir(&md_dir)
+        .upstream_with_context(&format!("Failed to read directory '{}'", md_dir.display()))?
+    {
+        let entry = entry.upstream_with_context(&format!(
+            "Failed to read directory entry in '{}'",
+            md_dir.display()
+        ))?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if let Some(member) = name.strip_prefix("dev-") {
+            members.push(path_append("/dev", member));
+        }
+    }
+    members.sort();
+    Ok(members)
+}
+
+/// Refuse md RAID arrays as root or flash device, naming the array members
+pub(crate) fn check_not_raid(dev: &Path) -> Result<()> {
+    if !is_md_device(dev)? {
+        return Ok(());
+    }
+
+    let disk = if is_whole_disk(dev)? {
+        dev.to_path_buf()
+    } else {
+        parent_disk(dev)?
+    };
+    let members = match md_members(&disk) {
+        Ok(members) => format!("{:?}", members),
+        Err(_) => String::from("unknown"),
+    };
+
+    Err(Error::with_context(
+        ErrorKind::InvState,
+        &format!(
+            "Device '{}' is on RAID (md) array '{}' with members {}, RAID devices are not supported",
+            dev.display(),
+            disk.display(),
+            members
+        ),
+    ))
+}
+
+/// Validate a flash device given explicitly instead of the auto-detected root disk. It has to
+/// be a whole disk and must not be root_disk, the disk of the running root file system, unless
+/// allow_self is set
+pub(crate) fn check_flash_target(target: &Path, root_disk: &Path, allow_self: bool) -> Result<()> {
+    trace!(
+        "check_flash_target: entered with '{}', root disk '{}'",
+        target.display(),
+        root_disk.display()
+    );
+    let canonical = |dev: &Path| dev.canonicalize().unwrap_or_else(|_| dev.to_path_buf());
+    if !allow_self && canonical(target) == canonical(root_disk) {
+        return Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!(
+                "Refusing to flash '{}', it holds the running root file system, use --allow-self to flash it anyway",
+                target.display()
+            ),
+        ));
+    }
+
+    if !is_whole_disk(target)? {
+        return Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!(
+                "The flash device '{}' is a partition, a whole disk is required",
+                target.display()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Make sure the disk to flash, flash_to if given or the disk holding the root file system, is
+/// not write protected. Called before the image is staged, a write protected disk can not be
+/// flashed
+pub(crate) fn check_flash_writable(flash_to: Option<&Path>) -> Result<()> {
+    trace!("check_flash_writable: entered with {:?}", flash_to);
+    let block_dev_info = BlockDeviceInfo::new()?;
+    let flash_dev = if let Some(flash_to) = flash_to {
+        block_dev_info
+            .get_devices()
+            .get(flash_to)
+            .ok_or_else(|| {
+                Error::with_context(
+                    ErrorKind::InvState,
+                    &format!(
+                        "Could not find configured flash device '{}'",
+                        flash_to.display()
+                    ),
+                )
+            })?
+            .get_dev_path()
+    } else {
+        block_dev_info.get_root_device().get_dev_path()
+    };
+
+    if is_write_protected(&flash_dev)? {
+        return Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!(
+                "The device '{}' is write protected, refusing to flash it",
+                flash_dev.display()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// virtual devices that are not considered as flash targets
+const VIRTUAL_DEV_PREFIXES: [&str; 4] = ["loop", "ram", "zram", "dm-"];
+
+/// A whole disk as listed by list_block_devices
+#[derive(Clone, Debug)]
+pub(crate) struct DiskInfo {
+    name: String,
+    dev_path: PathBuf,
+    size: u64,
+    removable: bool,
+    model: Option<String>,
+}
+
+#[allow(dead_code)]
+impl DiskInfo {
+    pub fn get_name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    pub fn get_dev_path(&self) -> &Path {
+        self.dev_path.as_path()
+    }
+
+    pub fn get_size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn is_removable(&self) -> bool {
+        self.removable
+    }
+
+    pub fn get_model(&self) -> Option<&str> {
+        self.model.as_deref()
+    }
+}
+
+/// List the whole disks found in /sys/class/block, partitions and loop, ram and
+/// device mapper devices are skipped. Devices that can not be read are logged and skipped
+#[allow(dead_code)]
+pub(crate) fn list_block_devices() -> Result<Vec<DiskInfo>> {
+    trace!("list_block_devices: entered");
+    let mut disks: Vec<DiskInfo> = Vec::new();
+    for entry in read_dir(SYS_CLASS_BLOCK)
+        .upstream_with_context(&format!("Failed to read directory '{}'", SYS_CLASS_BLOCK))?
+    {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(why) => {
+                warn!(
+                    "Failed to read directory entry in '{}', skipping it, error: {}",
+                    SYS_CLASS_BLOCK, why
+                );
+                continue;
+            }
+        };
+        let name = entry.file_name().to_string_lossy().to_string();
+        if VIRTUAL_DEV_PREFIXES
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+            || path_append(entry.path(), "partition").exists()
+        {
+            debug!("list_block_devices: skipping '{}'", name);
+            continue;
+        }
+
+        let dev_path = path_append("/dev", &name);
+        let model = match read_to_string(path_append(entry.path(), "device/model")) {
+            Ok(model) => Some(model.trim().to_string()),
+            Err(_) => None,
+        };
+
+        let (size, removable) = match get_device_size(&dev_path)
+            .and_then(|size| Ok((size, is_removable(&dev_path)?)))
+        {
+            Ok(res) => res,
+            Err(why) => {
+                warn!(
+                    "Failed to read block device '{}', skipping it, error: {}",
+                    dev_path.display(),
+                    why
+                );
+                continue;
+            }
+        };
+
+        disks.push(DiskInfo {
+            size,
+            removable,
+            name,
+            dev_path,
+            model,
+        });
+    }
+
+    disks.sort_by(|disk1, disk2| disk1.name.cmp(&disk2.name));
+    Ok(disks)
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub(crate) struct DeviceNum {
     major: u64,
@@ -123,6 +795,11 @@ impl BlockDeviceInfo {
             root_number.minor()
         );
 
+        if let Some(root_mount) = Mount::get_mount_at(Path::new("/"))? {
+            check_root_fs_type(root_mount.get_fs_type())?;
+        }
+        check_virtual_root(&root_number)?;
+
         let sys_path = PathBuf::from("/sys/block/");
         let read_dir = read_dir(&sys_path).upstream_with_context(&format!(
             "Failed to read directory '{}'",
@@ -371,3 +1048,72 @@ impl BlockDeviceInfo {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_flash_target, check_root_fs_type, disk_name_from_partition, has_luks_magic,
+        is_luks_dm_uuid, parse_mdstat,
+    };
+    use crate::common::ErrorKind;
+    use std::path::Path;
+
+    #[test]
+    fn test_refuse_flashing_root_disk() {
+        let root_disk = Path::new("/dev/takeover-test-root");
+        let err = check_flash_target(root_disk, root_disk, false).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvState);
+        assert!(err.to_string().contains("--allow-self"));
+        // with allow_self the target is validated as a disk, which fails for a missing device
+        assert_ne!(
+            check_flash_target(root_disk, root_disk, true)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvState
+        );
+    }
+
+    #[test]
+    fn test_detect_luks() {
+        assert!(is_luks_dm_uuid(
+            "CRYPT-LUKS2-3f2a9c0d8e1b4a5f9c7d6e5f4a3b2c1d-luks-3f2a9c0d\n"
+        ));
+        assert!(!is_luks_dm_uuid("LVM-abcdef"));
+        assert!(has_luks_magic(b"LUKS\xba\xbe\x00\x02"));
+        assert!(!has_luks_magic(b"\x00\x00\x00\x00\x00\x00"));
+    }
+
+    #[test]
+    fn test_disk_name_from_partition() {
+        assert_eq!(disk_name_from_partition("sda2"), Some("sda"));
+        assert_eq!(disk_name_from_partition("nvme0n1p3"), Some("nvme0n1"));
+        assert_eq!(disk_name_from_partition("mmcblk1p2"), Some("mmcblk1"));
+        assert_eq!(disk_name_from_partition("loop0p1"), Some("loop0"));
+        assert_eq!(disk_name_from_partition("sda"), None);
+        assert_eq!(disk_name_from_partition("nvme0n1"), None);
+        assert_eq!(disk_name_from_partition("mmcblk0"), None);
+    }
+
+    #[test]
+    fn test_parse_mdstat() {
+        const MDSTAT: &str = r#"Personalities : [raid1] [raid6] [raid5] [raid4]
+md1 : active raid5 sdd1[2] sdc1[1] sdb1[0]
+      1953260544 blocks super 1.2 level 5, 512k chunk, algorithm 2 [3/3] [UUU]
+
+md0 : active raid1 sda2[0] sdb2[1]
+      488254464 blocks super 1.2 [2/2] [UU]
+      bitmap: 1/4 pages [4KB], 65536KB chunk
+
+unused devices: <none>
+"#;
+        assert_eq!(parse_mdstat(MDSTAT), vec!["md1", "md0"]);
+        assert!(parse_mdstat("Personalities : \nunused devices: <none>\n").is_empty());
+    }
+
+    #[test]
+    fn test_check_root_fs_type() {
+        assert!(check_root_fs_type("ext4").is_ok());
+        assert!(check_root_fs_type("btrfs").is_err());
+        assert!(check_root_fs_type("zfs").is_err());
+    }
+}
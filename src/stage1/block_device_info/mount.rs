@@ -1,16 +1,38 @@
 use std::collections::HashMap;
+use std::ffi::OsStr;
 use std::fs::read_to_string;
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 
-use log::{debug, trace};
+use log::{debug, trace, warn};
+use nix::mount::MsFlags;
 
+use super::disk_name_from_partition;
 use crate::common::{Error, Result, ToError};
 use crate::ErrorKind;
 
+const MTAB_PATH: &str = "/etc/mtab";
+const MOUNTINFO_PATH: &str = "/proc/self/mountinfo";
+
+// mount options that map to mount flags, ro is left out so a remount makes the mount writable
+const OPTION_FLAGS: [(&str, MsFlags); 10] = [
+    ("nosuid", MsFlags::MS_NOSUID),
+    ("nodev", MsFlags::MS_NODEV),
+    ("noexec", MsFlags::MS_NOEXEC),
+    ("sync", MsFlags::MS_SYNCHRONOUS),
+    ("mand", MsFlags::MS_MANDLOCK),
+    ("dirsync", MsFlags::MS_DIRSYNC),
+    ("noatime", MsFlags::MS_NOATIME),
+    ("nodiratime", MsFlags::MS_NODIRATIME),
+    ("relatime", MsFlags::MS_RELATIME),
+    ("strictatime", MsFlags::MS_STRICTATIME),
+];
+
 #[derive(Clone, Debug)]
 pub(crate) struct Mount {
     mountpoint: PathBuf,
     fs_type: String,
+    options: Vec<String>,
 }
 
 impl Mount {
@@ -18,39 +40,176 @@ impl Mount {
         self.mountpoint.as_path()
     }
 
-    #[allow(dead_code)]
     pub fn get_fs_type(&self) -> &str {
         self.fs_type.as_str()
     }
+
+    #[allow(dead_code)]
+    pub fn get_options(&self) -> &[String] {
+        self.options.as_slice()
+    }
+
+    #[allow(dead_code)]
+    pub fn has_option(&self, opt: &str) -> bool {
+        self.options.iter().any(|option| option == opt)
+    }
+
+    #[allow(dead_code)]
+    pub fn is_read_only(&self) -> bool {
+        self.has_option("ro")
+    }
+
+    /// The mount flags to remount the file system read-write with its other options unchanged
+    pub fn remount_flags(&self) -> MsFlags {
+        OPTION_FLAGS
+            .iter()
+            .filter(|(option, _)| self.has_option(option))
+            .fold(MsFlags::MS_REMOUNT, |flags, (_, flag)| flags | *flag)
+    }
+}
+
+fn parse_options(options: &str) -> Vec<String> {
+    options
+        .split(',')
+        .filter(|option| !option.is_empty())
+        .map(|option| option.to_string())
+        .collect()
+}
+
+/// Decode the octal escapes (eg. \040 for space) the kernel uses for whitespace and backslashes
+/// in mtab and mountinfo fields
+fn unescape_path(field: &str) -> PathBuf {
+    let bytes = field.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        if bytes[idx] == b'\\'
+            && idx + 3 < bytes.len()
+            && bytes[idx + 1..idx + 4]
+                .iter()
+                .all(|digit| (b'0'..=b'7').contains(digit))
+        {
+            let value = bytes[idx + 1..idx + 4]
+                .iter()
+                .fold(0u32, |acc, digit| acc * 8 + (digit - b'0') as u32);
+            if value <= 0xFF {
+                decoded.push(value as u8);
+                idx += 4;
+                continue;
+            }
+        }
+        decoded.push(bytes[idx]);
+        idx += 1;
+    }
+    PathBuf::from(OsStr::from_bytes(&decoded))
 }
 
 pub(crate) type MountTab = HashMap<PathBuf, Mount>;
 
+/// Return all mounts of disk or its partitions from the (device, mount) tuples in mounts,
+/// a partition mounted in several places is returned once for each mountpoint. The mounts
+/// are returned last mounted first, so nested mounts come before their parents
+pub(crate) fn mounts_on_disk(disk: &Path, mounts: &[(PathBuf, Mount)]) -> Vec<(PathBuf, Mount)> {
+    let disk_name = if let Some(disk_name) = disk.file_name() {
+        disk_name.to_string_lossy()
+    } else {
+        return Vec::new();
+    };
+
+    mounts
+        .iter()
+        .rev()
+        .filter(|(device, _)| {
+            device.file_name().is_some_and(|dev_name| {
+                let dev_name = dev_name.to_string_lossy();
+                dev_name == disk_name
+                    || disk_name_from_partition(&dev_name) == Some(disk_name.as_ref())
+            })
+        })
+        .cloned()
+        .collect()
+}
+
+#[allow(dead_code)]
+pub(crate) trait MountTabExt {
+    fn find_by_mountpoint(&self, mountpoint: &Path) -> Option<&Mount>;
+    fn find_by_fs_type(&self, fs_type: &str) -> Vec<(&Path, &Mount)>;
+}
+
+impl MountTabExt for MountTab {
+    fn find_by_mountpoint(&self, mountpoint: &Path) -> Option<&Mount> {
+        self.values()
+            .find(|mount| mount.get_mountpoint() == mountpoint)
+    }
+
+    /// Returns (device, mount) tuples for all mounts of the given file system type
+    fn find_by_fs_type(&self, fs_type: &str) -> Vec<(&Path, &Mount)> {
+        self.iter()
+            .filter(|(_, mount)| mount.get_fs_type() == fs_type)
+            .map(|(device, mount)| (device.as_path(), mount))
+            .collect()
+    }
+}
+
 impl Mount {
     pub fn from_mtab() -> Result<MountTab> {
-        let mtab_str =
-            read_to_string("/etc/mtab").upstream_with_context("Failed to read from '/etc/mtab'")?;
+        let mtab_str = match read_to_string(MTAB_PATH) {
+            Ok(mtab_str) => mtab_str,
+            Err(why) => {
+                debug!(
+                    "from_mtab: failed to read '{}': {:?}, trying '{}'",
+                    MTAB_PATH, why, MOUNTINFO_PATH
+                );
+                return Mount::from_mountinfo();
+            }
+        };
+
+        let mounts = Mount::parse_mtab(&mtab_str)?;
+        if mounts.is_empty() {
+            // eg. a container or a broken mtab replacement
+            warn!(
+                "from_mtab: no device backed mounts found in '{}', trying '{}'",
+                MTAB_PATH, MOUNTINFO_PATH
+            );
+            Mount::require_device_mounts(Mount::from_mountinfo()?, MOUNTINFO_PATH)
+        } else {
+            Ok(mounts)
+        }
+    }
 
+    fn require_device_mounts(mounts: MountTab, source: &str) -> Result<MountTab> {
+        if mounts.is_empty() {
+            Err(Error::with_context(
+                ErrorKind::InvState,
+                &format!("No device backed mounts found in '{}'", source),
+            ))
+        } else {
+            Ok(mounts)
+        }
+    }
+
+    fn parse_mtab(mtab_str: &str) -> Result<MountTab> {
         let mut mounts: MountTab = MountTab::new();
 
         for (line_no, line) in mtab_str.lines().enumerate() {
             let columns: Vec<&str> = line.split_whitespace().collect();
-            if columns.len() < 3 {
+            if columns.len() < 4 {
                 return Err(Error::with_context(
                     ErrorKind::InvParam,
                     &format!("Failed to parse /etc/mtab line {} : '{}'", line_no, line),
                 ));
             }
 
-            let device_name = columns[0];
+            let device_name = unescape_path(columns[0]);
             if device_name.starts_with("/dev/") {
                 let mount = Mount {
-                    mountpoint: PathBuf::from(columns[1]),
+                    mountpoint: unescape_path(columns[1]),
                     fs_type: columns[2].to_string(),
+                    options: parse_options(columns[3]),
                 };
 
                 debug!("from_mtab: processing mount {:?}", mount);
-                mounts.insert(PathBuf::from(device_name), mount);
+                mounts.insert(device_name, mount);
             } else {
                 trace!("from_mtab: not processing line {}", line);
             }
@@ -58,4 +217,196 @@ impl Mount {
 
         Ok(mounts)
     }
+
+    /// Parse /proc/self/mountinfo into a MountTab keyed by device path.
+    pub fn from_mountinfo() -> Result<MountTab> {
+        Mount::parse_device_mountinfo(&Mount::read_mountinfo_str()?)
+    }
+
+    /// Parse the content of a mountinfo file into a MountTab of the device backed mounts,
+    /// the result is empty if nothing is mounted from a device
+    pub fn parse_device_mountinfo(mountinfo_str: &str) -> Result<MountTab> {
+        Ok(Mount::parse_device_mounts(mountinfo_str)?
+            .into_iter()
+            .collect())
+    }
+
+    /// All device backed mounts from /proc/self/mountinfo in mount order as (device, mount)
+    /// tuples, unlike a MountTab this keeps every mountpoint of a device
+    pub fn device_mounts() -> Result<Vec<(PathBuf, Mount)>> {
+        Mount::parse_device_mounts(&Mount::read_mountinfo_str()?)
+    }
+
+    fn parse_device_mounts(mountinfo_str: &str) -> Result<Vec<(PathBuf, Mount)>> {
+        Ok(Mount::parse_mountinfo(mountinfo_str)?
+            .into_iter()
+            .filter(|(device_name, mount)| {
+                if device_name.starts_with("/dev/") {
+                    debug!("from_mountinfo: processing mount {:?}", mount);
+                    true
+                } else {
+                    trace!(
+                        "from_mountinfo: not processing mount of '{}'",
+                        device_name.display()
+                    );
+                    false
+                }
+            })
+            .collect())
+    }
+
+    /// Find the topmost mount on mountpoint, including mounts not backed by a device
+    pub fn get_mount_at(mountpoint: &Path) -> Result<Option<Mount>> {
+        Ok(Mount::get_source_mount_at(mountpoint)?.map(|(_, mount)| mount))
+    }
+
+    /// Find the topmost mount on mountpoint like get_mount_at, returns it with its source, eg.
+    /// the device or 'tmpfs'
+    pub fn get_source_mount_at(mountpoint: &Path) -> Result<Option<(PathBuf, Mount)>> {
+        Ok(Mount::parse_mountinfo(&Mount::read_mountinfo_str()?)?
+            .into_iter()
+            .rev()
+            .find(|(_, mount)| mount.mountpoint == mountpoint))
+    }
+
+    fn read_mountinfo_str() -> Result<String> {
+        read_to_string(MOUNTINFO_PATH)
+            .upstream_with_context(&format!("Failed to read from '{}'", MOUNTINFO_PATH))
+    }
+
+    /// Parse all mounts from the content of /proc/self/mountinfo in mount order as
+    /// (source, mount) tuples. Line format, see proc(5):
+    /// 36 35 98:0 /mnt1 /mnt2 rw,noatime master:1 - ext3 /dev/root rw,errors=continue
+    fn parse_mountinfo(mountinfo_str: &str) -> Result<Vec<(PathBuf, Mount)>> {
+        let mut mounts: Vec<(PathBuf, Mount)> = Vec::new();
+
+        for (line_no, line) in mountinfo_str.lines().enumerate() {
+            let columns: Vec<&str> = line.split_whitespace().collect();
+            // optional fields are terminated by a single '-'
+            let separator = match columns.iter().skip(6).position(|col| *col == "-") {
+                Some(pos) => pos + 6,
+                None => {
+                    return Err(Error::with_context(
+                        ErrorKind::InvParam,
+                        &format!(
+                            "Failed to parse {} line {} : '{}'",
+                            MOUNTINFO_PATH, line_no, line
+                        ),
+                    ));
+                }
+            };
+
+            if columns.len() < separator + 4 {
+                return Err(Error::with_context(
+                    ErrorKind::InvParam,
+                    &format!(
+                        "Failed to parse {} line {} : '{}'",
+                        MOUNTINFO_PATH, line_no, line
+                    ),
+                ));
+            }
+
+            // per mount options (ro, noatime..) followed by superblock options
+            let mut options = parse_options(columns[5]);
+            for option in parse_options(columns[separator + 3]) {
+                if !options.contains(&option) {
+                    options.push(option);
+                }
+            }
+
+            mounts.push((
+                unescape_path(columns[separator + 2]),
+                Mount {
+                    mountpoint: unescape_path(columns[4]),
+                    fs_type: columns[separator + 1].to_string(),
+                    options,
+                },
+            ));
+        }
+
+        Ok(mounts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_mtab_escaped_mountpoint() {
+        const MTAB: &str = "/dev/sda1 / ext4 rw,relatime 0 0\n\
+                            /dev/sdb1 /mnt/My\\040Disk vfat rw 0 0\n\
+                            proc /proc proc rw 0 0\n";
+        let mounts = Mount::parse_mtab(MTAB).unwrap();
+        assert_eq!(mounts.len(), 2);
+        let mount = mounts.get(Path::new("/dev/sdb1")).unwrap();
+        assert_eq!(mount.get_mountpoint(), Path::new("/mnt/My Disk"));
+        assert_eq!(mount.get_fs_type(), "vfat");
+    }
+
+    #[test]
+    fn parse_mtab_pseudo_fs_only() {
+        const MTAB: &str = "proc /proc proc rw,nosuid,nodev,noexec 0 0\n\
+                            sysfs /sys sysfs rw,nosuid,nodev,noexec 0 0\n\
+                            tmpfs /run tmpfs rw,nosuid,nodev 0 0\n";
+        let mounts = Mount::parse_mtab(MTAB).unwrap();
+        assert!(mounts.is_empty());
+        let err = Mount::require_device_mounts(mounts, MTAB_PATH).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvState);
+    }
+
+    #[test]
+    fn remount_flags_from_options() {
+        let mount = Mount {
+            mountpoint: PathBuf::from("/"),
+            fs_type: "ext4".to_string(),
+            options: parse_options("ro,nodev,noatime,errors=remount-ro"),
+        };
+        assert_eq!(
+            mount.remount_flags(),
+            MsFlags::MS_REMOUNT | MsFlags::MS_NODEV | MsFlags::MS_NOATIME
+        );
+    }
+
+    #[test]
+    fn mounts_on_disk_partitions() {
+        const MOUNTINFO: &str = "20 1 8:2 / / rw - ext4 /dev/sda2 rw\n\
+             21 20 8:1 / /boot rw - vfat /dev/sda1 rw\n\
+             22 20 65:161 / /data rw - ext4 /dev/sdaa1 rw\n\
+             23 20 8:17 / /mnt rw - ext4 /dev/sdb1 rw\n\
+             24 20 259:1 / /efi rw - vfat /dev/nvme0n1p1 rw\n\
+             25 20 259:2 / /other rw - ext4 /dev/nvme0n10 rw\n\
+             26 20 8:1 / /mnt/boot rw - vfat /dev/sda1 rw\n\
+             27 20 0:4 / /proc rw - proc proc rw\n";
+        let mounts = Mount::parse_device_mounts(MOUNTINFO).unwrap();
+        let mountpoints = |disk: &str| -> Vec<PathBuf> {
+            mounts_on_disk(Path::new(disk), &mounts)
+                .into_iter()
+                .map(|(_, mount)| mount.mountpoint)
+                .collect()
+        };
+        assert_eq!(
+            mountpoints("/dev/sda"),
+            vec![
+                PathBuf::from("/mnt/boot"),
+                PathBuf::from("/boot"),
+                PathBuf::from("/")
+            ]
+        );
+        assert_eq!(mountpoints("/dev/nvme0n1"), vec![PathBuf::from("/efi")]);
+        assert!(mountpoints("/dev/sdc").is_empty());
+    }
+
+    #[test]
+    fn unescape_path_sequences() {
+        assert_eq!(
+            unescape_path("/a\\011b\\012c\\134d"),
+            PathBuf::from("/a\tb\nc\\d")
+        );
+        assert_eq!(unescape_path("/plain"), PathBuf::from("/plain"));
+        assert_eq!(
+            unescape_path("/trailing\\04"),
+            PathBuf::from("/trailing\\04")
+        );
+    }
 }
@@ -5,7 +5,9 @@ pub const DEV_TYPE_GEN_X86_64: &str = "genericx86-64-ext";
 pub const DEV_TYPE_RPI3: &str = "raspberrypi3";
 pub const DEV_TYPE_RPI2: &str = "raspberry-pi2";
 pub const DEV_TYPE_RPI1: &str = "raspberry-pi";
+pub const DEV_TYPE_RPI4: &str = "raspberrypi4";
 pub const DEV_TYPE_RPI4_64: &str = "raspberrypi4-64";
+pub const DEV_TYPE_RPI5: &str = "raspberrypi5";
 pub const DEV_TYPE_BBG: &str = "beaglebone-green";
 pub const DEV_TYPE_BBB: &str = "beaglebone-black";
 pub const DEV_TYPE_BBXM: &str = "beagleboard-xm";
@@ -13,16 +15,21 @@ pub const DEV_TYPE_BBXM: &str = "beagleboard-xm";
 pub const MAX_CONFIG_JSON: usize = 2048;
 pub const GZIP_MAGIC_COOKIE: u16 = 0x1f8b;
 
+// stage1 progress saved in the work directory
+pub const PROGRESS_FILE_NAME: &str = "takeover-progress.json";
+
 #[derive(Debug, Clone, Copy)]
 pub(crate) enum DeviceType {
     BeagleboneGreen,
     BeagleboneBlack,
     BeagleboardXM,
     IntelNuc,
+    GenericX86_64,
     RaspberryPi1,
     RaspberryPi2,
     RaspberryPi3,
     RaspberryPi4,
+    RaspberryPi5,
 }
 
 impl Display for DeviceType {
@@ -32,6 +39,7 @@ impl Display for DeviceType {
             "{},",
             match self {
                 Self::IntelNuc => "X68_64/Intel Nuc",
+                Self::GenericX86_64 => "Generic X86_64",
                 Self::BeagleboneGreen => "Beaglebone Green",
                 Self::BeagleboneBlack => "Beaglebone Black",
                 Self::BeagleboardXM => "Beagleboard XM",
@@ -39,6 +47,7 @@ impl Display for DeviceType {
                 Self::RaspberryPi2 => "Raspberry Pi 2",
                 Self::RaspberryPi3 => "Raspberry Pi 3",
                 Self::RaspberryPi4 => "Raspberry Pi 4",
+                Self::RaspberryPi5 => "Raspberry Pi 5",
             }
         )
     }
@@ -59,3 +68,41 @@ pub(crate) enum OSArch {
         S390EX,
     */
 }
+
+const ARM32_DEVICE_TYPES: [&str; 7] = [
+    DEV_TYPE_RPI1,
+    DEV_TYPE_RPI2,
+    DEV_TYPE_RPI3,
+    DEV_TYPE_RPI4,
+    DEV_TYPE_BBG,
+    DEV_TYPE_BBB,
+    DEV_TYPE_BBXM,
+];
+
+/// Check whether balena images of device_type can run on a host of the given architecture.
+/// The whole disk is reflashed so the host userland does not matter, an ARM64 host can also
+/// take 32 bit ARM images, eg. a Raspberry Pi 3 running a 64 bit OS.
+pub(crate) fn is_arch_compatible(arch: &OSArch, device_type: &str) -> bool {
+    match arch {
+        OSArch::AMD64 => [DEV_TYPE_INTEL_NUC, DEV_TYPE_GEN_X86_64].contains(&device_type),
+        OSArch::ARMHF => ARM32_DEVICE_TYPES.contains(&device_type),
+        OSArch::ARM64 => {
+            [DEV_TYPE_RPI4_64, DEV_TYPE_RPI5].contains(&device_type)
+                || ARM32_DEVICE_TYPES.contains(&device_type)
+        }
+        OSArch::I386 => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arm64_accepts_32_bit_arm_device_types() {
+        assert!(is_arch_compatible(&OSArch::ARM64, DEV_TYPE_RPI3));
+        assert!(is_arch_compatible(&OSArch::ARM64, DEV_TYPE_RPI4_64));
+        assert!(!is_arch_compatible(&OSArch::ARMHF, DEV_TYPE_RPI4_64));
+        assert!(!is_arch_compatible(&OSArch::ARM64, DEV_TYPE_INTEL_NUC));
+    }
+}
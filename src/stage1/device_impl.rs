@@ -1,10 +1,17 @@
-use log::{error, info, warn};
+use lazy_static::lazy_static;
+use log::{debug, error, info, trace, warn};
+use regex::Regex;
 use std::fs::read_to_string;
 
-use crate::common::ToError;
+use crate::common::{file_exists, ToError};
 use crate::{
     common::{get_os_name, Error, ErrorKind, Options, Result},
-    stage1::{defs::OSArch, device::Device, utils::get_os_arch},
+    stage1::{
+        defs::{DeviceType, OSArch},
+        device::Device,
+        events::{EventSink, Stage1Event},
+        utils::get_os_arch,
+    },
 };
 
 // mod beaglebone;
@@ -13,6 +20,124 @@ mod intel_nuc;
 mod raspberrypi;
 
 const DEVICE_TREE_MODEL: &str = "/proc/device-tree/model";
+const SYS_DEVICE_TREE_MODEL: &str = "/sys/firmware/devicetree/base/model";
+const DMI_SYS_VENDOR: &str = "/sys/class/dmi/id/sys_vendor";
+const DMI_PRODUCT_NAME: &str = "/sys/class/dmi/id/product_name";
+
+impl DeviceType {
+    /// Determine the device type from the device tree model, fails with NotFound if no
+    /// device tree is present (eg. on x86)
+    #[allow(dead_code)]
+    pub fn from_device_tree() -> Result<DeviceType> {
+        trace!("DeviceType::from_device_tree: entered");
+        let model_path = if let Some(model_path) = [DEVICE_TREE_MODEL, SYS_DEVICE_TREE_MODEL]
+            .iter()
+            .find(|path| file_exists(path))
+        {
+            *model_path
+        } else {
+            return Err(Error::with_context(
+                ErrorKind::NotFound,
+                &format!(
+                    "DeviceType::from_device_tree: no device tree model found in '{}' or '{}'",
+                    DEVICE_TREE_MODEL, SYS_DEVICE_TREE_MODEL
+                ),
+            ));
+        };
+
+        let model = read_to_string(model_path).upstream_with_context(&format!(
+            "DeviceType::from_device_tree: failed to read file '{}'",
+            model_path
+        ))?;
+        let model = model.trim_end_matches('\0').trim_end();
+        debug!(
+            "DeviceType::from_device_tree: got model '{}' from '{}'",
+            model, model_path
+        );
+
+        DeviceType::from_model(model)
+    }
+
+    /// Determine the x86 device type from DMI data, fails with NotFound if the DMI files are
+    /// missing or not readable
+    pub fn from_dmi() -> Result<DeviceType> {
+        trace!("DeviceType::from_dmi: entered");
+        let sys_vendor = read_dmi_value(DMI_SYS_VENDOR)?;
+        let product_name = read_dmi_value(DMI_PRODUCT_NAME)?;
+        debug!(
+            "DeviceType::from_dmi: got sys_vendor: '{}', product_name: '{}'",
+            sys_vendor, product_name
+        );
+
+        if sys_vendor.starts_with("Intel") && product_name.contains("NUC") {
+            Ok(DeviceType::IntelNuc)
+        } else {
+            Ok(DeviceType::GenericX86_64)
+        }
+    }
+
+    fn from_model(model: &str) -> Result<DeviceType> {
+        lazy_static! {
+            static ref RPI_REGEX: Regex = Regex::new(
+                r#"^Raspberry\s+Pi\s+(\d+|Compute\s+Module\s+(\d)|Zero(\s+(2)\b)?|Model)\b"#
+            )
+            .unwrap();
+            static ref BB_REGEX: Regex =
+                Regex::new(r#"^((\S+\s+)*(\S+))\s+Beagle(Bone|Board)\s+(\S+)$"#).unwrap();
+        }
+
+        if let Some(captures) = RPI_REGEX.captures(model) {
+            let pitype = if let Some(cm_type) = captures.get(2) {
+                cm_type.as_str()
+            } else if captures.get(4).is_some() {
+                "Zero 2"
+            } else {
+                captures.get(1).unwrap().as_str()
+            };
+
+            match pitype {
+                // the original 'Raspberry Pi Model B' has no number
+                "1" | "Zero" | "Model" => return Ok(DeviceType::RaspberryPi1),
+                "2" => return Ok(DeviceType::RaspberryPi2),
+                // the Zero 2 W is built around the SoC of the Pi 3
+                "3" | "Zero 2" => return Ok(DeviceType::RaspberryPi3),
+                // the Pi 400 is a Pi 4 in a keyboard
+                "4" | "400" => return Ok(DeviceType::RaspberryPi4),
+                "5" => return Ok(DeviceType::RaspberryPi5),
+                _ => (),
+            }
+        } else if model == "TI AM335x BeagleBone" {
+            // see beaglebone::is_bb
+            return Ok(DeviceType::BeagleboneGreen);
+        } else if let Some(captures) = BB_REGEX.captures(model) {
+            match captures.get(5).unwrap().as_str() {
+                "xM" => return Ok(DeviceType::BeagleboardXM),
+                "Green" => return Ok(DeviceType::BeagleboneGreen),
+                "Black" => return Ok(DeviceType::BeagleboneBlack),
+                _ => (),
+            }
+        }
+
+        Err(Error::with_context(
+            ErrorKind::UnsupportedDevice,
+            &format!(
+                "DeviceType::from_device_tree: unsupported device tree model: '{}'",
+                model
+            ),
+        ))
+    }
+}
+
+fn read_dmi_value(path: &str) -> Result<String> {
+    match read_to_string(path) {
+        Ok(value) => Ok(value.trim().to_string()),
+        Err(why) => Err(Error::with_all(
+            ErrorKind::NotFound,
+            &format!("DeviceType::from_dmi: failed to read file '{}'", path),
+            Box::new(why),
+        )),
+    }
+}
 
 pub(crate) fn check_os(supported: &[&str], opts: &Options, dev_type: &str) -> Result<bool> {
     let os_name = get_os_name()?;
@@ -39,9 +164,12 @@ pub(crate) fn check_os(supported: &[&str], opts: &Options, dev_type: &str) -> Re
     }
 }
 
-pub(crate) fn get_device(opts: &Options) -> Result<Box<dyn Device>> {
+pub(crate) fn get_device(opts: &Options, events: &EventSink) -> Result<Box<dyn Device>> {
     let os_arch = get_os_arch()?;
     info!("Detected OS Architecture is {:?}", os_arch);
+    events.emit(Stage1Event::ArchDetected {
+        arch: format!("{:?}", os_arch),
+    });
 
     match os_arch {
         OSArch::ARMHF | OSArch::ARM64 => {
@@ -68,7 +196,7 @@ pub(crate) fn get_device(opts: &Options) -> Result<Box<dyn Device>> {
                 dev_tree_model
             );
             error!("{}", message);
-            Err(Error::with_context(ErrorKind::InvState, &message))
+            Err(Error::with_context(ErrorKind::UnsupportedDevice, &message))
         }
         OSArch::AMD64 => Ok(Box::new(intel_nuc::IntelNuc::from_config(opts)?)),
         /*            OSArch::I386 => {
@@ -76,8 +204,43 @@ pub(crate) fn get_device(opts: &Options) -> Result<Box<dyn Device>> {
                 },
         */
         _ => Err(Error::with_context(
-            ErrorKind::InvParam,
+            ErrorKind::UnsupportedArch,
             &format!("get_device: unexpected OsArch encountered: {:?}", os_arch),
         )),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::DeviceType;
+
+    #[test]
+    fn device_type_from_model() {
+        let device_type = |model: &str| DeviceType::from_model(model).unwrap();
+        assert!(matches!(
+            device_type("Raspberry Pi 4 Model B Rev 1.1"),
+            DeviceType::RaspberryPi4
+        ));
+        assert!(matches!(
+            device_type("Raspberry Pi 400 Rev 1.0"),
+            DeviceType::RaspberryPi4
+        ));
+        assert!(matches!(
+            device_type("Raspberry Pi Zero W Rev 1.1"),
+            DeviceType::RaspberryPi1
+        ));
+        assert!(matches!(
+            device_type("Raspberry Pi Zero 2 W Rev 1.0"),
+            DeviceType::RaspberryPi3
+        ));
+        assert!(matches!(
+            device_type("Raspberry Pi Compute Module 3 Plus Rev 1.0"),
+            DeviceType::RaspberryPi3
+        ));
+        assert!(matches!(
+            device_type("TI AM335x BeagleBone Black"),
+            DeviceType::BeagleboneBlack
+        ));
+        assert!(DeviceType::from_model("Raspberry Pi 42 Rev 1.0").is_err());
+    }
+}
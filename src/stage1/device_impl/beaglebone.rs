@@ -61,7 +61,7 @@ pub(crate) fn is_bb(opts: &Options, model_string: &str) -> Result<Option<Box<dyn
             _ => {
                 let message = format!("The beaglebone model reported by your device ('{}') is not supported by balena-migrate", model);
                 error!("{}", message);
-                Err(Error::with_context(ErrorKind::InvParam, &message))
+                Err(Error::with_context(ErrorKind::UnsupportedDevice, &message))
             }
         }
     } else {
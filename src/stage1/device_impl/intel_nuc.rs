@@ -1,4 +1,4 @@
-use log::{error, info};
+use log::warn;
 
 use crate::stage1::device_impl::check_os;
 use crate::{
@@ -7,13 +7,14 @@ use crate::{
     stage1::{
         defs::{DeviceType, DEV_TYPE_GEN_X86_64, DEV_TYPE_INTEL_NUC},
         device::Device,
-        utils::is_secure_boot,
     },
 };
 
 const X86_SLUGS: [&str; 2] = [DEV_TYPE_INTEL_NUC, DEV_TYPE_GEN_X86_64];
 
-pub(crate) struct IntelNuc;
+pub(crate) struct IntelNuc {
+    device_type: DeviceType,
+}
 
 impl IntelNuc {
     pub fn from_config(opts: &Options) -> Result<IntelNuc> {
@@ -30,30 +31,22 @@ impl IntelNuc {
             "Manjaro Linux",
         ];
 
-        if opts.migrate() {
-            if !check_os(SUPPORTED_OSSES, opts, "Generic x86_64/Intel Nuc")? {
-                return Err(Error::displayed());
-            }
-
-            // **********************************************************************
-            // ** AMD64 specific initialisation/checks
-            // **********************************************************************
-
-            let secure_boot = is_secure_boot()?;
-            info!(
-                "Secure boot is {}enabled",
-                if secure_boot { "" } else { "not " }
-            );
-
-            if secure_boot {
-                error!(
-                    "{} does not currently support systems with secure boot enabled.",
-                    env!("CARGO_PKG_NAME")
+        if opts.migrate() && !check_os(SUPPORTED_OSSES, opts, "Generic x86_64/Intel Nuc")? {
+            return Err(Error::displayed());
+        }
+        let device_type = match DeviceType::from_dmi() {
+            Ok(device_type) => device_type,
+            Err(why) => {
+                warn!(
+                    "Failed to determine device type from DMI, assuming {}, error: {}",
+                    DeviceType::GenericX86_64,
+                    why
                 );
-                return Err(Error::displayed());
+                DeviceType::GenericX86_64
             }
-        }
-        Ok(IntelNuc)
+        };
+
+        Ok(IntelNuc { device_type })
     }
 }
 
@@ -62,6 +55,6 @@ impl<'a> Device for IntelNuc {
         X86_SLUGS.contains(&dev_type)
     }
     fn get_device_type(&self) -> DeviceType {
-        DeviceType::IntelNuc
+        self.device_type
     }
 }
@@ -5,21 +5,28 @@ use crate::stage1::device_impl::check_os;
 use crate::{
     common::{options::Options, Error, ErrorKind, Result},
     stage1::{
-        defs::{DeviceType, DEV_TYPE_RPI1, DEV_TYPE_RPI2, DEV_TYPE_RPI3, DEV_TYPE_RPI4_64},
+        defs::{
+            DeviceType, DEV_TYPE_RPI1, DEV_TYPE_RPI2, DEV_TYPE_RPI3, DEV_TYPE_RPI4,
+            DEV_TYPE_RPI4_64, DEV_TYPE_RPI5,
+        },
         device::Device,
+        utils::is_32bit_userland,
     },
 };
 
 // Pi Zero W:  "Raspberry Pi Zero W Rev 1.1"
 // Balena Fin: "Raspberry Pi Compute Module 3 Plus Rev 1.0"
 // RPI 4:      "Raspberry Pi 4 Model B Rev 1.1"
+// RPI 5:      "Raspberry Pi 5 Model B Rev 1.0"
 // RPI 2:      "Raspberry Pi 2 Model B Rev 1.1"
 // RPI Zero W: "Raspberry Pi Zero W Rev 1.1"
-const RPI_MODEL_REGEX: &str = r#"^Raspberry\s+Pi\s+(1|2|3|4|Compute Module 3|Zero)\s+(Model\s+(\S+)|W|Plus)\s+(Rev\s+(\S+))$"#;
+const RPI_MODEL_REGEX: &str = r#"^Raspberry\s+Pi\s+(1|2|3|4|5|Compute Module 3|Zero)\s+(Model\s+(\S+)|W|Plus)\s+(Rev\s+(\S+))$"#;
 const RPI1_SLUGS: [&str; 1] = [DEV_TYPE_RPI1];
 const RPI2_SLUGS: [&str; 1] = [DEV_TYPE_RPI2];
 const RPI3_SLUGS: [&str; 1] = [DEV_TYPE_RPI3];
+const RPI4_SLUGS: [&str; 1] = [DEV_TYPE_RPI4];
 const RPI4_64_SLUGS: [&str; 1] = [DEV_TYPE_RPI4_64];
+const RPI5_SLUGS: [&str; 1] = [DEV_TYPE_RPI5];
 
 const SUPPORTED_OSSES: [&str; 4] = [
     "Raspbian GNU/Linux 8 (jessie)",
@@ -71,14 +78,24 @@ pub(crate) fn is_rpi(opts: &Options, model_string: &str) -> Result<Option<Box<dy
                 Ok(Some(Box::new(RaspberryPi3::from_config(opts)?)))
             }
             "4" => {
-                info!("Identified RaspberryPi 4");
-                Ok(Some(Box::new(RaspberryPi4_64::from_config(opts)?)))
+                // the balena device type depends on the bitness of the installed OS
+                if is_32bit_userland()? {
+                    info!("Identified RaspberryPi 4 running a 32 bit OS");
+                    Ok(Some(Box::new(RaspberryPi4::from_config(opts)?)))
+                } else {
+                    info!("Identified RaspberryPi 4");
+                    Ok(Some(Box::new(RaspberryPi4_64::from_config(opts)?)))
+                }
+            }
+            "5" => {
+                info!("Identified RaspberryPi 5");
+                Ok(Some(Box::new(RaspberryPi5::from_config(opts)?)))
             }
             _ => {
                 debug!("unknown PI type: '{}'", pitype);
                 let message = format!("The raspberry pi type reported by your device ('{} {} rev {}') is not supported by balena-migrate", pitype, model, revision);
                 error!("{}", message);
-                Err(Error::with_context(ErrorKind::InvParam, &message))
+                Err(Error::with_context(ErrorKind::UnsupportedDevice, &message))
             }
         }
     } else {
@@ -151,6 +168,28 @@ impl Device for RaspberryPi3 {
     }
 }
 
+pub(crate) struct RaspberryPi4;
+
+impl RaspberryPi4 {
+    pub fn from_config(opts: &Options) -> Result<RaspberryPi4> {
+        if opts.migrate() && !check_os(&SUPPORTED_OSSES, opts, "Raspberry PI 4")? {
+            return Err(Error::displayed());
+        }
+
+        Ok(RaspberryPi4)
+    }
+}
+
+impl Device for RaspberryPi4 {
+    fn supports_device_type(&self, dev_type: &str) -> bool {
+        RPI4_SLUGS.contains(&dev_type)
+    }
+
+    fn get_device_type(&self) -> DeviceType {
+        DeviceType::RaspberryPi4
+    }
+}
+
 pub(crate) struct RaspberryPi4_64;
 
 impl RaspberryPi4_64 {
@@ -172,3 +211,25 @@ impl Device for RaspberryPi4_64 {
         DeviceType::RaspberryPi4
     }
 }
+
+pub(crate) struct RaspberryPi5;
+
+impl RaspberryPi5 {
+    pub fn from_config(opts: &Options) -> Result<RaspberryPi5> {
+        if opts.migrate() && !check_os(&SUPPORTED_OSSES, opts, "Raspberry PI 5")? {
+            return Err(Error::displayed());
+        }
+
+        Ok(RaspberryPi5)
+    }
+}
+
+impl Device for RaspberryPi5 {
+    fn supports_device_type(&self, dev_type: &str) -> bool {
+        RPI5_SLUGS.contains(&dev_type)
+    }
+
+    fn get_device_type(&self) -> DeviceType {
+        DeviceType::RaspberryPi5
+    }
+}
@@ -0,0 +1,54 @@
+use std::fmt::{self, Debug};
+use std::path::PathBuf;
+
+use log::{error, info};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event")]
+pub(crate) enum Stage1Event {
+    ArchDetected { arch: String },
+    SecureBootChecked { enabled: bool },
+    Mounted { path: PathBuf },
+}
+
+pub(crate) type EventHandler = Box<dyn Fn(&Stage1Event)>;
+
+/// Dispatches stage1 progress events to a pluggable handler, by default events are logged
+pub(crate) struct EventSink {
+    handler: EventHandler,
+}
+
+impl EventSink {
+    pub fn new() -> EventSink {
+        EventSink::with_handler(Box::new(|event| info!("Stage1 event: {:?}", event)))
+    }
+
+    pub fn with_handler(handler: EventHandler) -> EventSink {
+        EventSink { handler }
+    }
+
+    /// Write events as JSON lines to stdout for consumption by a wrapping tool
+    pub fn json_stdout() -> EventSink {
+        EventSink::with_handler(Box::new(|event| match serde_json::to_string(event) {
+            Ok(json) => println!("{}", json),
+            Err(why) => error!("Failed to serialize event {:?}, error: {:?}", event, why),
+        }))
+    }
+
+    pub fn emit(&self, event: Stage1Event) {
+        (self.handler)(&event);
+    }
+}
+
+impl Default for EventSink {
+    fn default() -> Self {
+        EventSink::new()
+    }
+}
+
+impl Debug for EventSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventSink").finish()
+    }
+}
@@ -0,0 +1,433 @@
+use std::fs::{
+    copy as copy_file, create_dir_all, read_to_string, remove_dir, remove_file, rename, write, File,
+};
+use std::io::{self, copy, Read};
+use std::path::{Path, PathBuf};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use log::{debug, info, trace, warn};
+use nix::mount::{mount, MsFlags};
+
+use crate::{
+    common::{
+        defs::{BALENA_BOOT_FSTYPE, NIX_NONE, SYSTEM_CONNECTIONS_DIR},
+        disk_util::{
+            Disk, PartInfo, PartitionIterator, PartitionReader, PartitionType, DEF_BLOCK_SIZE,
+        },
+        file_exists,
+        loop_device::LoopDevice,
+        partition::{read_partitions, PartitionEntry},
+        Error, ErrorKind, Result, ToError,
+    },
+    stage1::{
+        migrate_info::balena_cfg_json::BalenaCfgJson,
+        mount_guard::MountGuard,
+        utils::{mktemp, staging_dir as choose_staging_dir},
+    },
+};
+
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+const CONFIG_JSON: &str = "config.json";
+const DEFAULT_CONNECTION_NAME: &str = "takeover-connection";
+
+fn is_gzip_image(img: &Path) -> Result<bool> {
+    let mut magic = [0u8; 2];
+    File::open(img)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .upstream_with_context(&format!("Failed to read from image '{}'", img.display()))?;
+    Ok(magic == GZIP_MAGIC)
+}
+
+/// Find the first FAT partition, the balena boot partition holding config.json
+fn find_boot_partition(part_iterator: &mut PartitionIterator) -> Result<PartInfo> {
+    for part_info in part_iterator {
+        if let PartitionType::Fat = PartitionType::from_ptype(part_info.ptype) {
+            debug!(
+                "find_boot_partition: found FAT partition {} at lba {}",
+                part_info.index, part_info.start_lba
+            );
+            return Ok(part_info);
+        }
+    }
+    Err(Error::with_context(
+        ErrorKind::NotFound,
+        "No FAT boot partition found in image",
+    ))
+}
+
+/// Find the first FAT partition in the uncompressed image img, MBR or GPT
+fn find_raw_boot_partition(img: &Path) -> Result<PartitionEntry> {
+    let boot_part = read_partitions(img)?
+        .into_iter()
+        .find(|partition| partition.is_fat())
+        .ok_or_else(|| {
+            Error::with_context(
+                ErrorKind::NotFound,
+                &format!("No FAT boot partition found in image '{}'", img.display()),
+            )
+        })?;
+    debug!(
+        "find_raw_boot_partition: found partition {} of type {} at offset {}",
+        boot_part.get_index(),
+        boot_part.get_type_id(),
+        boot_part.get_start()
+    );
+    Ok(boot_part)
+}
+
+/// Extract the boot partition of the compressed image img to a temporary file in staging_dir
+/// if configured, otherwise it is staged in memory if it fits or in disk_dir. Returns the
+/// temporary file and the partition info
+fn extract_boot_partition(
+    img: &Path,
+    staging_dir: Option<&Path>,
+    disk_dir: &Path,
+) -> Result<(PathBuf, PartInfo)> {
+    let mut disk = Disk::from_gzip_img(img)?;
+    let mut part_iterator = PartitionIterator::new(&mut disk)?;
+    let part_info = find_boot_partition(&mut part_iterator)?;
+    let work_dir = if let Some(staging_dir) = staging_dir {
+        staging_dir.to_path_buf()
+    } else {
+        choose_staging_dir(part_info.num_sectors * DEF_BLOCK_SIZE as u64, disk_dir)?
+    };
+    let boot_file = mktemp(false, Some("boot."), Some(".img"), Some(&work_dir))?;
+    let mut reader = PartitionReader::from_part_iterator(&part_info, &mut part_iterator);
+    let res = File::create(&boot_file)
+        .and_then(|mut file| copy(&mut reader, &mut file))
+        .upstream_with_context(&format!(
+            "Failed to extract boot partition from '{}' to '{}'",
+            img.display(),
+            boot_file.display()
+        ));
+    if let Err(why) = res {
+        let _res = remove_file(&boot_file);
+        return Err(why);
+    }
+    Ok((boot_file, part_info))
+}
+
+/// Recompress the gzip image img with the size bytes at offset replaced by the content of
+/// part_file, streaming through the decompressed image. The new image is written to work_dir
+/// and replaces img when complete.
+fn replace_gzip_partition(
+    img: &Path,
+    part_file: &Path,
+    offset: u64,
+    size: u64,
+    work_dir: &Path,
+) -> Result<()> {
+    trace!(
+        "replace_gzip_partition: entered with '{}', '{}', offset: {}, size: {}",
+        img.display(),
+        part_file.display(),
+        offset,
+        size
+    );
+    let new_img = mktemp(false, Some("image."), Some(".img.gz"), Some(work_dir))?;
+    let res = File::open(img)
+        .and_then(|img_file| {
+            let mut decoder = GzDecoder::new(img_file);
+            let mut encoder = GzEncoder::new(File::create(&new_img)?, Compression::default());
+            io::copy(&mut (&mut decoder).take(offset), &mut encoder)?;
+            let written = io::copy(&mut File::open(part_file)?.take(size), &mut encoder)?;
+            let skipped = io::copy(&mut (&mut decoder).take(size), &mut io::sink())?;
+            if written != size || skipped != size {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!(
+                        "partition of {} bytes, replaced {} bytes with {} bytes",
+                        size, skipped, written
+                    ),
+                ));
+            }
+            io::copy(&mut decoder, &mut encoder)?;
+            encoder.finish()?.sync_all()
+        })
+        // the staging directory might be on a different file system than the image
+        .and_then(|_| rename(&new_img, img).or_else(|_| copy_file(&new_img, img).map(|_| ())))
+        .upstream_with_context(&format!(
+            "Failed to write modified image '{}' to '{}'",
+            img.display(),
+            new_img.display()
+        ));
+    if file_exists(&new_img) {
+        let _res = remove_file(&new_img);
+    }
+    res
+}
+
+/// Mount the region of file at offset on a temporary mountpoint in work_dir and call f with
+/// the mountpoint
+fn mount_and_call<T, F: FnOnce(&Path) -> Result<T>>(
+    file: &Path,
+    offset: Option<u64>,
+    size: Option<u64>,
+    read_only: bool,
+    work_dir: &Path,
+    f: F,
+) -> Result<T> {
+    let loop_device = LoopDevice::for_file(file, offset, size, None, true)?;
+    let mountpoint = mktemp(true, Some("boot-mnt."), None, Some(work_dir))?;
+    let flags = if read_only {
+        MsFlags::MS_RDONLY
+    } else {
+        MsFlags::empty()
+    };
+
+    let res = mount(
+        Some(loop_device.get_path()),
+        &mountpoint,
+        Some(BALENA_BOOT_FSTYPE.as_bytes()),
+        flags,
+        NIX_NONE,
+    )
+    .upstream_with_context(&format!(
+        "Failed to mount '{}' on '{}'",
+        loop_device.get_path().display(),
+        mountpoint.display()
+    ))
+    .and_then(|_| {
+        // unmounts before the loop device is released
+        let mut guard = MountGuard::new();
+        guard.add(&mountpoint);
+        f(&mountpoint)
+    });
+
+    if let Err(why) = remove_dir(&mountpoint) {
+        warn!(
+            "Failed to remove temporary mountpoint '{}', error: {:?}",
+            mountpoint.display(),
+            why
+        );
+    }
+    res
+}
+
+/// Mount the boot partition of img in a temporary directory and call f with the mountpoint.
+/// For compressed images the boot partition is extracted to a temporary file first, if it is
+/// mounted writable the image is recompressed with the modified partition afterwards.
+/// Temporary files are created in staging_dir if given, otherwise next to img.
+fn with_boot_partition<T, F: FnOnce(&Path) -> Result<T>>(
+    img: &Path,
+    read_only: bool,
+    staging_dir: Option<&Path>,
+    f: F,
+) -> Result<T> {
+    let img_dir = img.parent().unwrap_or_else(|| Path::new("/tmp"));
+    let work_dir = staging_dir.unwrap_or(img_dir);
+    if is_gzip_image(img)? {
+        let (boot_file, part_info) = extract_boot_partition(img, staging_dir, img_dir)?;
+        let boot_dir = boot_file.parent().unwrap_or(work_dir);
+        let res = mount_and_call(&boot_file, None, None, read_only, boot_dir, f).and_then(|res| {
+            if !read_only {
+                let block_size = DEF_BLOCK_SIZE as u64;
+                replace_gzip_partition(
+                    img,
+                    &boot_file,
+                    part_info.start_lba * block_size,
+                    part_info.num_sectors * block_size,
+                    work_dir,
+                )?;
+            }
+            Ok(res)
+        });
+        if let Err(why) = remove_file(&boot_file) {
+            warn!(
+                "Failed to remove extracted boot partition '{}', error: {:?}",
+                boot_file.display(),
+                why
+            );
+        }
+        res
+    } else {
+        let boot_part = find_raw_boot_partition(img)?;
+        mount_and_call(
+            img,
+            Some(boot_part.get_start()),
+            Some(boot_part.get_size()),
+            read_only,
+            work_dir,
+            f,
+        )
+    }
+}
+
+/// Read the device type from the config.json in the boot partition of img, fails with
+/// ErrorKind::NotFound if the image contains no config.json. Temporary files are created in
+/// staging_dir if given.
+#[allow(dead_code)]
+pub(crate) fn read_image_device_type(img: &Path, staging_dir: Option<&Path>) -> Result<String> {
+    trace!("read_image_device_type: entered with '{}'", img.display());
+    with_boot_partition(img, true, staging_dir, |mountpoint| {
+        let config_path = mountpoint.join(CONFIG_JSON);
+        if !file_exists(&config_path) {
+            return Err(Error::with_context(
+                ErrorKind::NotFound,
+                &format!("No {} found in image '{}'", CONFIG_JSON, img.display()),
+            ));
+        }
+        BalenaCfgJson::new(&config_path)?.get_device_type()
+    })
+}
+
+/// Derive the file name for a NetworkManager connection from its id, characters that are not
+/// safe in file names are replaced
+fn connection_file_name(nm_connection: &str) -> String {
+    let mut in_connection = false;
+    for line in nm_connection.lines().map(|line| line.trim()) {
+        if line.starts_with('[') {
+            in_connection = line == "[connection]";
+        } else if in_connection {
+            if let Some(id) = line.strip_prefix("id=") {
+                let name: String = id
+                    .trim()
+                    .chars()
+                    .map(|c| {
+                        if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                            c
+                        } else {
+                            '_'
+                        }
+                    })
+                    .collect();
+                if !name.is_empty() && !name.starts_with('.') {
+                    return name;
+                }
+            }
+        }
+    }
+    String::from(DEFAULT_CONNECTION_NAME)
+}
+
+/// Write the NetworkManager connection nm_connection to the system-connections directory of
+/// the boot partition of the image img, so the device is reachable after flashing. Compressed
+/// images are recompressed, which takes a while for large images
+#[allow(dead_code)]
+pub(crate) fn inject_network_config(
+    img: &Path,
+    nm_connection: &str,
+    staging_dir: Option<&Path>,
+) -> Result<()> {
+    trace!("inject_network_config: entered with '{}'", img.display());
+    if nm_connection.trim().is_empty() {
+        return Err(Error::with_context(
+            ErrorKind::InvParam,
+            "Refusing to inject an empty network connection",
+        ));
+    }
+
+    with_boot_partition(img, false, staging_dir, |mountpoint| {
+        let connections_dir = mountpoint.join(SYSTEM_CONNECTIONS_DIR);
+        create_dir_all(&connections_dir).upstream_with_context(&format!(
+            "Failed to create directory '{}'",
+            connections_dir.display()
+        ))?;
+
+        let connection_path = connections_dir.join(connection_file_name(nm_connection));
+        if file_exists(&connection_path) {
+            if let Ok(existing) = read_to_string(&connection_path) {
+                if existing == nm_connection {
+                    warn!(
+                        "An identical network connection already exists in '{}'",
+                        connection_path.display()
+                    );
+                    return Ok(());
+                }
+            }
+            warn!(
+                "Replacing existing network connection '{}'",
+                connection_path.display()
+            );
+        }
+
+        write(&connection_path, nm_connection).upstream_with_context(&format!(
+            "Failed to write network connection to '{}'",
+            connection_path.display()
+        ))?;
+        info!(
+            "Injected network connection '{}' into image '{}'",
+            connection_path.display(),
+            img.display()
+        );
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connection_file_name_from_id() {
+        assert_eq!(
+            connection_file_name("[connection]\nid=My Wifi\ntype=wifi\n"),
+            "My_Wifi"
+        );
+        assert_eq!(
+            connection_file_name("[wifi]\nid=other\n[connection]\ntype=wifi\n"),
+            DEFAULT_CONNECTION_NAME
+        );
+        assert_eq!(
+            connection_file_name("[connection]\nid=../etc\n"),
+            DEFAULT_CONNECTION_NAME
+        );
+        assert_eq!(connection_file_name("[connection]\nid=a/b\n"), "a_b");
+    }
+
+    #[test]
+    fn replaces_partition_in_gzip_image() {
+        use std::io::Write;
+
+        let dir = std::env::temp_dir().join(format!("takeover_gzpart_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let img = dir.join("image.img.gz");
+        let part = dir.join("part.img");
+        let content: Vec<u8> = (0..4096u32).map(|val| (val % 251) as u8).collect();
+        let mut encoder = GzEncoder::new(File::create(&img).unwrap(), Compression::default());
+        encoder.write_all(&content).unwrap();
+        encoder.finish().unwrap();
+        std::fs::write(&part, vec![0xAAu8; 1024]).unwrap();
+
+        let res = replace_gzip_partition(&img, &part, 1024, 1024, &dir);
+        let mut result = Vec::new();
+        GzDecoder::new(File::open(&img).unwrap())
+            .read_to_end(&mut result)
+            .unwrap();
+        let too_short = replace_gzip_partition(&img, &part, 1024, 2048, &dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        res.unwrap();
+        let mut expected = content.clone();
+        expected[1024..2048].copy_from_slice(&[0xAAu8; 1024]);
+        assert_eq!(result, expected);
+        assert!(too_short.is_err());
+    }
+
+    #[test]
+    fn finds_fat_partition_in_raw_image() {
+        let mut image = vec![0u8; 4096];
+        // second partition entry of the MBR is FAT32 LBA starting at sector 2
+        let entry = 446 + 16;
+        image[entry + 4] = 0x0c;
+        image[entry + 8..entry + 12].copy_from_slice(&2u32.to_le_bytes());
+        image[entry + 12..entry + 16].copy_from_slice(&4u32.to_le_bytes());
+        image[446 + 4] = 0x83;
+        image[446 + 8..446 + 12].copy_from_slice(&6u32.to_le_bytes());
+        image[446 + 12..446 + 16].copy_from_slice(&2u32.to_le_bytes());
+        image[510] = 0x55;
+        image[511] = 0xAA;
+
+        let path = std::env::temp_dir().join(format!("takeover_boot_{}.img", std::process::id()));
+        std::fs::write(&path, &image).unwrap();
+        let is_gzip = is_gzip_image(&path);
+        let boot_part = find_raw_boot_partition(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(!is_gzip.unwrap());
+        let boot_part = boot_part.unwrap();
+        assert_eq!(boot_part.get_index(), 2);
+        assert_eq!(boot_part.get_start(), 2 * DEF_BLOCK_SIZE as u64);
+        assert_eq!(boot_part.get_size(), 4 * DEF_BLOCK_SIZE as u64);
+    }
+}
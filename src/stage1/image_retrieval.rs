@@ -8,8 +8,11 @@ use semver::{Identifier, Version, VersionReq};
 
 use crate::{
     common::{
+        available_space,
         defs::NIX_NONE,
-        disk_util::{Disk, PartitionIterator, PartitionReader},
+        disk_util::{Disk, PartitionIterator, PartitionReader, DEF_BLOCK_SIZE},
+        format_size_with_unit,
+        image_writer::{copy_image_digest, HashWriter},
         is_admin,
         loop_device::LoopDevice,
         path_append,
@@ -20,7 +23,7 @@ use crate::{
         api_calls::{get_os_image, get_os_versions, Versions},
         defs::{
             DEV_TYPE_BBB, DEV_TYPE_BBG, DEV_TYPE_GEN_X86_64, DEV_TYPE_INTEL_NUC, DEV_TYPE_RPI1,
-            DEV_TYPE_RPI2, DEV_TYPE_RPI3, DEV_TYPE_RPI4_64,
+            DEV_TYPE_RPI2, DEV_TYPE_RPI3, DEV_TYPE_RPI4, DEV_TYPE_RPI4_64, DEV_TYPE_RPI5,
         },
         migrate_info::balena_cfg_json::BalenaCfgJson,
     },
@@ -36,10 +39,12 @@ const FLASHER_DEVICES: [&str; 4] = [
     DEV_TYPE_BBG,
     DEV_TYPE_BBB,
 ];
-const SUPPORTED_DEVICES: [&str; 8] = [
+const SUPPORTED_DEVICES: [&str; 10] = [
     DEV_TYPE_RPI3,
     DEV_TYPE_RPI2,
+    DEV_TYPE_RPI4,
     DEV_TYPE_RPI4_64,
+    DEV_TYPE_RPI5,
     DEV_TYPE_RPI1,
     DEV_TYPE_INTEL_NUC,
     DEV_TYPE_GEN_X86_64,
@@ -47,6 +52,9 @@ const SUPPORTED_DEVICES: [&str; 8] = [
     DEV_TYPE_BBB,
 ];
 
+// extra space required in work_dir in addition to the downloaded image or extracted partition
+const EXTRACT_SPACE_MARGIN: u64 = 64 * 1024 * 1024;
+
 const IMG_NAME_GEN_X86_64: &str = "resin-image-genericx86-64-ext.resinos-img";
 const IMG_NAME_INTEL_NUC: &str = "resin-image-genericx86-64.resinos-img";
 const IMG_NAME_BBG: &str = "resin-image-beaglebone-green.resinos-img";
@@ -166,17 +174,35 @@ fn determine_version(ver_str: &str, versions: &Versions) -> Result<Version> {
     }
 }
 
+/// Extract the flasher image from the root_a partition of the downloaded image and write it
+/// compressed to image_file_name. Returns the SHA-256 and size of the uncompressed image
 fn extract_image<P1: AsRef<Path>, P2: AsRef<Path>>(
     stream: Box<dyn Read>,
     image_file_name: P1,
     device_type: &str,
     work_dir: P2,
-) -> Result<()> {
+) -> Result<(String, u64)> {
     let work_dir = work_dir.as_ref();
     let progress = StreamProgress::new(stream, 10, Level::Info, None);
     let mut disk = Disk::from_gzip_stream(progress)?;
     let mut part_iterator = PartitionIterator::new(&mut disk)?;
     if let Some(part_info) = part_iterator.nth(1) {
+        let part_size = part_info.num_sectors * DEF_BLOCK_SIZE as u64;
+        let required = part_size + EXTRACT_SPACE_MARGIN;
+        let available = available_space(work_dir)?;
+        if available < required {
+            return Err(Error::with_context(
+                ErrorKind::InvState,
+                &format!(
+                    "Not enough space in '{}' to extract the balena OS image, required {}, available {}, missing {}",
+                    work_dir.display(),
+                    format_size_with_unit(required),
+                    format_size_with_unit(available),
+                    format_size_with_unit(required - available)
+                ),
+            ));
+        }
+
         let mut reader = PartitionReader::from_part_iterator(&part_info, &mut part_iterator);
         let extract_file_name = path_append(work_dir, "root_a.img");
         let mut tmp_file = File::create(&extract_file_name).upstream_with_context(&format!(
@@ -240,8 +266,8 @@ fn extract_image<P1: AsRef<Path>, P2: AsRef<Path>>(
         debug!("image path is '{}'", img_path.display());
         let img_file_name = image_file_name.as_ref();
 
-        {
-            let mut gz_writer = GzBuilder::new().write(
+        let digest = {
+            let gz_writer = GzBuilder::new().write(
                 File::create(img_file_name).upstream_with_context(&format!(
                     "Failed to open image file for writing: '{}'",
                     img_file_name.display()
@@ -267,12 +293,17 @@ fn extract_image<P1: AsRef<Path>, P2: AsRef<Path>>(
 
             let mut stream_progress = StreamProgress::new(img_reader, 10, Level::Info, size);
 
-            copy(&mut stream_progress, &mut gz_writer).upstream_with_context(&format!(
-                "Failed to compress image '{}' to '{}'",
-                img_path.display(),
-                img_file_name.display()
-            ))?;
-        }
+            // hash the uncompressed image while it is compressed
+            let mut hash_writer = HashWriter::new(gz_writer);
+            let size =
+                copy(&mut stream_progress, &mut hash_writer).upstream_with_context(&format!(
+                    "Failed to compress image '{}' to '{}'",
+                    img_path.display(),
+                    img_file_name.display()
+                ))?;
+            let (_gz_writer, sha256) = hash_writer.into_inner();
+            (sha256, size)
+        };
 
         info!(
             "The balena OS image was successfully written to '{}', cleaning up",
@@ -307,7 +338,7 @@ fn extract_image<P1: AsRef<Path>, P2: AsRef<Path>>(
                 why
             );
         }
-        Ok(())
+        Ok(digest)
     } else {
         Err(Error::with_context(
             ErrorKind::InvState,
@@ -316,12 +347,14 @@ fn extract_image<P1: AsRef<Path>, P2: AsRef<Path>>(
     }
 }
 
+/// Download the balena OS image to work_dir. The image is hashed while it is written, returns
+/// the path of the image with the SHA-256 and size of its uncompressed content
 pub(crate) fn download_image(
     balena_cfg: &BalenaCfgJson,
     work_dir: &Path,
     device_type: &str,
     version: &str,
-) -> Result<PathBuf> {
+) -> Result<(PathBuf, (String, u64))> {
     if !SUPPORTED_DEVICES.contains(&device_type) {
         return Err(Error::with_context(
             ErrorKind::InvParam,
@@ -351,7 +384,26 @@ pub(crate) fn download_image(
 
     // TODO: extract OS image for flasher
 
-    let stream = get_os_image(&api_endpoint, &api_key, device_type, &version.to_string())?;
+    let (stream, image_size) =
+        get_os_image(&api_endpoint, &api_key, device_type, &version.to_string())?;
+
+    // fail before anything is written, the download would run out of space half way through
+    if let Some(image_size) = image_size {
+        let required = image_size + EXTRACT_SPACE_MARGIN;
+        let available = available_space(work_dir)?;
+        if available < required {
+            return Err(Error::with_context(
+                ErrorKind::InvState,
+                &format!(
+                    "Not enough space in '{}' to download the balena OS image, required {}, available {}, missing {}",
+                    work_dir.display(),
+                    format_size_with_unit(required),
+                    format_size_with_unit(available),
+                    format_size_with_unit(required - available)
+                ),
+            ));
+        }
+    }
 
     let img_file_name = path_append(
         work_dir,
@@ -367,7 +419,8 @@ pub(crate) fn download_image(
             error!("please run this program as root");
             return Err(Error::displayed());
         }
-        extract_image(stream, &img_file_name, device_type, work_dir)?;
+        let digest = extract_image(stream, &img_file_name, device_type, work_dir)?;
+        Ok((img_file_name, digest))
     } else {
         debug!("Downloading file '{}'", img_file_name.display());
         let mut file = File::create(&img_file_name).upstream_with_context(&format!(
@@ -376,16 +429,20 @@ pub(crate) fn download_image(
         ))?;
 
         // TODO: show progress
-        let mut progress = StreamProgress::new(stream, 10, Level::Info, None);
-        copy(&mut progress, &mut file).upstream_with_context(&format!(
-            "Failed to write downloaded data to '{}'",
-            img_file_name.display()
-        ))?;
+        let progress = StreamProgress::new(stream, 10, Level::Info, None);
+        let digest = copy_image_digest(progress, &mut file).map_err(|why| {
+            Error::from_upstream(
+                Box::new(why),
+                &format!(
+                    "Failed to write downloaded data to '{}'",
+                    img_file_name.display()
+                ),
+            )
+        })?;
         info!(
             "The balena OS image was successfully written to '{}'",
             img_file_name.display()
         );
+        Ok((img_file_name, digest))
     }
-
-    Ok(img_file_name)
 }
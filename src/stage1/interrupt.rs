@@ -0,0 +1,59 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::{debug, trace};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+
+use crate::common::{Error, ErrorKind, Result, ToError};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+const INTERRUPT_SIGNALS: [Signal; 2] = [Signal::SIGINT, Signal::SIGTERM];
+
+// only stores the flag, everything else is unsafe in a signal context
+extern "C" fn handle_interrupt(_signal: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Install handlers for SIGINT and SIGTERM that record the interrupt instead of terminating
+/// the process, so teardown can run from check_interrupted
+pub(crate) fn install_interrupt_handler() -> Result<()> {
+    trace!("install_interrupt_handler: entered");
+    let action = SigAction::new(
+        SigHandler::Handler(handle_interrupt),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+    for signal in INTERRUPT_SIGNALS.iter() {
+        // handle_interrupt only touches an atomic
+        unsafe { sigaction(*signal, &action) }
+            .upstream_with_context(&format!("Failed to install handler for signal {}", signal))?;
+    }
+    debug!("install_interrupt_handler: handling SIGINT and SIGTERM");
+    Ok(())
+}
+
+/// Fail with ErrorKind::Interrupted if SIGINT or SIGTERM was received
+pub(crate) fn check_interrupted() -> Result<()> {
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        Err(Error::with_context(
+            ErrorKind::Interrupted,
+            "Takeover was interrupted by a signal",
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flag_raises_interrupted() {
+        assert!(check_interrupted().is_ok());
+        handle_interrupt(libc::SIGINT);
+        let res = check_interrupted();
+        INTERRUPTED.store(false, Ordering::SeqCst);
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::Interrupted);
+    }
+}
@@ -1,22 +1,29 @@
 use log::{debug, error, info, warn};
-use nix::mount::umount;
-use std::fs::{read_to_string, remove_dir_all, OpenOptions};
+use nix::unistd::{access, AccessFlags};
+use serde::{Deserialize, Serialize};
+use std::fs::{read_to_string, remove_dir_all, write, OpenOptions};
 use std::path::{Path, PathBuf};
 use std::ptr::read_volatile;
 
 use crate::common::defs::BACKUP_ARCH_NAME;
-use crate::common::path_append;
+use crate::common::{format_size_with_unit, image_writer::image_digest, path_append};
 use crate::{
-    common::{file_exists, get_os_name, options::Options, Error, ErrorKind, Result, ToError},
+    common::{
+        file_exists, get_os_name, options::Options, read_os_release, Error, ErrorKind, Result,
+        ToError,
+    },
     stage1::{
         backup::config::backup_cfg_from_file,
         backup::{create, create_ext},
-        defs::{DEV_TYPE_GEN_X86_64, GZIP_MAGIC_COOKIE, MAX_CONFIG_JSON},
+        block_device_info::{check_flash_writable, mount::Mount},
+        defs::{DEV_TYPE_GEN_X86_64, GZIP_MAGIC_COOKIE, MAX_CONFIG_JSON, PROGRESS_FILE_NAME},
         device::Device,
         device_impl::get_device,
+        events::{EventHandler, EventSink, Stage1Event},
         image_retrieval::download_image,
         migrate_info::balena_cfg_json::BalenaCfgJson,
-        utils::mktemp,
+        steps::{Stage1Step, StepRecord},
+        utils::{mktemp, read_hostname, unmount},
         wifi_config::WifiConfig,
     },
 };
@@ -25,6 +32,120 @@ use crate::stage1::utils::ReadBuffer;
 use flate2::read::GzDecoder;
 use std::io::copy;
 
+/// Make sure the configured staging directory exists and is writable
+pub(crate) fn check_staging_dir(staging_dir: &Path) -> Result<PathBuf> {
+    let staging_dir = staging_dir.canonicalize().map_err(|why| {
+        Error::with_all(
+            ErrorKind::InvParam,
+            &format!(
+                "The staging directory '{}' could not be found",
+                staging_dir.display()
+            ),
+            Box::new(why),
+        )
+    })?;
+    if !staging_dir.is_dir() {
+        return Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!(
+                "The staging directory '{}' is not a directory",
+                staging_dir.display()
+            ),
+        ));
+    }
+    access(&staging_dir, AccessFlags::W_OK | AccessFlags::X_OK).map_err(|why| {
+        Error::with_all(
+            ErrorKind::InvParam,
+            &format!(
+                "The staging directory '{}' is not writable",
+                staging_dir.display()
+            ),
+            Box::new(why),
+        )
+    })?;
+    Ok(staging_dir)
+}
+
+fn reverse_paths(paths: &[PathBuf]) -> impl Iterator<Item = &Path> {
+    paths.iter().rev().map(|path| path.as_path())
+}
+
+/// The options that determine the results of the setup steps, progress saved for other
+/// options is not used
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct ProgressKey {
+    device_type: String,
+    version: String,
+    image: Option<PathBuf>,
+    backup_config: Option<PathBuf>,
+}
+
+impl ProgressKey {
+    fn new(opts: &Options, config: &BalenaCfgJson) -> Result<ProgressKey> {
+        Ok(ProgressKey {
+            device_type: config.get_device_type()?,
+            version: opts.version().to_string(),
+            image: opts.image().clone(),
+            backup_config: opts.backup_config().map(|path| path.to_path_buf()),
+        })
+    }
+}
+
+/// The progress of stage1 saved in the work directory, so a retry in a new process can skip
+/// the completed idempotent steps
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct Progress {
+    key: ProgressKey,
+    completed_step: Option<Stage1Step>,
+    image_path: Option<PathBuf>,
+    image_digest: Option<(String, u64)>,
+    backup: Option<PathBuf>,
+}
+
+impl Progress {
+    /// Load the progress saved for key, starting over if there is none
+    fn load(path: &Path, key: ProgressKey) -> Progress {
+        if file_exists(path) {
+            match read_to_string(path)
+                .map_err(|why| why.to_string())
+                .and_then(|json| {
+                    serde_json::from_str::<Progress>(&json).map_err(|why| why.to_string())
+                }) {
+                Ok(progress) if progress.key == key => {
+                    info!(
+                        "Found progress of an earlier run in '{}', completed step: {:?}",
+                        path.display(),
+                        progress.completed_step
+                    );
+                    return progress;
+                }
+                Ok(_) => info!(
+                    "Ignoring progress in '{}' saved for different options",
+                    path.display()
+                ),
+                Err(why) => warn!(
+                    "Ignoring invalid progress in '{}', error: {}",
+                    path.display(),
+                    why
+                ),
+            }
+        }
+        Progress {
+            key,
+            ..Progress::default()
+        }
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string(self).upstream_with_context(&format!(
+            "Failed to serialize progress to '{}'",
+            path.display()
+        ))?;
+        write(path, json)
+            .upstream_with_context(&format!("Failed to write progress to '{}'", path.display()))
+    }
+}
+
 #[link_section = ".config_json_section"]
 static CONFIG_JSON: [u8; MAX_CONFIG_JSON] = [0; MAX_CONFIG_JSON];
 
@@ -35,41 +156,46 @@ pub(crate) struct MigrateInfo {
     os_name: String,
     // assets: Assets,
     mounts: Vec<PathBuf>,
+    swaps: Vec<PathBuf>,
+    loop_devices: Vec<PathBuf>,
     to_dir: Option<PathBuf>,
-    image_path: PathBuf,
-    device: Box<dyn Device>,
+    image_path: Option<PathBuf>,
+    // SHA-256 and size of the uncompressed image
+    image_digest: Option<(String, u64)>,
+    device: Option<Box<dyn Device>>,
     config: BalenaCfgJson,
     work_dir: PathBuf,
     wifis: Vec<WifiConfig>,
     nwmgr_files: Vec<PathBuf>,
     backup: Option<PathBuf>,
+    dry_run: bool,
+    events: EventSink,
+    flash_to: Option<PathBuf>,
+    staging_dir: Option<PathBuf>,
+    check_timeout: u64,
+    root_mount: Option<Mount>,
+    completed_step: Option<Stage1Step>,
+    progress: Progress,
 }
 
 #[allow(dead_code)]
 impl MigrateInfo {
     pub fn new(opts: &Options) -> Result<MigrateInfo> {
-        let device = get_device(opts)?;
-        info!("Detected device type: {}", device.get_device_type());
+        let staging_dir = if let Some(staging_dir) = opts.staging_dir() {
+            let staging_dir = check_staging_dir(staging_dir)?;
+            info!("Using '{}' as staging directory", staging_dir.display());
+            Some(staging_dir)
+        } else {
+            None
+        };
 
-        let mut config = if let Some(balena_cfg) = opts.config() {
-            BalenaCfgJson::new(balena_cfg)?
+        let events = if opts.json_events() {
+            EventSink::json_stdout()
         } else {
-            match MigrateInfo::get_internal_cfg_json(&opts.work_dir()) {
-                Ok(balena_cfg_json) => balena_cfg_json,
-                Err(why) => {
-                    if why.kind() == ErrorKind::NotFound {
-                        error!("The required parameter --config/-c was not provided and no internal config.json was found");
-                        return Err(Error::displayed());
-                    } else {
-                        return Err(why);
-                    }
-                }
-            }
+            EventSink::new()
         };
 
-        if opts.migrate() {
-            config.check(opts, &*device)?;
-        }
+        let mut config = MigrateInfo::load_config(opts, staging_dir.as_deref())?;
 
         info!(
             "config.json is for device type {}",
@@ -84,12 +210,89 @@ impl MigrateInfo {
                 opts.work_dir().display()
             ))?;
 
-        let image_path = if let Some(image_path) = opts.image() {
+        if opts.migrate_name() {
+            let hostname = read_hostname()?;
+
+            info!("Writing hostname to config.json: '{}'", hostname);
+            config.set_host_name(&hostname);
+        }
+
+        if opts.migrate() {
+            match read_os_release() {
+                Ok(release) => info!(
+                    "Migrating from {} (id: {}, version: {})",
+                    release.pretty_name.as_deref().unwrap_or("unknown OS"),
+                    release.id,
+                    release.version_id.as_deref().unwrap_or("unknown")
+                ),
+                Err(why) => warn!("Failed to identify the running OS: {}", why),
+            }
+        }
+
+        let progress = Progress::load(
+            &path_append(&work_dir, PROGRESS_FILE_NAME),
+            ProgressKey::new(opts, &config)?,
+        );
+
+        Ok(MigrateInfo {
+            // assets: Assets::new(),
+            os_name: get_os_name()?,
+            to_dir: None,
+            mounts: Vec::new(),
+            swaps: Vec::new(),
+            loop_devices: Vec::new(),
+            config,
+            image_path: None,
+            image_digest: None,
+            device: None,
+            work_dir,
+            wifis: Vec::new(),
+            nwmgr_files: Vec::from(opts.nwmgr_cfg()),
+            backup: None,
+            dry_run: opts.dry_run(),
+            events,
+            flash_to: opts.flash_to().clone(),
+            staging_dir,
+            check_timeout: opts.check_timeout(),
+            root_mount: None,
+            completed_step: None,
+            progress,
+        })
+    }
+
+    /// Detect the device type and check it matches config.json
+    pub fn detect_device(&mut self, opts: &Options) -> Result<()> {
+        let device = get_device(opts, &self.events)?;
+        info!("Detected device type: {}", device.get_device_type());
+
+        if opts.migrate() {
+            self.config.check(opts, &*device)?;
+        }
+        self.device = Some(device);
+        Ok(())
+    }
+
+    /// Find the root file system mount and make sure the disk to flash can be written
+    pub fn resolve_root(&mut self, opts: &Options) -> Result<()> {
+        self.root_mount = Mount::get_mount_at(Path::new("/")).unwrap_or_default();
+        if opts.migrate() {
+            // no point in staging the image for a disk that can not be written
+            check_flash_writable(opts.flash_to().as_deref())?;
+        }
+        Ok(())
+    }
+
+    /// Use the image given with --image or download it to the staging or work directory.
+    /// Downloads are hashed while they are written, a given image is hashed once here
+    pub fn stage_image(&mut self, opts: &Options) -> Result<()> {
+        let (image_path, (image_sha256, image_size)) = if let Some(image_path) = opts.image() {
             if file_exists(&image_path) {
-                image_path.canonicalize().upstream_with_context(&format!(
+                let image_path = image_path.canonicalize().upstream_with_context(&format!(
                     "Failed to canonicalize path '{}'",
                     image_path.display()
-                ))?
+                ))?;
+                let digest = image_digest(&image_path)?;
+                (image_path, digest)
             } else {
                 error!(
                     "The balena-os image configured as '{}' could not be found",
@@ -98,38 +301,43 @@ impl MigrateInfo {
                 return Err(Error::displayed());
             }
         } else {
-            let image_path = download_image(
-                &config,
-                &work_dir,
-                config.get_device_type()?.as_str(),
+            let (image_path, digest) = download_image(
+                &self.config,
+                self.staging_dir().unwrap_or(&self.work_dir),
+                self.config.get_device_type()?.as_str(),
                 opts.version(),
             )?;
-            image_path.canonicalize().upstream_with_context(&format!(
+            let image_path = image_path.canonicalize().upstream_with_context(&format!(
                 "Failed to canonicalize path '{}'",
                 image_path.display()
-            ))?
+            ))?;
+            (image_path, digest)
         };
 
-        if !opts.migrate() {
-            return Err(Error::with_context(
-                ErrorKind::ImageDownloaded,
-                "Image downloaded successfully",
-            ));
-        }
-
         debug!("image path: '{}'", image_path.display());
+        info!(
+            "Image '{}' has {} uncompressed, SHA-256 '{}'",
+            image_path.display(),
+            format_size_with_unit(image_size),
+            image_sha256
+        );
+        self.image_path = Some(image_path);
+        self.image_digest = Some((image_sha256, image_size));
+        Ok(())
+    }
 
+    /// Scan for the configured wifis, the device needs them or network manager files to come
+    /// online
+    pub fn scan_networks(&mut self, opts: &Options) -> Result<()> {
         let wifi_ssids = opts.wifis();
 
-        let wifis: Vec<WifiConfig> = if !wifi_ssids.is_empty() || !opts.no_wifis() {
+        self.wifis = if !wifi_ssids.is_empty() || !opts.no_wifis() {
             WifiConfig::scan(wifi_ssids)?
         } else {
             Vec::new()
         };
 
-        let nwmgr_files = Vec::from(opts.nwmgr_cfg());
-
-        if nwmgr_files.is_empty() && wifis.is_empty() {
+        if self.nwmgr_files.is_empty() && self.wifis.is_empty() {
             if opts.no_nwmgr_check() {
                 warn!(
                     "No Network manager files were found, the device might not be able to come online"
@@ -141,13 +349,21 @@ impl MigrateInfo {
                 return Err(Error::displayed());
             }
         }
+        Ok(())
+    }
 
-        let backup = if let Some(backup_cfg) = opts.backup_config() {
-            let backup_path = path_append(&work_dir, BACKUP_ARCH_NAME);
+    /// Create the backup configured with --backup-cfg in the work directory
+    pub fn create_backup(&mut self, opts: &Options) -> Result<()> {
+        self.backup = if let Some(backup_cfg) = opts.backup_config() {
+            let backup_path = path_append(&self.work_dir, BACKUP_ARCH_NAME);
             let created = if opts.tar_internal() {
                 create(backup_path.as_path(), backup_cfg_from_file(backup_cfg)?)?
             } else {
-                create_ext(backup_path.as_path(), backup_cfg_from_file(backup_cfg)?)?
+                create_ext(
+                    backup_path.as_path(),
+                    backup_cfg_from_file(backup_cfg)?,
+                    self.staging_dir(),
+                )?
             };
             if created {
                 Some(backup_path)
@@ -157,35 +373,50 @@ impl MigrateInfo {
         } else {
             None
         };
+        Ok(())
+    }
 
-        if opts.migrate_name() {
-            let hostname = read_to_string("/proc/sys/kernel/hostname")
-                .upstream_with_context("Failed to read file '/proc/sys/kernel/hostname'")?
-                .trim()
-                .to_string();
-
-            info!("Writing hostname to config.json: '{}'", hostname);
-            config.set_host_name(&hostname);
-        }
-
-        Ok(MigrateInfo {
-            // assets: Assets::new(),
-            os_name: get_os_name()?,
+    /// Create a MigrateInfo without probing the system, for tests of the later stage1 steps
+    #[cfg(test)]
+    pub fn for_test(
+        opts: &Options,
+        device: Box<dyn Device>,
+        config: BalenaCfgJson,
+        image_path: PathBuf,
+    ) -> MigrateInfo {
+        MigrateInfo {
+            os_name: String::from("test OS"),
             to_dir: None,
             mounts: Vec::new(),
+            swaps: Vec::new(),
+            loop_devices: Vec::new(),
             config,
-            image_path,
-            device,
-            work_dir,
-            wifis,
-            nwmgr_files,
-            backup,
-        })
+            image_path: Some(image_path),
+            image_digest: None,
+            device: Some(device),
+            work_dir: opts.work_dir(),
+            wifis: Vec::new(),
+            nwmgr_files: Vec::new(),
+            backup: None,
+            dry_run: opts.dry_run(),
+            events: EventSink::new(),
+            flash_to: opts.flash_to().clone(),
+            staging_dir: opts.staging_dir().clone(),
+            check_timeout: opts.check_timeout(),
+            root_mount: None,
+            completed_step: None,
+            progress: Progress::default(),
+        }
     }
 
     pub fn update_config(&mut self) -> Result<()> {
         if self.config.is_modified() {
-            let target_path = mktemp(false, Some("config."), Some(".json"), Some(&self.work_dir))?;
+            let target_path = mktemp(
+                false,
+                Some("config."),
+                Some(".json"),
+                Some(self.staging_dir().unwrap_or(&self.work_dir)),
+            )?;
             self.config.write(&target_path)?;
             info!("Copied config.json to '{}'", target_path.display());
         }
@@ -201,7 +432,9 @@ impl MigrateInfo {
     }
 
     pub fn is_x86(&self) -> bool {
-        self.device.supports_device_type(DEV_TYPE_GEN_X86_64)
+        self.device
+            .as_ref()
+            .is_some_and(|device| device.supports_device_type(DEV_TYPE_GEN_X86_64))
     }
 
     pub fn backup(&self) -> Option<&Path> {
@@ -212,8 +445,35 @@ impl MigrateInfo {
         }
     }
 
-    pub fn image_path(&self) -> &Path {
-        self.image_path.as_path()
+    pub fn work_dir(&self) -> &Path {
+        self.work_dir.as_path()
+    }
+
+    /// The configured directory to stage temporary files in
+    pub fn staging_dir(&self) -> Option<&Path> {
+        if let Some(staging_dir) = &self.staging_dir {
+            Some(staging_dir.as_path())
+        } else {
+            None
+        }
+    }
+
+    pub fn image_path(&self) -> Result<&Path> {
+        self.image_path.as_deref().ok_or_else(|| {
+            Error::with_context(ErrorKind::InvState, "The image has not been staged yet")
+        })
+    }
+
+    /// The SHA-256 and size of the uncompressed image, computed when it was staged
+    pub fn image_digest(&self) -> Result<(&str, u64)> {
+        if let Some((image_sha256, image_size)) = &self.image_digest {
+            Ok((image_sha256.as_str(), *image_size))
+        } else {
+            Err(Error::with_context(
+                ErrorKind::InvState,
+                "The image has not been staged yet",
+            ))
+        }
     }
 
     pub fn balena_cfg(&self) -> &BalenaCfgJson {
@@ -228,6 +488,60 @@ impl MigrateInfo {
         &self.mounts
     }
 
+    /// The recorded mountpoints in the order they have to be unmounted, last mounted first
+    pub fn mounts_reverse(&self) -> impl Iterator<Item = &Path> {
+        reverse_paths(&self.mounts)
+    }
+
+    pub fn add_swap<P: AsRef<Path>>(&mut self, swap: P) {
+        self.swaps.push(swap.as_ref().to_path_buf())
+    }
+
+    pub fn swaps(&self) -> &Vec<PathBuf> {
+        &self.swaps
+    }
+
+    pub fn add_loop_device<P: AsRef<Path>>(&mut self, loop_device: P) {
+        self.loop_devices.push(loop_device.as_ref().to_path_buf())
+    }
+
+    pub fn remove_loop_device(&mut self, loop_device: &Path) {
+        self.loop_devices.retain(|device| device != loop_device)
+    }
+
+    pub fn loop_devices(&self) -> &Vec<PathBuf> {
+        &self.loop_devices
+    }
+
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    pub fn flash_to(&self) -> Option<&Path> {
+        if let Some(flash_to) = &self.flash_to {
+            Some(flash_to.as_path())
+        } else {
+            None
+        }
+    }
+
+    pub fn check_timeout(&self) -> u64 {
+        self.check_timeout
+    }
+
+    /// The root file system mount as found when takeover started
+    pub fn root_mount(&self) -> Option<&Mount> {
+        self.root_mount.as_ref()
+    }
+
+    pub fn set_event_handler(&mut self, handler: EventHandler) {
+        self.events = EventSink::with_handler(handler);
+    }
+
+    pub fn emit(&self, event: Stage1Event) {
+        self.events.emit(event)
+    }
+
     pub fn nwmgr_files(&self) -> &Vec<PathBuf> {
         &self.nwmgr_files
     }
@@ -236,17 +550,30 @@ impl MigrateInfo {
         &self.wifis
     }
 
-    pub fn umount_all(&mut self) {
-        while let Some(mountpoint) = self.mounts.pop() {
-            if let Err(why) = umount(&mountpoint) {
-                warn!(
-                    "Failed to unmount mountpoint: '{}', error : {:?}",
-                    mountpoint.display(),
-                    why
-                );
+    /// Unmount the recorded mounts, last mounted first, busy mounts are reported with the
+    /// processes using them and lazily unmounted if lazy_fallback is set. Returns the number of
+    /// mountpoints that could not be unmounted
+    pub fn umount_all(&mut self, lazy_fallback: bool) -> usize {
+        let mut failures: usize = 0;
+        for mountpoint in self.mounts_reverse() {
+            if self.dry_run {
+                info!("Dry run - would unmount '{}'", mountpoint.display());
+                continue;
+            }
+            match unmount(mountpoint, lazy_fallback) {
+                Ok(_) => info!("Unmounted '{}'", mountpoint.display()),
+                Err(why) => {
+                    warn!("{}", why);
+                    failures += 1;
+                }
             }
         }
 
+        self.mounts.clear();
+        failures
+    }
+
+    pub fn remove_to_dir(&self) {
         if let Some(takeover_dir) = &self.to_dir {
             if let Err(why) = remove_dir_all(takeover_dir) {
                 warn!(
@@ -258,6 +585,27 @@ impl MigrateInfo {
         }
     }
 
+    /// Read the config.json given with --config or the one embedded in the executable
+    pub fn load_config(opts: &Options, staging_dir: Option<&Path>) -> Result<BalenaCfgJson> {
+        if let Some(balena_cfg) = opts.config() {
+            BalenaCfgJson::new(balena_cfg)
+        } else {
+            let cfg_dir = staging_dir
+                .map(|staging_dir| staging_dir.to_path_buf())
+                .unwrap_or_else(|| opts.work_dir());
+            MigrateInfo::get_internal_cfg_json(&cfg_dir).map_err(|why| {
+                if why.kind() == ErrorKind::NotFound {
+                    Error::with_context(
+                        ErrorKind::NotFound,
+                        "The required parameter --config/-c was not provided and no internal config.json was found",
+                    )
+                } else {
+                    why
+                }
+            })
+        }
+    }
+
     fn get_internal_cfg_json(work_dir: &Path) -> Result<BalenaCfgJson> {
         const SIZE_LEN: usize = std::mem::size_of::<u32>();
         const COOKIE_LEN: usize = std::mem::size_of::<u16>();
@@ -333,3 +681,135 @@ impl MigrateInfo {
         }
     }
 }
+
+impl StepRecord for MigrateInfo {
+    fn completed_step(&self) -> Option<Stage1Step> {
+        self.completed_step
+    }
+
+    fn set_completed_step(&mut self, step: Stage1Step) {
+        self.completed_step = Some(step);
+        match step {
+            Stage1Step::StageImage => {
+                self.progress.image_path = self.image_path.clone();
+                self.progress.image_digest = self.image_digest.clone();
+            }
+            Stage1Step::CreateBackup => self.progress.backup = self.backup.clone(),
+            _ => (),
+        }
+        // a retry that has not caught up yet must not lose the progress of the earlier run
+        if step.is_idempotent() || self.progress.completed_step < Some(step) {
+            self.progress.completed_step = self.progress.completed_step.max(Some(step));
+            let progress_path = path_append(&self.work_dir, PROGRESS_FILE_NAME);
+            if let Err(why) = self.progress.save(&progress_path) {
+                warn!(
+                    "Failed to save progress, a retry will repeat all steps: {}",
+                    why
+                );
+            }
+        }
+    }
+
+    fn restore_step(&mut self, step: Stage1Step) -> bool {
+        if self.progress.completed_step < Some(step) {
+            return false;
+        }
+        match step {
+            Stage1Step::StageImage => {
+                match (&self.progress.image_path, &self.progress.image_digest) {
+                    (Some(image_path), Some(image_digest)) if file_exists(image_path) => {
+                        info!("Using the image staged earlier: '{}'", image_path.display());
+                        self.image_path = Some(image_path.clone());
+                        self.image_digest = Some(image_digest.clone());
+                        true
+                    }
+                    _ => false,
+                }
+            }
+            Stage1Step::CreateBackup => match &self.progress.backup {
+                Some(backup) if !file_exists(backup) => false,
+                backup => {
+                    if let Some(backup) = backup {
+                        info!("Using the backup created earlier: '{}'", backup.display());
+                    }
+                    self.backup = backup.clone();
+                    true
+                }
+            },
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_staging_dir, reverse_paths, Progress, ProgressKey};
+    use crate::common::ErrorKind;
+    use crate::stage1::steps::Stage1Step;
+    use std::fs::{create_dir_all, remove_dir_all};
+    use std::path::{Path, PathBuf};
+
+    fn test_key(version: &str) -> ProgressKey {
+        ProgressKey {
+            device_type: String::from("raspberrypi3"),
+            version: String::from(version),
+            image: None,
+            backup_config: None,
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_staging_dir() {
+        assert_eq!(
+            check_staging_dir(Path::new("/no/such/staging/dir"))
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvParam
+        );
+        assert_eq!(
+            check_staging_dir(Path::new("/proc/self/status"))
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvParam
+        );
+        let temp_dir = std::env::temp_dir();
+        assert_eq!(
+            check_staging_dir(&temp_dir).unwrap(),
+            temp_dir.canonicalize().unwrap()
+        );
+    }
+
+    #[test]
+    fn unmounts_nested_mounts_first() {
+        let mounts = vec![PathBuf::from("/a"), PathBuf::from("/a/b")];
+        let unmount_order: Vec<&Path> = reverse_paths(&mounts).collect();
+        assert_eq!(unmount_order, vec![Path::new("/a/b"), Path::new("/a")]);
+    }
+
+    #[test]
+    fn loads_progress_saved_for_same_options() {
+        let work_dir =
+            std::env::temp_dir().join(format!("takeover_progress_{}", std::process::id()));
+        create_dir_all(&work_dir).unwrap();
+        let progress_path = work_dir.join("progress.json");
+
+        let progress = Progress {
+            key: test_key("2.80.3"),
+            completed_step: Some(Stage1Step::StageImage),
+            image_path: Some(work_dir.join("balena.img.gz")),
+            image_digest: Some((String::from("b140168e"), 512)),
+            backup: None,
+        };
+        progress.save(&progress_path).unwrap();
+
+        let same = Progress::load(&progress_path, test_key("2.80.3"));
+        let other = Progress::load(&progress_path, test_key("2.85.0"));
+        let missing = Progress::load(&work_dir.join("missing.json"), test_key("2.80.3"));
+        remove_dir_all(&work_dir).unwrap();
+
+        assert_eq!(same, progress);
+        assert_eq!(other.completed_step, None);
+        assert_eq!(other.key, test_key("2.85.0"));
+        assert_eq!(missing.completed_step, None);
+    }
+}
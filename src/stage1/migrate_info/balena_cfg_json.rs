@@ -1,6 +1,11 @@
 use crate::{
     common::{Error, ErrorKind, Options, Result, ToError},
-    stage1::{device::Device, utils::check_tcp_connect},
+    stage1::{
+        api_calls::{check_https_reachable, API_PING_URL},
+        defs::is_arch_compatible,
+        device::Device,
+        utils::{check_tcp_connect, get_os_arch},
+    },
 };
 
 use log::{error, info};
@@ -12,6 +17,49 @@ use std::path::{Path, PathBuf};
 use url::Url;
 
 pub const BALENA_API_PORT: u16 = 80;
+const BALENA_REGISTRY_PORT: u16 = 443;
+
+const REQUIRED_KEYS: [&str; 3] = ["applicationId", "deviceType", "apiEndpoint"];
+// a preregistered device carries its own credentials instead of a provisioning key
+const REGISTERED_KEYS: [&str; 2] = ["uuid", "deviceApiKey"];
+const PROVISIONING_KEY: &str = "apiKey";
+const REGISTERED_AT_KEY: &str = "registered_at";
+
+/// Check config parses as JSON and contains the keys a device needs to register with the
+/// balena API, fails with ErrorKind::InvParam listing all missing keys
+pub(crate) fn validate_config_json(config: &str) -> Result<()> {
+    let config: HashMap<String, Value> = serde_json::from_str(config).map_err(|why| {
+        Error::with_all(
+            ErrorKind::InvParam,
+            "Failed to parse config.json",
+            Box::new(why),
+        )
+    })?;
+
+    let has_key = |key: &str| config.get(key).is_some_and(|value| !value.is_null());
+    let mut missing: Vec<&str> = REQUIRED_KEYS
+        .iter()
+        .copied()
+        .filter(|key| !has_key(key))
+        .collect();
+    if has_key(REGISTERED_AT_KEY) {
+        missing.extend(REGISTERED_KEYS.iter().copied().filter(|key| !has_key(key)));
+    } else if !has_key(PROVISIONING_KEY) {
+        missing.push(PROVISIONING_KEY);
+    }
+
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!(
+                "config.json is missing required keys: {}",
+                missing.join(", ")
+            ),
+        ))
+    }
+}
 
 #[derive(Debug, Clone)]
 pub(crate) struct BalenaCfgJson {
@@ -81,6 +129,17 @@ impl BalenaCfgJson {
             return Err(Error::displayed());
         }
 
+        let os_arch = get_os_arch()?;
+        if !is_arch_compatible(&os_arch, device_type.as_str()) {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "The devicetype configured in config.json ({}) is not compatible with the detected OS architecture {:?}",
+                    device_type, os_arch
+                ),
+            ));
+        }
+
         if opts.api_check() {
             let api_endpoint = &self.get_api_endpoint()?;
 
@@ -108,6 +167,18 @@ impl BalenaCfgJson {
                     ),
                     ));
                 }
+
+                let ping_url = format!("{}{}", api_endpoint.trim_end_matches('/'), API_PING_URL);
+                if let Err(why) = check_https_reachable(&ping_url, opts.check_timeout()) {
+                    return Err(Error::from_upstream(
+                        Box::new(why),
+                        &format!(
+                            "failed to reach api server @ {} your device might not come online",
+                            ping_url
+                        ),
+                    ));
+                }
+                info!("api server @ {} is reachable", ping_url);
             } else {
                 return Err(Error::with_context(
                     ErrorKind::InvParam,
@@ -227,6 +298,19 @@ impl BalenaCfgJson {
         self.get_uint_val("vpnPort")
     }
 
+    /// Get the endpoints besides the API a device connects to once it runs balenaOS, the vpn
+    /// and the container registry, endpoints that are not configured are left out
+    pub fn get_service_endpoints(&self) -> Vec<(String, u16)> {
+        let mut endpoints = Vec::new();
+        if let (Ok(vpn_endpoint), Ok(vpn_port)) = (self.get_vpn_endpoint(), self.get_vpn_port()) {
+            endpoints.push((vpn_endpoint, vpn_port as u16));
+        }
+        if let Ok(registry_endpoint) = self.get_str_val("registryEndpoint") {
+            endpoints.push((registry_endpoint, BALENA_REGISTRY_PORT));
+        }
+        endpoints
+    }
+
     pub fn get_device_type(&self) -> Result<String> {
         self.get_str_val("deviceType")
     }
@@ -235,3 +319,60 @@ impl BalenaCfgJson {
         &self.file
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{validate_config_json, BalenaCfgJson};
+    use crate::common::ErrorKind;
+
+    #[test]
+    fn collects_service_endpoints() {
+        let path = std::env::temp_dir().join(format!("takeover_cfg_ep_{}", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{"vpnEndpoint":"vpn.balena-cloud.com","vpnPort":443,
+            "registryEndpoint":"registry2.balena-cloud.com"}"#,
+        )
+        .unwrap();
+        let endpoints = BalenaCfgJson::new(&path).map(|cfg| cfg.get_service_endpoints());
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            endpoints.unwrap(),
+            vec![
+                (String::from("vpn.balena-cloud.com"), 443),
+                (String::from("registry2.balena-cloud.com"), 443)
+            ]
+        );
+    }
+
+    #[test]
+    fn validates_required_keys() {
+        const PROVISIONING: &str = r#"{"applicationId":1234,"deviceType":"raspberrypi3",
+            "apiEndpoint":"https://api.balena-cloud.com","apiKey":"secret"}"#;
+        const REGISTERED: &str = r#"{"applicationId":1234,"deviceType":"raspberrypi3",
+            "apiEndpoint":"https://api.balena-cloud.com","registered_at":1600000000,
+            "uuid":"abcdef","deviceApiKey":"secret"}"#;
+        assert!(validate_config_json(PROVISIONING).is_ok());
+        assert!(validate_config_json(REGISTERED).is_ok());
+
+        let err = validate_config_json(r#"{"deviceType":"raspberrypi3","apiEndpoint":null}"#)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvParam);
+        assert!(err
+            .to_string()
+            .contains("applicationId, apiEndpoint, apiKey"));
+
+        let err = validate_config_json(
+            r#"{"applicationId":1,"deviceType":"x",
+            "apiEndpoint":"https://api","registered_at":1}"#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("uuid, deviceApiKey"));
+
+        assert_eq!(
+            validate_config_json("{").unwrap_err().kind(),
+            ErrorKind::InvParam
+        );
+    }
+}
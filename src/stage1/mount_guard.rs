@@ -0,0 +1,116 @@
+use std::path::{Path, PathBuf};
+
+use log::{debug, warn};
+
+use crate::common::Result;
+use crate::stage1::utils::unmount;
+
+type UnmountFn = fn(&Path) -> Result<()>;
+
+fn default_unmount(mountpoint: &Path) -> Result<()> {
+    unmount(mountpoint, true)
+}
+
+/// Unmounts the recorded mountpoints in reverse order when dropped unless it has been disarmed
+pub(crate) struct MountGuard {
+    mounts: Vec<PathBuf>,
+    armed: bool,
+    unmount_fn: UnmountFn,
+}
+
+impl MountGuard {
+    pub fn new() -> MountGuard {
+        MountGuard::with_unmount_fn(default_unmount)
+    }
+
+    fn with_unmount_fn(unmount_fn: UnmountFn) -> MountGuard {
+        MountGuard {
+            mounts: Vec::new(),
+            armed: true,
+            unmount_fn,
+        }
+    }
+
+    pub fn add<P: AsRef<Path>>(&mut self, mountpoint: P) {
+        self.mounts.push(mountpoint.as_ref().to_path_buf());
+    }
+
+    #[allow(dead_code)]
+    pub fn mounts(&self) -> &[PathBuf] {
+        self.mounts.as_slice()
+    }
+
+    /// Keep the mounts, eg. when they are meant to persist into stage2
+    #[allow(dead_code)]
+    pub fn disarm(&mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for MountGuard {
+    fn drop(&mut self) {
+        if !self.armed {
+            debug!("MountGuard: disarmed, keeping {} mounts", self.mounts.len());
+            return;
+        }
+
+        while let Some(mountpoint) = self.mounts.pop() {
+            debug!("MountGuard: unmounting '{}'", mountpoint.display());
+            if let Err(why) = (self.unmount_fn)(&mountpoint) {
+                warn!("MountGuard: {}", why);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MountGuard;
+    use crate::common::{Error, ErrorKind, Result};
+    use std::cell::RefCell;
+    use std::path::{Path, PathBuf};
+
+    thread_local! {
+        static UNMOUNTED: RefCell<Vec<PathBuf>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn record_unmount(mountpoint: &Path) -> Result<()> {
+        UNMOUNTED.with(|unmounted| unmounted.borrow_mut().push(mountpoint.to_path_buf()));
+        Ok(())
+    }
+
+    fn mount_and_fail(guard: &mut MountGuard) -> Result<()> {
+        guard.add("/tmp/guard_test");
+        guard.add("/tmp/guard_test/nested");
+        Err(Error::new(ErrorKind::InvState))
+    }
+
+    #[test]
+    fn unmounts_on_early_return() {
+        UNMOUNTED.with(|unmounted| unmounted.borrow_mut().clear());
+        {
+            let mut guard = MountGuard::with_unmount_fn(record_unmount);
+            assert!(mount_and_fail(&mut guard).is_err());
+        }
+        UNMOUNTED.with(|unmounted| {
+            assert_eq!(
+                *unmounted.borrow(),
+                vec![
+                    PathBuf::from("/tmp/guard_test/nested"),
+                    PathBuf::from("/tmp/guard_test")
+                ]
+            )
+        });
+    }
+
+    #[test]
+    fn keeps_mounts_when_disarmed() {
+        UNMOUNTED.with(|unmounted| unmounted.borrow_mut().clear());
+        {
+            let mut guard = MountGuard::with_unmount_fn(record_unmount);
+            guard.add("/tmp/guard_test");
+            guard.disarm();
+        }
+        UNMOUNTED.with(|unmounted| assert!(unmounted.borrow().is_empty()));
+    }
+}
@@ -0,0 +1,705 @@
+use std::fmt::{self, Display, Formatter};
+use std::fs::{metadata, read_dir, read_to_string, symlink_metadata};
+use std::path::{Path, PathBuf};
+
+use log::{error, info, trace, warn};
+use url::Url;
+
+use crate::{
+    common::{
+        available_memory, available_space, format_size_with_unit, image_writer::image_digest,
+        options::Options, Result, ToError,
+    },
+    stage1::{
+        api_calls::{check_https_reachable, API_PING_URL},
+        block_device_info::{
+            check_flash_target, get_device_size, is_write_protected, BlockDeviceInfo,
+        },
+        copy_commands,
+        defs::{is_arch_compatible, OSArch},
+        exe_copy::ExeCopy,
+        migrate_info::{
+            balena_cfg_json::{validate_config_json, BalenaCfgJson, BALENA_API_PORT},
+            check_staging_dir, MigrateInfo,
+        },
+        utils::{
+            check_endpoints, get_os_arch, get_secure_boot_state, is_already_balena, SecureBootState,
+        },
+    },
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl Display for CheckStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let output = match *self {
+            Self::Pass => "PASS",
+            Self::Warn => "WARN",
+            Self::Fail => "FAIL",
+        };
+        write!(f, "{}", output)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct CheckResult {
+    name: &'static str,
+    status: CheckStatus,
+    message: String,
+}
+
+#[allow(dead_code)]
+impl CheckResult {
+    pub fn get_name(&self) -> &str {
+        self.name
+    }
+
+    pub fn get_status(&self) -> CheckStatus {
+        self.status
+    }
+
+    pub fn get_message(&self) -> &str {
+        self.message.as_str()
+    }
+}
+
+/// The results of all preflight checks
+#[derive(Debug, Default)]
+pub(crate) struct PreflightReport {
+    checks: Vec<CheckResult>,
+}
+
+#[allow(dead_code)]
+impl PreflightReport {
+    fn add(&mut self, name: &'static str, status: CheckStatus, message: String) {
+        self.checks.push(CheckResult {
+            name,
+            status,
+            message,
+        });
+    }
+
+    pub fn get_checks(&self) -> &[CheckResult] {
+        self.checks.as_slice()
+    }
+
+    pub fn has_failures(&self) -> bool {
+        self.checks
+            .iter()
+            .any(|check| check.status == CheckStatus::Fail)
+    }
+
+    pub fn has_warnings(&self) -> bool {
+        self.checks
+            .iter()
+            .any(|check| check.status == CheckStatus::Warn)
+    }
+
+    pub fn log(&self) {
+        for check in &self.checks {
+            match check.status {
+                CheckStatus::Pass => info!("[{}] {}: {}", check.status, check.name, check.message),
+                CheckStatus::Warn => warn!("[{}] {}: {}", check.status, check.name, check.message),
+                CheckStatus::Fail => error!("[{}] {}: {}", check.status, check.name, check.message),
+            }
+        }
+    }
+}
+
+/// What the checks need to know about the migration. It is gathered from the options only, so
+/// preflight runs before the image is downloaded or backups are created
+struct PreflightContext {
+    config: Option<BalenaCfgJson>,
+    image_path: Option<PathBuf>,
+    flash_to: Option<PathBuf>,
+    staging_dir: Option<PathBuf>,
+    check_timeout: u64,
+}
+
+impl PreflightContext {
+    fn new(opts: &Options, report: &mut PreflightReport) -> PreflightContext {
+        let staging_dir = match opts.staging_dir() {
+            Some(staging_dir) => match check_staging_dir(staging_dir) {
+                Ok(staging_dir) => Some(staging_dir),
+                Err(why) => {
+                    report.add("staging space", CheckStatus::Fail, why.to_string());
+                    None
+                }
+            },
+            None => Some(opts.work_dir()),
+        };
+
+        let config = match MigrateInfo::load_config(opts, staging_dir.as_deref()) {
+            Ok(config) => Some(config),
+            Err(why) => {
+                report.add("config.json", CheckStatus::Fail, why.to_string());
+                None
+            }
+        };
+
+        PreflightContext {
+            config,
+            image_path: opts.image().clone(),
+            flash_to: opts.flash_to().clone(),
+            staging_dir,
+            check_timeout: opts.check_timeout(),
+        }
+    }
+}
+
+// extra space for config files, network configurations and file system overhead
+const REQUIREMENTS_MARGIN: u64 = 64 * 1024 * 1024;
+
+/// The resources a migration needs
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Requirements {
+    staging_space: u64,
+    memory: u64,
+    target_disk: u64,
+}
+
+#[allow(dead_code)]
+impl Requirements {
+    /// Space needed to stage the image and preserved files on disk
+    pub fn get_staging_space(&self) -> u64 {
+        self.staging_space
+    }
+
+    /// Memory needed to stage the image, preserved files and copied commands in a tmpfs
+    pub fn get_memory(&self) -> u64 {
+        self.memory
+    }
+
+    /// The minimum size of the disk to flash
+    pub fn get_target_disk(&self) -> u64 {
+        self.target_disk
+    }
+}
+
+/// Sum up the size of path and all files below it, symlinks are not followed
+fn path_size(path: &Path) -> Result<u64> {
+    let path_meta = symlink_metadata(path)
+        .upstream_with_context(&format!("Failed to read metadata of '{}'", path.display()))?;
+    if !path_meta.is_dir() {
+        return Ok(path_meta.len());
+    }
+
+    let mut size = 0;
+    for entry in read_dir(path)
+        .upstream_with_context(&format!("Failed to read directory '{}'", path.display()))?
+    {
+        let entry = entry.upstream_with_context(&format!(
+            "Failed to read directory entry in '{}'",
+            path.display()
+        ))?;
+        size += path_size(&entry.path())?;
+    }
+    Ok(size)
+}
+
+/// Estimate the resources needed to migrate with a compressed image of img_size bytes that
+/// decompresses to disk_image_size bytes, preserving the files in preserve. commands_size is
+/// the space the commands copied to the takeover tmpfs take. Missing files in preserve are
+/// skipped like stage_preserve_files does.
+pub(crate) fn estimate_requirements(
+    img_size: u64,
+    disk_image_size: u64,
+    preserve: &[PathBuf],
+    commands_size: u64,
+) -> Result<Requirements> {
+    trace!(
+        "estimate_requirements: entered with {}, {}, {:?}, {}",
+        img_size,
+        disk_image_size,
+        preserve,
+        commands_size
+    );
+    let mut preserve_size = 0;
+    for path in preserve {
+        if symlink_metadata(path).is_ok() {
+            preserve_size += path_size(path)?;
+        }
+    }
+
+    // the compressed image is staged, the decompressed image is flashed
+    let staging_space = img_size + preserve_size + REQUIREMENTS_MARGIN;
+    Ok(Requirements {
+        staging_space,
+        memory: staging_space + commands_size,
+        target_disk: disk_image_size,
+    })
+}
+
+fn check_arch(config: &BalenaCfgJson, report: &mut PreflightReport) {
+    match get_os_arch() {
+        Ok(os_arch) => match config.get_device_type() {
+            Ok(device_type) => {
+                if is_arch_compatible(&os_arch, device_type.as_str()) {
+                    report.add(
+                        "architecture",
+                        CheckStatus::Pass,
+                        format!(
+                            "OS architecture {:?} is compatible with device type {}",
+                            os_arch, device_type
+                        ),
+                    );
+                } else {
+                    report.add(
+                        "architecture",
+                        CheckStatus::Fail,
+                        format!(
+                            "OS architecture {:?} is not compatible with device type {}",
+                            os_arch, device_type
+                        ),
+                    );
+                }
+            }
+            Err(why) => report.add("architecture", CheckStatus::Fail, why.to_string()),
+        },
+        Err(why) => report.add("architecture", CheckStatus::Fail, why.to_string()),
+    }
+}
+
+/// Check the device does not run balenaOS already, returns false if it does and there is no
+/// point in running the remaining checks
+fn check_not_balena(report: &mut PreflightReport) -> bool {
+    match is_already_balena() {
+        Ok(true) => {
+            report.add(
+                "balenaOS",
+                CheckStatus::Fail,
+                String::from("This device already runs balenaOS, there is nothing to migrate"),
+            );
+            false
+        }
+        Ok(false) => true,
+        Err(why) => {
+            report.add("balenaOS", CheckStatus::Warn, why.to_string());
+            true
+        }
+    }
+}
+
+fn check_config(config: &BalenaCfgJson, report: &mut PreflightReport) {
+    let config_path = config.get_path();
+    match read_to_string(config_path) {
+        Ok(config) => match validate_config_json(&config) {
+            Ok(_) => report.add(
+                "config.json",
+                CheckStatus::Pass,
+                format!("'{}' contains all required keys", config_path.display()),
+            ),
+            Err(why) => report.add("config.json", CheckStatus::Fail, why.to_string()),
+        },
+        Err(why) => report.add(
+            "config.json",
+            CheckStatus::Fail,
+            format!("Failed to read '{}', error: {}", config_path.display(), why),
+        ),
+    }
+}
+
+fn check_secure_boot(report: &mut PreflightReport) {
+    match get_secure_boot_state() {
+        Ok(SecureBootState::Enabled) => report.add(
+            "secure boot",
+            CheckStatus::Fail,
+            String::from("Secure boot is enabled"),
+        ),
+        Ok(SecureBootState::SetupMode) => report.add(
+            "secure boot",
+            CheckStatus::Warn,
+            String::from("Secure boot is in setup mode"),
+        ),
+        Ok(_) => report.add(
+            "secure boot",
+            CheckStatus::Pass,
+            String::from("Secure boot is not enabled"),
+        ),
+        Err(why) => report.add(
+            "secure boot",
+            CheckStatus::Warn,
+            format!("Failed to determine secure boot state: {}", why),
+        ),
+    }
+}
+
+/// Resolve the root device, returns the device to flash to if it could be determined
+fn check_root_device(
+    context: &PreflightContext,
+    allow_self: bool,
+    report: &mut PreflightReport,
+) -> Option<PathBuf> {
+    match BlockDeviceInfo::new() {
+        Ok(block_dev_info) => {
+            let root_dev = block_dev_info.get_root_device().get_dev_path();
+            report.add(
+                "root device",
+                CheckStatus::Pass,
+                format!("Root device is '{}'", root_dev.display()),
+            );
+            if let Some(flash_dev) = &context.flash_to {
+                if let Some(flash_dev) = block_dev_info.get_devices().get(flash_dev) {
+                    let flash_dev = flash_dev.get_dev_path();
+                    if let Err(why) = check_flash_target(&flash_dev, &root_dev, allow_self) {
+                        report.add("target disk", CheckStatus::Fail, why.to_string());
+                        None
+                    } else {
+                        Some(flash_dev)
+                    }
+                } else {
+                    report.add(
+                        "target disk",
+                        CheckStatus::Fail,
+                        format!(
+                            "Could not find configured flash device '{}'",
+                            flash_dev.display()
+                        ),
+                    );
+                    None
+                }
+            } else {
+                Some(root_dev)
+            }
+        }
+        Err(why) => {
+            report.add("root device", CheckStatus::Fail, why.to_string());
+            None
+        }
+    }
+}
+
+/// Estimate the requirements of the migration, reports a failure if the image can not be read.
+/// The image is decompressed once to determine the size it takes on the target disk. Without
+/// an image given in the options nothing is downloaded and the requirements are not checked.
+fn check_requirements(
+    opts: &Options,
+    context: &PreflightContext,
+    report: &mut PreflightReport,
+) -> Option<Requirements> {
+    let image_path = if let Some(image_path) = &context.image_path {
+        image_path
+    } else {
+        report.add(
+            "requirements",
+            CheckStatus::Warn,
+            String::from("No image was given with --image, the space and memory requirements are checked once the image has been downloaded"),
+        );
+        return None;
+    };
+
+    let res = metadata(image_path)
+        .upstream_with_context(&format!(
+            "Failed to read image size of '{}'",
+            image_path.display()
+        ))
+        .and_then(|image_meta| {
+            let (_digest, disk_image_size) = image_digest(image_path)?;
+            let is_x86 = matches!(get_os_arch()?, OSArch::AMD64);
+            let commands = ExeCopy::new(copy_commands(opts, is_x86)?)?;
+            estimate_requirements(
+                image_meta.len(),
+                disk_image_size,
+                opts.preserve(),
+                commands.get_req_space(),
+            )
+        });
+    match res {
+        Ok(requirements) => Some(requirements),
+        Err(why) => {
+            report.add("requirements", CheckStatus::Fail, why.to_string());
+            None
+        }
+    }
+}
+
+/// Check the image and preserved files fit into the memory stage2 stages them in
+fn check_memory(requirements: &Requirements, report: &mut PreflightReport) {
+    match available_memory() {
+        Ok(mem_available) => {
+            let status = if mem_available >= requirements.memory {
+                CheckStatus::Pass
+            } else {
+                CheckStatus::Fail
+            };
+            report.add(
+                "memory",
+                status,
+                format!(
+                    "{} of memory required, {} available",
+                    format_size_with_unit(requirements.memory),
+                    format_size_with_unit(mem_available)
+                ),
+            );
+        }
+        Err(why) => report.add("memory", CheckStatus::Fail, why.to_string()),
+    }
+}
+
+/// Check the staging directory can hold the image and preserved files
+fn check_staging_space(
+    staging_dir: &Path,
+    requirements: &Requirements,
+    report: &mut PreflightReport,
+) {
+    match available_space(staging_dir) {
+        Ok(space) => {
+            let status = if space >= requirements.staging_space {
+                CheckStatus::Pass
+            } else {
+                CheckStatus::Warn
+            };
+            report.add(
+                "staging space",
+                status,
+                format!(
+                    "{} required in '{}' for on disk staging, {} available",
+                    format_size_with_unit(requirements.staging_space),
+                    staging_dir.display(),
+                    format_size_with_unit(space)
+                ),
+            );
+        }
+        Err(why) => report.add("staging space", CheckStatus::Warn, why.to_string()),
+    }
+}
+
+/// Check the target disk can be written, a write protected disk can not be flashed
+fn check_write_protected(flash_dev: &Path, report: &mut PreflightReport) {
+    match is_write_protected(flash_dev) {
+        Ok(false) => report.add(
+            "target disk",
+            CheckStatus::Pass,
+            format!("'{}' is writable", flash_dev.display()),
+        ),
+        Ok(true) => report.add(
+            "target disk",
+            CheckStatus::Fail,
+            format!(
+                "'{}' is write protected, check for a lock switch or worn out media",
+                flash_dev.display()
+            ),
+        ),
+        Err(why) => report.add("target disk", CheckStatus::Fail, why.to_string()),
+    }
+}
+
+/// Check the target disk meets the minimum size of the requirements
+fn check_disk_size(requirements: &Requirements, flash_dev: &Path, report: &mut PreflightReport) {
+    let image_size = requirements.target_disk;
+
+    match get_device_size(flash_dev) {
+        Ok(dev_size) => {
+            if dev_size >= image_size {
+                report.add(
+                    "target disk",
+                    CheckStatus::Pass,
+                    format!(
+                        "'{}' has a size of {}, image size is {}",
+                        flash_dev.display(),
+                        format_size_with_unit(dev_size),
+                        format_size_with_unit(image_size)
+                    ),
+                );
+            } else {
+                report.add(
+                    "target disk",
+                    CheckStatus::Fail,
+                    format!(
+                        "'{}' has a size of {} which is too small for an image of {}",
+                        flash_dev.display(),
+                        format_size_with_unit(dev_size),
+                        format_size_with_unit(image_size)
+                    ),
+                );
+            }
+        }
+        Err(why) => report.add("target disk", CheckStatus::Fail, why.to_string()),
+    }
+}
+
+fn check_network(config: &BalenaCfgJson, check_timeout: u64, report: &mut PreflightReport) {
+    let api_endpoint = match config.get_api_endpoint() {
+        Ok(api_endpoint) => api_endpoint,
+        Err(why) => {
+            report.add("network", CheckStatus::Fail, why.to_string());
+            return;
+        }
+    };
+
+    let api_url = match Url::parse(&api_endpoint) {
+        Ok(api_url) => api_url,
+        Err(why) => {
+            report.add(
+                "network",
+                CheckStatus::Fail,
+                format!(
+                    "Failed to parse balena api url '{}', error: {}",
+                    api_endpoint, why
+                ),
+            );
+            return;
+        }
+    };
+
+    let api_host = if let Some(api_host) = api_url.host() {
+        api_host.to_string()
+    } else {
+        report.add(
+            "network",
+            CheckStatus::Fail,
+            format!("Failed to parse api server url '{}'", api_endpoint),
+        );
+        return;
+    };
+    let api_port = api_url.port().unwrap_or(BALENA_API_PORT);
+
+    let mut endpoints = vec![(api_host, api_port)];
+    endpoints.extend(config.get_service_endpoints());
+    let results = match check_endpoints(&endpoints, check_timeout) {
+        Ok(results) => results,
+        Err(why) => {
+            report.add("network", CheckStatus::Fail, why.to_string());
+            return;
+        }
+    };
+
+    // the api is checked first, without a connection it can not be pinged
+    let mut api_reachable = true;
+    for (idx, (host, port, res)) in results.into_iter().enumerate() {
+        match res {
+            Ok(_) => report.add(
+                "network",
+                CheckStatus::Pass,
+                format!("balena endpoint @ {}:{} is reachable", host, port),
+            ),
+            Err(why) => {
+                if idx == 0 {
+                    api_reachable = false;
+                }
+                report.add(
+                    "network",
+                    CheckStatus::Fail,
+                    format!(
+                        "Failed to connect to balena endpoint @ {}:{}, error: {}",
+                        host, port, why
+                    ),
+                )
+            }
+        }
+    }
+    if !api_reachable {
+        return;
+    }
+
+    let ping_url = format!("{}{}", api_endpoint.trim_end_matches('/'), API_PING_URL);
+    match check_https_reachable(&ping_url, check_timeout) {
+        Ok(_) => report.add(
+            "network",
+            CheckStatus::Pass,
+            format!("api server @ {} is reachable", ping_url),
+        ),
+        Err(why) => report.add(
+            "network",
+            CheckStatus::Fail,
+            format!("Failed to reach api server @ {}, error: {}", ping_url, why),
+        ),
+    }
+}
+
+/// Run all stage1 validations without modifying the system and report the results of every
+/// check instead of aborting on the first failure. Nothing is downloaded or backed up, the
+/// checks only use what is given in the options
+pub(crate) fn preflight(opts: &Options) -> Result<PreflightReport> {
+    trace!("preflight: entered");
+
+    let mut report = PreflightReport::default();
+
+    if !check_not_balena(&mut report) {
+        return Ok(report);
+    }
+    let context = PreflightContext::new(opts, &mut report);
+    if let Some(config) = &context.config {
+        check_config(config, &mut report);
+        check_arch(config, &mut report);
+    }
+    check_secure_boot(&mut report);
+    let flash_dev = check_root_device(&context, opts.allow_self(), &mut report);
+    if let Some(flash_dev) = &flash_dev {
+        check_write_protected(flash_dev, &mut report);
+    }
+    if let Some(requirements) = check_requirements(opts, &context, &mut report) {
+        check_memory(&requirements, &mut report);
+        if let Some(staging_dir) = &context.staging_dir {
+            check_staging_space(staging_dir, &requirements, &mut report);
+        }
+        if let Some(flash_dev) = flash_dev {
+            check_disk_size(&requirements, &flash_dev, &mut report);
+        }
+    }
+    if let Some(config) = &context.config {
+        check_network(config, context.check_timeout, &mut report);
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_requirements, estimate_requirements, CheckStatus, PreflightContext, PreflightReport,
+        REQUIREMENTS_MARGIN,
+    };
+    use crate::common::options::Options;
+    use std::path::PathBuf;
+    use structopt::StructOpt;
+
+    #[test]
+    fn estimates_image_and_preserved_files() {
+        let dir = std::env::temp_dir().join(format!("takeover_req_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("a"), vec![0u8; 1000]).unwrap();
+        std::fs::write(dir.join("sub/b"), vec![0u8; 24]).unwrap();
+
+        let res = estimate_requirements(
+            4096,
+            16384,
+            &[dir.clone(), PathBuf::from("/no/such/preserved/file")],
+            2048,
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let requirements = res.unwrap();
+        assert_eq!(
+            requirements.get_staging_space(),
+            4096 + 1024 + REQUIREMENTS_MARGIN
+        );
+        assert_eq!(
+            requirements.get_memory(),
+            requirements.get_staging_space() + 2048
+        );
+        assert_eq!(requirements.get_target_disk(), 16384);
+    }
+
+    #[test]
+    fn skips_requirements_without_image() {
+        let opts = Options::from_iter(vec!["takeover", "--preflight"]);
+        let context = PreflightContext {
+            config: None,
+            image_path: None,
+            flash_to: None,
+            staging_dir: None,
+            check_timeout: opts.check_timeout(),
+        };
+        let mut report = PreflightReport::default();
+        assert!(check_requirements(&opts, &context, &mut report).is_none());
+        assert_eq!(report.get_checks().len(), 1);
+        assert_eq!(report.get_checks()[0].get_status(), CheckStatus::Warn);
+        assert!(!report.has_failures());
+    }
+}
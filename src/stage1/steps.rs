@@ -0,0 +1,208 @@
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use crate::common::Result;
+
+/// The steps stage1 executes in this order to prepare the takeover
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub(crate) enum Stage1Step {
+    DetectDevice,
+    CheckSecureBoot,
+    ResolveRoot,
+    StageImage,
+    ScanNetworks,
+    CreateBackup,
+    SwapOff,
+    CheckMemory,
+    CreateTakeoverDir,
+    MountFilesystems,
+    CopyFiles,
+    StageConfigs,
+    WriteStage2Config,
+    ReplaceInit,
+}
+
+impl Stage1Step {
+    /// The steps that inspect the system and stage the image, they do not modify the running
+    /// system and need no rollback
+    pub const SETUP: [Stage1Step; 6] = [
+        Stage1Step::DetectDevice,
+        Stage1Step::CheckSecureBoot,
+        Stage1Step::ResolveRoot,
+        Stage1Step::StageImage,
+        Stage1Step::ScanNetworks,
+        Stage1Step::CreateBackup,
+    ];
+
+    /// The setup steps needed to only download the image
+    pub const DOWNLOAD: [Stage1Step; 4] = [
+        Stage1Step::DetectDevice,
+        Stage1Step::CheckSecureBoot,
+        Stage1Step::ResolveRoot,
+        Stage1Step::StageImage,
+    ];
+
+    /// The steps that set up the takeover directory and hand over to stage2
+    pub const PREPARE: [Stage1Step; 8] = [
+        Stage1Step::SwapOff,
+        Stage1Step::CheckMemory,
+        Stage1Step::CreateTakeoverDir,
+        Stage1Step::MountFilesystems,
+        Stage1Step::CopyFiles,
+        Stage1Step::StageConfigs,
+        Stage1Step::WriteStage2Config,
+        Stage1Step::ReplaceInit,
+    ];
+
+    /// Steps whose results are kept on disk, running them again gives the same result. Once
+    /// completed they are skipped on retry if their results can be restored
+    pub fn is_idempotent(self) -> bool {
+        matches!(self, Stage1Step::StageImage | Stage1Step::CreateBackup)
+    }
+}
+
+/// Keeps track of the last completed step
+pub(crate) trait StepRecord {
+    fn completed_step(&self) -> Option<Stage1Step>;
+    fn set_completed_step(&mut self, step: Stage1Step);
+    /// Restore the results of step if an earlier run completed it, returns false if the step
+    /// was not completed or its results are not available any more
+    fn restore_step(&mut self, step: Stage1Step) -> bool;
+}
+
+/// Execute steps in order by calling run_step, recording each completed step in state.
+/// Idempotent steps an earlier run completed are skipped if state can restore their results,
+/// so a retry after a failure does not repeat them. Execution stops at the first failing step.
+pub(crate) fn run_steps<T: StepRecord, F: FnMut(Stage1Step, &mut T) -> Result<()>>(
+    steps: &[Stage1Step],
+    state: &mut T,
+    mut run_step: F,
+) -> Result<()> {
+    for step in steps.iter().copied() {
+        if step.is_idempotent() && state.restore_step(step) {
+            info!("Skipping completed step {:?}", step);
+        } else {
+            debug!("run_steps: executing step {:?}", step);
+            run_step(step, state)?;
+        }
+        state.set_completed_step(step);
+    }
+    debug!("run_steps: completed {:?}", steps.last());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{Error, ErrorKind};
+
+    #[derive(Default)]
+    struct Record {
+        completed: Option<Stage1Step>,
+        executed: Vec<Stage1Step>,
+        // the last step completed by an earlier run with its results still available
+        resume: Option<Stage1Step>,
+    }
+
+    impl StepRecord for Record {
+        fn completed_step(&self) -> Option<Stage1Step> {
+            self.completed
+        }
+
+        fn set_completed_step(&mut self, step: Stage1Step) {
+            self.completed = Some(step);
+        }
+
+        fn restore_step(&mut self, step: Stage1Step) -> bool {
+            Some(step) <= self.resume
+        }
+    }
+
+    #[test]
+    fn stops_at_failed_step() {
+        let mut record = Record::default();
+        let res = run_steps(&Stage1Step::PREPARE, &mut record, |step, record| {
+            record.executed.push(step);
+            if step == Stage1Step::CopyFiles {
+                Err(Error::with_context(ErrorKind::InvState, "copy failed"))
+            } else {
+                Ok(())
+            }
+        });
+        assert!(res.is_err());
+        assert_eq!(
+            record.executed,
+            vec![
+                Stage1Step::SwapOff,
+                Stage1Step::CheckMemory,
+                Stage1Step::CreateTakeoverDir,
+                Stage1Step::MountFilesystems,
+                Stage1Step::CopyFiles,
+            ]
+        );
+        assert_eq!(record.completed, Some(Stage1Step::MountFilesystems));
+    }
+
+    #[test]
+    fn runs_all_steps() {
+        let mut record = Record::default();
+        run_steps(&Stage1Step::PREPARE, &mut record, |step, record| {
+            record.executed.push(step);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(record.executed, Stage1Step::PREPARE.to_vec());
+        assert_eq!(record.completed, Some(Stage1Step::ReplaceInit));
+    }
+
+    #[test]
+    fn resumes_after_failed_step() {
+        let mut record = Record::default();
+        let res = run_steps(&Stage1Step::SETUP, &mut record, |step, record| {
+            record.executed.push(step);
+            if step == Stage1Step::ScanNetworks {
+                Err(Error::with_context(ErrorKind::InvState, "scan failed"))
+            } else {
+                Ok(())
+            }
+        });
+        assert!(res.is_err());
+        assert_eq!(record.completed, Some(Stage1Step::StageImage));
+
+        // a retry recreates the record from the saved progress
+        let mut record = Record {
+            resume: record.completed,
+            ..Record::default()
+        };
+        run_steps(&Stage1Step::SETUP, &mut record, |step, record| {
+            record.executed.push(step);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            record.executed,
+            vec![
+                Stage1Step::DetectDevice,
+                Stage1Step::CheckSecureBoot,
+                Stage1Step::ResolveRoot,
+                Stage1Step::ScanNetworks,
+                Stage1Step::CreateBackup,
+            ]
+        );
+        assert_eq!(record.completed, Some(Stage1Step::CreateBackup));
+    }
+
+    #[test]
+    fn repeats_steps_that_are_not_idempotent() {
+        let mut record = Record {
+            resume: Some(Stage1Step::MountFilesystems),
+            ..Record::default()
+        };
+        run_steps(&Stage1Step::PREPARE, &mut record, |step, record| {
+            record.executed.push(step);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(record.executed, Stage1Step::PREPARE.to_vec());
+    }
+}
@@ -1,122 +1,595 @@
-use libc::S_IFREG;
-use log::info;
-use nix::mount::{mount, MsFlags};
+use lazy_static::lazy_static;
+use log::{info, LevelFilter};
+use mod_logger::{LogDestination, Logger, NO_STREAM};
+use nix::errno::Errno;
+use nix::mount::{mount, umount, umount2, MntFlags, MsFlags};
+use nix::sys::statfs::{statfs, TMPFS_MAGIC};
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 use std::cmp::min;
 use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::path::{Path, PathBuf};
 
 use crate::{
     common::{
-        call,
-        defs::{MOKUTIL_CMD, NIX_NONE, SYS_EFI_DIR},
-        dir_exists,
-        system::{mkdir, mknod, uname},
-        whereis, Error, ErrorKind, Result, ToError,
+        available_memory, available_space, call, call_timeout,
+        defs::{
+            DEFAULT_CALL_TIMEOUT, EFI_SECURE_BOOT_VAR, EFI_SETUP_MODE_VAR, MODPROBE_CMD,
+            MOKUTIL_CMD, NIX_NONE, OPENRC_BIN, PROC_INIT_CMDLINE, PROC_INIT_COMM, SWAPOFF_CMD,
+            SWAPON_CMD, SYSTEMD_RUN_DIR, SYS_EFIVARS_DIR, SYS_EFI_DIR,
+        },
+        dir_exists, file_exists, format_size_with_unit,
+        loop_device::LoopDevice,
+        read_os_release,
+        system::{copy_dir, describe_processes_using, is_dir, mkdir, stat, uname},
+        whereis, Error, ErrorKind, OsRelease, Result, ToError,
+    },
+    stage1::{
+        defs::OSArch,
+        proxy::{check_proxy_connect, proxy_for},
     },
-    stage1::defs::OSArch,
 };
 
-use log::{error, trace, warn};
+use log::{debug, error, trace, warn};
 use regex::Regex;
 
 use crate::common::path_append;
+use crate::stage1::block_device_info::mount::Mount;
+use crate::stage1::events::Stage1Event;
 use crate::stage1::migrate_info::MigrateInfo;
 
-use std::fs::create_dir_all;
+use std::fs::{create_dir_all, read_to_string, File};
 use std::io::Read;
 
+#[derive(Debug, Clone)]
+pub(crate) struct KernelInfo {
+    release: String,
+    version: String,
+    arch: String,
+}
+
+impl KernelInfo {
+    #[allow(dead_code)]
+    pub fn get_release(&self) -> &str {
+        self.release.as_str()
+    }
+    #[allow(dead_code)]
+    pub fn get_version(&self) -> &str {
+        self.version.as_str()
+    }
+    pub fn get_arch(&self) -> &str {
+        self.arch.as_str()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum InitSystem {
+    Systemd,
+    OpenRC,
+    SysV,
+    Unknown,
+}
+
+fn init_system_from_comm(comm: &str) -> InitSystem {
+    match comm.trim() {
+        "systemd" => InitSystem::Systemd,
+        "openrc-init" => InitSystem::OpenRC,
+        "init" => InitSystem::SysV,
+        _ => InitSystem::Unknown,
+    }
+}
+
+/// Determine the init system of the host, callers should warn rather than guess when the init
+/// system is Unknown
+#[allow(dead_code)]
+pub(crate) fn detect_init_system() -> InitSystem {
+    trace!("detect_init_system: entered");
+    if let Ok(true) = dir_exists(SYSTEMD_RUN_DIR) {
+        return InitSystem::Systemd;
+    }
+
+    if file_exists(OPENRC_BIN) {
+        return InitSystem::OpenRC;
+    }
+
+    match read_to_string(PROC_INIT_COMM) {
+        Ok(comm) => {
+            let init_system = init_system_from_comm(&comm);
+            debug!(
+                "detect_init_system: pid 1 is '{}', assuming {:?}",
+                comm.trim(),
+                init_system
+            );
+            init_system
+        }
+        Err(why) => {
+            warn!(
+                "detect_init_system: failed to read '{}', error: {}",
+                PROC_INIT_COMM, why
+            );
+            InitSystem::Unknown
+        }
+    }
+}
+
+const RAMFS_TYPES: [&str; 3] = ["rootfs", "tmpfs", "ramfs"];
+// the kernel starts /init from an initramfs
+const INITRAMFS_INIT: &str = "/init";
+
+/// Check if the arguments of pid 1 (NUL separated) are those of an initramfs init
+fn is_initramfs_cmdline(cmdline: &[u8]) -> bool {
+    cmdline
+        .split(|byte| *byte == 0)
+        .next()
+        .is_some_and(|init| init == INITRAMFS_INIT.as_bytes())
+}
+
+/// Determine if the root file system is a ramdisk, eg. when running from an initramfs
+#[allow(dead_code)]
+pub(crate) fn running_in_ramfs() -> Result<bool> {
+    trace!("running_in_ramfs: entered");
+    if let Some(root_mount) = Mount::get_mount_at(Path::new("/"))? {
+        if RAMFS_TYPES.contains(&root_mount.get_fs_type()) {
+            debug!(
+                "running_in_ramfs: root file system is of type {}",
+                root_mount.get_fs_type()
+            );
+            return Ok(true);
+        }
+    }
+
+    let cmdline = std::fs::read(PROC_INIT_CMDLINE)
+        .upstream_with_context(&format!("Failed to read '{}'", PROC_INIT_CMDLINE))?;
+    Ok(is_initramfs_cmdline(&cmdline))
+}
+
+/// Retrieve kernel release, version & machine architecture ( uname -r, -v, -m )
+pub(crate) fn get_kernel_info() -> Result<KernelInfo> {
+    trace!("get_kernel_info: entered");
+    let uname_res = uname()?;
+    Ok(KernelInfo {
+        release: uname_res.get_release().to_string(),
+        version: uname_res.get_version().to_string(),
+        arch: uname_res.get_machine().to_string(),
+    })
+}
+
 pub(crate) fn get_os_arch() -> Result<OSArch> {
     trace!("get_os_arch: entered");
 
-    let uname_res = uname()?;
-    let machine = uname_res.get_machine();
+    let kernel_info = get_kernel_info()?;
+    let machine = kernel_info.get_arch();
     match machine {
         "x86_64" => Ok(OSArch::AMD64),
         "i386" => Ok(OSArch::I386),
-        "armv7l" => Ok(OSArch::ARMHF),
-        "armv6l" => Ok(OSArch::ARMHF),
-        "aarch64" => Ok(OSArch::ARM64),
+        "armv7l" | "armv6l" => {
+            // balena OS armhf images require VFP
+            if has_hard_float()? {
+                Ok(OSArch::ARMHF)
+            } else {
+                Err(Error::with_context(
+                    ErrorKind::UnsupportedArch,
+                    &format!(
+                        "get_os_arch: the {} CPU does not support hard float, which is required by balena OS",
+                        machine
+                    ),
+                ))
+            }
+        }
+        "aarch64" => {
+            // a 64 bit kernel might be running a 32 bit userland
+            if is_32bit_userland()? {
+                info!(
+                    "get_os_arch: detected 32 bit userland on {} kernel",
+                    machine
+                );
+                Ok(OSArch::ARMHF)
+            } else {
+                Ok(OSArch::ARM64)
+            }
+        }
         _ => Err(Error::with_context(
-            ErrorKind::InvParam,
+            ErrorKind::UnsupportedArch,
             &format!("get_os_arch: unsupported architecture '{}'", machine),
         )),
     }
 }
 
+const PROC_CPUINFO: &str = "/proc/cpuinfo";
+
+/// Check the Features lines of cpuinfo for VFP or NEON support
+fn cpuinfo_has_hard_float(cpuinfo: &str) -> bool {
+    cpuinfo
+        .lines()
+        .filter_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            if key.trim() == "Features" {
+                Some(value)
+            } else {
+                None
+            }
+        })
+        .flat_map(|features| features.split_whitespace())
+        .any(|feature| feature.starts_with("vfp") || feature == "neon")
+}
+
+/// Determine if the CPU supports hard float (VFP / NEON) from /proc/cpuinfo
+pub(crate) fn has_hard_float() -> Result<bool> {
+    trace!("has_hard_float: entered");
+    let cpuinfo = read_to_string(PROC_CPUINFO)
+        .upstream_with_context(&format!("Failed to read '{}'", PROC_CPUINFO))?;
+    Ok(cpuinfo_has_hard_float(&cpuinfo))
+}
+
+const USERLAND_BINARY: &str = "/bin/sh";
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELF_HEADER_SIZE: usize = 20;
+const EI_CLASS: usize = 4;
+const ELFCLASS32: u8 = 1;
+const ELFCLASS64: u8 = 2;
+const EI_DATA: usize = 5;
+const ELFDATA2LSB: u8 = 1;
+const ELFDATA2MSB: u8 = 2;
+const E_MACHINE: usize = 18;
+const EM_386: u16 = 3;
+const EM_ARM: u16 = 40;
+const EM_X86_64: u16 = 62;
+const EM_AARCH64: u16 = 183;
+
+/// Read the start of the ELF header of path
+fn read_elf_header<P: AsRef<Path>>(path: P) -> Result<[u8; ELF_HEADER_SIZE]> {
+    let path = path.as_ref();
+    let mut header = [0u8; ELF_HEADER_SIZE];
+    let mut file = File::open(path).upstream_with_context(&format!(
+        "Failed to open file '{}' to read ELF header",
+        path.display()
+    ))?;
+    file.read_exact(&mut header)
+        .upstream_with_context(&format!(
+            "Failed to read ELF header from file '{}'",
+            path.display()
+        ))?;
+
+    if header[0..ELF_MAGIC.len()] != ELF_MAGIC {
+        return Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!("File '{}' is not an ELF file", path.display()),
+        ));
+    }
+    Ok(header)
+}
+
+/// Check whether the userland is 32 bit, a 64 bit kernel can run a 32 bit userland
+pub(crate) fn is_32bit_userland() -> Result<bool> {
+    is_32bit_elf(USERLAND_BINARY)
+}
+
+fn is_32bit_elf<P: AsRef<Path>>(path: P) -> Result<bool> {
+    let header = read_elf_header(&path)?;
+    match header[EI_CLASS] {
+        ELFCLASS32 => Ok(true),
+        ELFCLASS64 => Ok(false),
+        class => Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!(
+                "Invalid ELF class {} in file '{}'",
+                class,
+                path.as_ref().display()
+            ),
+        )),
+    }
+}
+
+/// Map the e_machine field of an ELF header to the architecture it targets
+fn elf_header_arch(header: &[u8; ELF_HEADER_SIZE]) -> Result<OSArch> {
+    let machine_bytes = [header[E_MACHINE], header[E_MACHINE + 1]];
+    let machine = match header[EI_DATA] {
+        ELFDATA2LSB => u16::from_le_bytes(machine_bytes),
+        ELFDATA2MSB => u16::from_be_bytes(machine_bytes),
+        data => {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!("Invalid ELF data encoding {}", data),
+            ))
+        }
+    };
+
+    match machine {
+        EM_X86_64 => Ok(OSArch::AMD64),
+        EM_386 => Ok(OSArch::I386),
+        EM_ARM => Ok(OSArch::ARMHF),
+        EM_AARCH64 => Ok(OSArch::ARM64),
+        _ => Err(Error::with_context(
+            ErrorKind::UnsupportedArch,
+            &format!("Unsupported ELF machine type {}", machine),
+        )),
+    }
+}
+
+/// Determine the architecture an ELF file, eg. the balena OS kernel, was built for
+#[allow(dead_code)]
+pub(crate) fn elf_arch(path: &Path) -> Result<OSArch> {
+    trace!("elf_arch: entered with '{}'", path.display());
+    let header = read_elf_header(path)?;
+    elf_header_arch(&header).error_with_all(
+        ErrorKind::InvParam,
+        &format!(
+            "Failed to determine architecture of ELF file '{}'",
+            path.display()
+        ),
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum SecureBootState {
+    Disabled,
+    /// No platform key is enrolled, secure boot is not enforced
+    SetupMode,
+    Enabled,
+    /// Not an EFI system or secure boot is not supported by the firmware
+    Unsupported,
+}
+
+/// Returns true only if secure boot is enabled and enforced, see get_secure_boot_state
+#[allow(dead_code)]
+pub(crate) fn is_secure_boot() -> Result<bool> {
+    Ok(get_secure_boot_state()? == SecureBootState::Enabled)
+}
+
 /******************************************************************
- * Try to find out if secure boot is enabled using mokutil
- * assuming secure boot is not enabled if mokutil is absent
+ * Determine the secure boot state from the SetupMode EFI variable
+ * and mokutil, falling back to the SecureBoot EFI variable if
+ * mokutil is absent
  ******************************************************************/
 
-pub(crate) fn is_secure_boot() -> Result<bool> {
-    trace!("is_secure_boot: entered");
+pub(crate) fn get_secure_boot_state() -> Result<SecureBootState> {
+    trace!("get_secure_boot_state: entered");
 
-    // TODO: check for efi vars
+    if !dir_exists(SYS_EFI_DIR)? {
+        return Ok(SecureBootState::Unsupported);
+    }
 
-    if dir_exists(SYS_EFI_DIR)? {
-        let mokutil_path = match whereis(MOKUTIL_CMD) {
-            Ok(path) => path,
-            Err(_why) => {
-                warn!("The mokutil command '{}' could not be found", MOKUTIL_CMD);
-                return Ok(false);
-            }
-        };
+    match read_efivar_value(EFI_SETUP_MODE_VAR) {
+        Ok(Some(1)) => return Ok(SecureBootState::SetupMode),
+        Ok(_) => (),
+        Err(why) => debug!(
+            "get_secure_boot_state: failed to read SetupMode EFI variable: {}",
+            why
+        ),
+    }
 
-        let cmd_res = call(&mokutil_path, &["--sb-state"], true)?;
-        if cmd_res.stderr.is_empty() {
-            let regex = Regex::new(r"^SecureBoot\s+(disabled|enabled)$").unwrap();
-            let lines = cmd_res.stdout.lines();
-            for line in lines {
-                if let Some(cap) = regex.captures(line) {
-                    if cap.get(1).unwrap().as_str() == "enabled" {
-                        return Ok(true);
-                    } else {
-                        return Ok(false);
-                    }
-                }
+    let mokutil_path = match whereis(MOKUTIL_CMD) {
+        Ok(path) => path,
+        Err(_why) => {
+            warn!(
+                "The mokutil command '{}' could not be found, reading EFI variables instead",
+                MOKUTIL_CMD
+            );
+            return secure_boot_state_efivar();
+        }
+    };
+
+    let cmd_res = call_timeout(&mokutil_path, &["--sb-state"], true, DEFAULT_CALL_TIMEOUT)?;
+    if let Some(signal) = cmd_res.killed_by_signal() {
+        Err(Error::with_context(
+            ErrorKind::ExecProcess,
+            &format!("mokutil was killed by signal {}", signal),
+        ))
+    } else if cmd_res.stderr.is_empty() {
+        match parse_sb_state(&cmd_res.stdout) {
+            Some(true) => Ok(SecureBootState::Enabled),
+            Some(false) => Ok(SecureBootState::Disabled),
+            None => {
+                error!(
+                    "is_secure_boot: failed to parse command output: '{}'",
+                    cmd_res.stdout
+                );
+                Err(Error::with_context(
+                    ErrorKind::InvParam,
+                    &"is_secure_boot: failed to parse command output".to_string(),
+                ))
             }
+        }
+    } else if cmd_res
+        .stderr
+        .starts_with("This system doesn't support Secure Boot")
+    {
+        Ok(SecureBootState::Unsupported)
+    } else {
+        Err(Error::with_context(
+            ErrorKind::ExecProcess,
+            &format!("mokutil returned an error message: '{}'", cmd_res.stderr),
+        ))
+    }
+}
 
-            error!(
-                "is_secure_boot: failed to parse command output: '{}'",
-                cmd_res.stdout
-            );
-            Err(Error::with_context(
-                ErrorKind::InvParam,
-                &"is_secure_boot: failed to parse command output".to_string(),
-            ))
-        } else if cmd_res
-            .stderr
-            .starts_with("This system doesn't support Secure Boot")
-        {
-            Ok(false)
-        } else {
-            Err(Error::with_context(
-                ErrorKind::ExecProcess,
-                &format!("mokutil returned an error message: '{}'", cmd_res.stderr),
-            ))
+/// Parse the output of 'mokutil --sb-state', different mokutil versions print eg.
+/// 'SecureBoot enabled' or 'SecureBoot validation is disabled'
+fn parse_sb_state(output: &str) -> Option<bool> {
+    lazy_static! {
+        static ref SB_STATE_REGEX: Regex =
+            Regex::new(r"(?i)^\s*SecureBoot\b.*\b(disabled|enabled)\b").unwrap();
+    }
+
+    for line in output.lines() {
+        if let Some(cap) = SB_STATE_REGEX.captures(line) {
+            return Some(cap.get(1).unwrap().as_str().eq_ignore_ascii_case("enabled"));
         }
+    }
+    None
+}
+
+/// Read a boolean EFI variable, the file consists of 4 bytes of attributes followed by
+/// a single value byte. Returns None if the variable does not exist
+fn read_efivar_value(var_name: &str) -> Result<Option<u8>> {
+    trace!("read_efivar_value: entered with '{}'", var_name);
+
+    let efivars_empty = match std::fs::read_dir(SYS_EFIVARS_DIR) {
+        Ok(mut entries) => entries.next().is_none(),
+        Err(_) => true,
+    };
+
+    if efivars_empty {
+        // efivarfs is not mounted, we can not tell
+        return Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!(
+                "is_secure_boot: unable to determine secure boot state, no EFI variables found in '{}'",
+                SYS_EFIVARS_DIR
+            ),
+        ));
+    }
+
+    let var_path = path_append(SYS_EFIVARS_DIR, var_name);
+    if !file_exists(&var_path) {
+        debug!(
+            "read_efivar_value: EFI variable '{}' not found",
+            var_path.display()
+        );
+        return Ok(None);
+    }
+
+    let var_data = std::fs::read(&var_path).upstream_with_context(&format!(
+        "is_secure_boot: failed to read EFI variable '{}'",
+        var_path.display()
+    ))?;
+
+    if var_data.len() != 5 {
+        return Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!(
+                "is_secure_boot: unexpected size {} of EFI variable '{}'",
+                var_data.len(),
+                var_path.display()
+            ),
+        ));
+    }
+
+    Ok(Some(var_data[4]))
+}
+
+/// Determine the secure boot state from the SecureBoot EFI variable, a value of 1 means
+/// secure boot is enabled
+fn secure_boot_state_efivar() -> Result<SecureBootState> {
+    match read_efivar_value(EFI_SECURE_BOOT_VAR)? {
+        Some(1) => Ok(SecureBootState::Enabled),
+        Some(_) => Ok(SecureBootState::Disabled),
+        None => Ok(SecureBootState::Unsupported),
+    }
+}
+
+const MKTEMP_RAND_CHARS: usize = 10;
+const MKTEMP_MIN_TEMPLATE: usize = 3;
+
+/// The directory mktemp uses if no path is given, $TMPDIR or /tmp
+fn default_temp_dir() -> PathBuf {
+    match std::env::var_os("TMPDIR") {
+        Some(tmp_dir) if !tmp_dir.is_empty() => PathBuf::from(tmp_dir),
+        _ => PathBuf::from("/tmp"),
+    }
+}
+
+// space to leave free when staging files
+const STAGING_MARGIN: u64 = 64 * 1024 * 1024;
+
+/// Get the space available for staging files in dir, for memory backed file systems this is
+/// limited by the available memory. Returns the space and whether dir is memory backed.
+fn staging_space(dir: &Path) -> Result<(u64, bool)> {
+    let fs_stat = statfs(dir).upstream_with_context(&format!(
+        "Failed to retrieve file system type of '{}'",
+        dir.display()
+    ))?;
+    let space = available_space(dir)?;
+    if fs_stat.filesystem_type() == TMPFS_MAGIC {
+        Ok((min(space, available_memory()?), true))
     } else {
-        Ok(false)
+        Ok((space, false))
+    }
+}
+
+/// Choose the directory to stage a file of size bytes in. The default temporary directory is
+/// preferred, if it is a tmpfs the file must fit into available memory. Otherwise disk_dir is
+/// used or ErrorKind::InvState is returned if neither has enough space.
+pub(crate) fn staging_dir(size: u64, disk_dir: &Path) -> Result<PathBuf> {
+    trace!(
+        "staging_dir: entered with size {}, '{}'",
+        size,
+        disk_dir.display()
+    );
+    let required = size + STAGING_MARGIN;
+    let temp_dir = default_temp_dir();
+    let (temp_space, in_memory) = staging_space(&temp_dir)?;
+    if temp_space >= required {
+        debug!(
+            "staging_dir: using '{}', {} available",
+            temp_dir.display(),
+            format_size_with_unit(temp_space)
+        );
+        return Ok(temp_dir);
     }
+
+    let (disk_space, _) = staging_space(disk_dir)?;
+    if disk_space >= required {
+        info!(
+            "Not enough space in '{}' ({} available{}), staging {} in '{}'",
+            temp_dir.display(),
+            format_size_with_unit(temp_space),
+            if in_memory { " memory" } else { "" },
+            format_size_with_unit(size),
+            disk_dir.display()
+        );
+        return Ok(disk_dir.to_path_buf());
+    }
+
+    Err(Error::with_context(
+        ErrorKind::InvState,
+        &format!(
+            "Not enough space to stage {}, '{}' has {} and '{}' has {} available",
+            format_size_with_unit(required),
+            temp_dir.display(),
+            format_size_with_unit(temp_space),
+            disk_dir.display(),
+            format_size_with_unit(disk_space)
+        ),
+    ))
 }
 
+/// Create a uniquely named temporary file or directory in path, $TMPDIR or /tmp.
+/// If prefix ends in a template like 'tmp.XXXXXX' the trailing X's are replaced by random
+/// characters, otherwise 10 random characters are appended to the prefix.
 pub(crate) fn mktemp<P: AsRef<Path>>(
     dir: bool,
     prefix: Option<&str>,
     suffix: Option<&str>,
     path: Option<P>,
 ) -> Result<PathBuf> {
-    loop {
-        let mut file_name = String::new();
-        if let Some(prefix) = prefix {
-            file_name.push_str(prefix);
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let parent = if let Some(path) = &path {
+        path.as_ref().to_path_buf()
+    } else {
+        default_temp_dir()
+    };
+
+    let (prefix, rand_chars) = if let Some(prefix) = prefix {
+        let template_len = prefix.len() - prefix.trim_end_matches('X').len();
+        if template_len >= MKTEMP_MIN_TEMPLATE {
+            (&prefix[..prefix.len() - template_len], template_len)
+        } else {
+            (prefix, MKTEMP_RAND_CHARS)
         }
+    } else {
+        ("", MKTEMP_RAND_CHARS)
+    };
+
+    loop {
+        let mut file_name = String::from(prefix);
         file_name.push_str(
             thread_rng()
                 .sample_iter(&Alphanumeric)
-                .take(10)
+                .take(rand_chars)
                 .collect::<String>()
                 .as_str(),
         );
@@ -124,21 +597,33 @@ pub(crate) fn mktemp<P: AsRef<Path>>(
             file_name.push_str(suffix);
         }
 
-        let new_path = if let Some(path) = &path {
-            path_append(path.as_ref(), file_name.as_str())
-        } else {
-            path_append("/tmp", file_name.as_str())
-        };
+        let new_path = path_append(&parent, file_name.as_str());
 
-        match if dir {
-            mkdir(new_path.as_path(), 0o755)
+        if dir {
+            match mkdir(new_path.as_path(), 0o755) {
+                Ok(_) => return Ok(new_path),
+                Err(why) => {
+                    if why.kind() != ErrorKind::FileExists {
+                        return Err(Error::with_cause(ErrorKind::Upstream, Box::new(why)));
+                    }
+                }
+            }
         } else {
-            mknod(new_path.as_path(), S_IFREG | 0o755, 0)
-        } {
-            Ok(_) => return Ok(new_path),
-            Err(why) => {
-                if why.kind() != ErrorKind::FileExists {
-                    return Err(Error::with_cause(ErrorKind::Upstream, Box::new(why)));
+            // create_new maps to O_CREAT | O_EXCL
+            match OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o755)
+                .open(&new_path)
+            {
+                Ok(_) => return Ok(new_path),
+                Err(why) => {
+                    if why.kind() != io::ErrorKind::AlreadyExists {
+                        return Err(Error::from_upstream(
+                            Box::new(why),
+                            &format!("Failed to create file '{}'", new_path.display()),
+                        ));
+                    }
                 }
             }
         }
@@ -146,24 +631,137 @@ pub(crate) fn mktemp<P: AsRef<Path>>(
 }
 
 pub(crate) fn check_tcp_connect(host: &str, port: u16, timeout: u64) -> Result<()> {
-    use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+    check_tcp_connect_retry(host, port, timeout, 1, 0)
+}
+
+// upper limit for the wait between connection attempts in seconds
+const MAX_RETRY_DELAY: u64 = 300;
+
+/// Try to connect to host:port up to retries times, waiting backoff seconds after the first
+/// failed attempt and doubling the wait after each further failure, up to MAX_RETRY_DELAY.
+pub(crate) fn check_tcp_connect_retry(
+    host: &str,
+    port: u16,
+    timeout: u64,
+    retries: u32,
+    backoff: u64,
+) -> Result<()> {
+    use std::thread::sleep;
     use std::time::Duration;
-    let url = format!("{}:{}", host, port);
-    let mut addrs_iter = url.to_socket_addrs().upstream_with_context(&format!(
-        "check_tcp_connect: failed to resolve host address: '{}'",
-        url
-    ))?;
+    trace!(
+        "check_tcp_connect_retry: entered with host: '{}', port: {}, retries: {}",
+        host,
+        port,
+        retries
+    );
 
-    if let Some(ref sock_addr) = addrs_iter.next() {
-        let tcp_stream = TcpStream::connect_timeout(sock_addr, Duration::from_secs(timeout))
-            .upstream_with_context(&format!(
-                "check_tcp_connect: failed to connect to: '{}' with timeout: {}",
-                url, timeout
-            ))?;
+    if timeout == 0 {
+        return Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!(
+                "check_tcp_connect: invalid timeout 0 for '{}:{}', the timeout must be at least 1 second",
+                host, port
+            ),
+        ));
+    }
 
-        let _res = tcp_stream.shutdown(Shutdown::Both);
-        Ok(())
+    let mut delay = backoff.min(MAX_RETRY_DELAY);
+    let mut attempt = 1;
+    loop {
+        match try_tcp_connect(host, port, timeout) {
+            Ok(_) => return Ok(()),
+            Err(why) => {
+                if attempt >= retries {
+                    return Err(why);
+                }
+                warn!(
+                    "check_tcp_connect_retry: attempt {} of {} failed, retrying in {} seconds, error: {}",
+                    attempt, retries, delay, why
+                );
+                sleep(Duration::from_secs(delay));
+                delay = delay.saturating_mul(2).min(MAX_RETRY_DELAY);
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// Check every endpoint in endpoints without stopping at the first failure and return the
+/// result for each, fails only if timeout is invalid
+pub(crate) fn check_endpoints(
+    endpoints: &[(String, u16)],
+    timeout: u64,
+) -> Result<Vec<(String, u16, Result<()>)>> {
+    trace!(
+        "check_endpoints: entered with {} endpoints",
+        endpoints.len()
+    );
+    if timeout == 0 {
+        return Err(Error::with_context(
+            ErrorKind::InvParam,
+            "check_endpoints: invalid timeout 0, the timeout must be at least 1 second",
+        ));
+    }
+
+    Ok(endpoints
+        .iter()
+        .map(|(host, port)| (host.clone(), *port, check_tcp_connect(host, *port, timeout)))
+        .collect())
+}
+
+/// Format host and port as host:port, enclosing IPv6 literals in brackets
+fn format_host_port(host: &str, port: u16) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]:{}", host, port)
     } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+fn resolve_host(host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+    // resolve the unbracketed host so scoped literals like fe80::1%eth0 reach getaddrinfo
+    let bare_host = host.trim_start_matches('[').trim_end_matches(']');
+    Ok((bare_host, port)
+        .to_socket_addrs()
+        .upstream_with_context(&format!(
+            "check_tcp_connect: failed to resolve host address: '{}'",
+            format_host_port(host, port)
+        ))?
+        .collect())
+}
+
+fn try_tcp_connect(host: &str, port: u16, timeout: u64) -> Result<()> {
+    use std::net::{Shutdown, TcpStream};
+    use std::time::Duration;
+    let url = format_host_port(host, port);
+    if let Some(proxy) = proxy_for(host)? {
+        debug!("check_tcp_connect: connecting to '{}' through proxy", url);
+        return check_proxy_connect(&proxy, host, port, timeout);
+    }
+    let addrs_iter = resolve_host(host, port)?;
+
+    let mut failures: Vec<String> = Vec::new();
+    for sock_addr in addrs_iter {
+        match TcpStream::connect_timeout(&sock_addr, Duration::from_secs(timeout)) {
+            Ok(tcp_stream) => {
+                debug!(
+                    "check_tcp_connect: connected to '{}' via {}",
+                    url, sock_addr
+                );
+                let _res = tcp_stream.shutdown(Shutdown::Both);
+                return Ok(());
+            }
+            Err(why) => {
+                debug!(
+                    "check_tcp_connect: failed to connect to '{}' via {}, error: {:?}",
+                    url, sock_addr, why
+                );
+                failures.push(format!("{}: {}", sock_addr, why));
+            }
+        }
+    }
+
+    if failures.is_empty() {
         Err(Error::with_context(
             ErrorKind::InvState,
             &format!(
@@ -171,6 +769,16 @@ pub(crate) fn check_tcp_connect(host: &str, port: u16, timeout: u64) -> Result<(
                 url
             ),
         ))
+    } else {
+        Err(Error::with_context(
+            ErrorKind::Upstream,
+            &format!(
+                "check_tcp_connect: failed to connect to: '{}' with timeout: {}, tried {}",
+                url,
+                timeout,
+                failures.join(", ")
+            ),
+        ))
     }
 }
 
@@ -179,9 +787,109 @@ pub(crate) fn mount_fs<P: AsRef<Path>>(
     fs: &str,
     fs_type: &str,
     mig_info: Option<&mut MigrateInfo>,
+) -> Result<()> {
+    mount_fs_with_flags(mount_dir, fs, fs_type, MsFlags::empty(), mig_info)
+}
+
+/// Unmount path, if the mount is busy and lazy_fallback is set detach it instead
+pub(crate) fn unmount(path: &Path, lazy_fallback: bool) -> Result<()> {
+    trace!(
+        "unmount: entered with '{}', lazy_fallback: {}",
+        path.display(),
+        lazy_fallback
+    );
+    match umount(path) {
+        Ok(_) => Ok(()),
+        Err(nix::Error::Sys(Errno::EBUSY)) if lazy_fallback => {
+            // the file system stays alive until the last user is gone
+            warn!(
+                "Mountpoint '{}' is busy, held by {}, using lazy unmount - the file system is detached but remains in use",
+                path.display(),
+                describe_processes_using(path)
+            );
+            umount2(path, MntFlags::MNT_DETACH).upstream_with_context(&format!(
+                "Failed to lazily unmount mountpoint: '{}'",
+                path.display()
+            ))
+        }
+        Err(nix::Error::Sys(Errno::EBUSY)) => Err(Error::with_context(
+            ErrorKind::Upstream,
+            &format!(
+                "Failed to unmount mountpoint: '{}', mountpoint busy, held by {}",
+                path.display(),
+                describe_processes_using(path)
+            ),
+        )),
+        Err(why) => Err(Error::with_all(
+            ErrorKind::Upstream,
+            &format!("Failed to unmount mountpoint: '{}'", path.display()),
+            Box::new(why),
+        )),
+    }
+}
+
+pub(crate) fn mount_fs_with_flags<P: AsRef<Path>>(
+    mount_dir: P,
+    fs: &str,
+    fs_type: &str,
+    flags: MsFlags,
+    mig_info: Option<&mut MigrateInfo>,
 ) -> Result<()> {
     let mount_dir = mount_dir.as_ref();
-    if !dir_exists(mount_dir)? {
+    let mig_info = match mig_info {
+        Some(mig_info) if mig_info.dry_run() => {
+            info!(
+                "Dry run - would mount {} on '{}' with fstype {} and flags {:?}",
+                fs,
+                mount_dir.display(),
+                fs_type,
+                flags
+            );
+            mig_info.add_mount(mount_dir);
+            return Ok(());
+        }
+        mig_info => mig_info,
+    };
+
+    if dir_exists(mount_dir)? {
+        let canonical_dir = mount_dir.canonicalize().upstream_with_context(&format!(
+            "Failed to canonicalize path '{}'",
+            mount_dir.display()
+        ))?;
+        if let Some((source, mount)) = Mount::get_source_mount_at(&canonical_dir)? {
+            // devices might be given through a symlink, eg. /dev/disk/by-uuid/..
+            let fs_source = if fs.starts_with('/') {
+                Path::new(fs)
+                    .canonicalize()
+                    .unwrap_or_else(|_| PathBuf::from(fs))
+            } else {
+                PathBuf::from(fs)
+            };
+            if mount.get_fs_type() == fs_type && source == fs_source {
+                info!(
+                    "File system {} is already mounted on '{}'",
+                    fs_type,
+                    mount_dir.display()
+                );
+                if let Some(mig_info) = mig_info {
+                    mig_info.add_mount(mount_dir);
+                }
+                return Ok(());
+            } else {
+                return Err(Error::with_context(
+                    ErrorKind::InvState,
+                    &format!(
+                        "Failed to mount {} ({}) on '{}', {} ({}) is already mounted there",
+                        fs,
+                        fs_type,
+                        mount_dir.display(),
+                        source.display(),
+                        mount.get_fs_type()
+                    ),
+                ));
+            }
+        }
+    } else {
         create_dir_all(mount_dir).upstream_with_context(&format!(
             "Failed to create mount directory '{}'",
             mount_dir.display()
@@ -192,18 +900,22 @@ pub(crate) fn mount_fs<P: AsRef<Path>>(
         Some(fs.as_bytes()),
         mount_dir,
         Some(fs_type.as_bytes()),
-        MsFlags::empty(),
+        flags,
         NIX_NONE,
     )
     .upstream_with_context(&format!(
-        "Failed to mount {} on {} with fstype {}",
+        "Failed to mount {} on {} with fstype {} and flags {:?}",
         fs,
         mount_dir.display(),
-        fs_type
+        fs_type,
+        flags
     ))?;
 
     if let Some(mig_info) = mig_info {
         mig_info.add_mount(mount_dir);
+        mig_info.emit(Stage1Event::Mounted {
+            path: mount_dir.to_path_buf(),
+        });
     }
 
     info!("Mounted {} file system on '{}'", fs, mount_dir.display());
@@ -211,6 +923,354 @@ pub(crate) fn mount_fs<P: AsRef<Path>>(
     Ok(())
 }
 
+/// Bind mount source on target, creating target if necessary
+#[allow(dead_code)]
+pub(crate) fn bind_mount<P1: AsRef<Path>, P2: AsRef<Path>>(
+    source: P1,
+    target: P2,
+    mig_info: Option<&mut MigrateInfo>,
+) -> Result<()> {
+    let source = source.as_ref();
+    let target = target.as_ref();
+    trace!(
+        "bind_mount: entered with source: '{}', target: '{}'",
+        source.display(),
+        target.display()
+    );
+
+    if !file_exists(source) {
+        return Err(Error::with_context(
+            ErrorKind::FileNotFound,
+            &format!(
+                "bind_mount: source path '{}' does not exist",
+                source.display()
+            ),
+        ));
+    }
+
+    let mig_info = match mig_info {
+        Some(mig_info) if mig_info.dry_run() => {
+            info!(
+                "Dry run - would bind mount '{}' on '{}'",
+                source.display(),
+                target.display()
+            );
+            mig_info.add_mount(target);
+            return Ok(());
+        }
+        mig_info => mig_info,
+    };
+
+    if !dir_exists(target)? {
+        create_dir_all(target).upstream_with_context(&format!(
+            "Failed to create mount directory '{}'",
+            target.display()
+        ))?;
+    }
+
+    mount(Some(source), target, NIX_NONE, MsFlags::MS_BIND, NIX_NONE).upstream_with_context(
+        &format!(
+            "Failed to bind mount '{}' on '{}'",
+            source.display(),
+            target.display()
+        ),
+    )?;
+
+    if let Some(mig_info) = mig_info {
+        mig_info.add_mount(target);
+        mig_info.emit(Stage1Event::Mounted {
+            path: target.to_path_buf(),
+        });
+    }
+
+    info!(
+        "Bind mounted '{}' on '{}'",
+        source.display(),
+        target.display()
+    );
+
+    Ok(())
+}
+
+const PROC_SWAPS: &str = "/proc/swaps";
+
+/// Read active swaps from /proc/swaps as (path, type) tuples
+fn read_swaps() -> Result<Vec<(PathBuf, String)>> {
+    let swaps_str = read_to_string(PROC_SWAPS)
+        .upstream_with_context(&format!("Failed to read from '{}'", PROC_SWAPS))?;
+
+    let mut swaps: Vec<(PathBuf, String)> = Vec::new();
+    // skip header line
+    for line in swaps_str.lines().skip(1) {
+        let columns: Vec<&str> = line.split_whitespace().collect();
+        if columns.len() < 2 {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!("Failed to parse {} line: '{}'", PROC_SWAPS, line),
+            ));
+        }
+        swaps.push((PathBuf::from(columns[0]), columns[1].to_string()));
+    }
+    Ok(swaps)
+}
+
+/// Get the device backed swaps that are currently active
+#[allow(dead_code)]
+pub(crate) fn active_swaps() -> Result<Vec<PathBuf>> {
+    Ok(read_swaps()?
+        .into_iter()
+        .filter(|(_, swap_type)| swap_type == "partition")
+        .map(|(path, _)| path)
+        .collect())
+}
+
+/// Disable all active swaps, recording them in mig_info so they can be restored
+pub(crate) fn swapoff_all(mig_info: &mut MigrateInfo) -> Result<()> {
+    trace!("swapoff_all: entered");
+    for (swap, swap_type) in read_swaps()? {
+        if mig_info.dry_run() {
+            info!(
+                "Dry run - would disable swap {} '{}'",
+                swap_type,
+                swap.display()
+            );
+            continue;
+        }
+        let swap_str = swap.to_string_lossy();
+        call_command!(
+            SWAPOFF_CMD,
+            &[&*swap_str],
+            &format!("Failed to disable swap {} '{}'", swap_type, swap.display())
+        )?;
+        info!("Disabled swap {} '{}'", swap_type, swap.display());
+        mig_info.add_swap(&swap);
+    }
+    Ok(())
+}
+
+const PROC_MODULES: &str = "/proc/modules";
+const LIB_MODULES_DIR: &str = "/lib/modules";
+const MODULES_BUILTIN: &str = "modules.builtin";
+
+/// Module names use '-' and '_' interchangeably, the kernel reports them with '_'
+fn normalize_module_name(name: &str) -> String {
+    name.replace('-', "_")
+}
+
+/// Extract the names of the loaded modules from the content of /proc/modules
+fn parse_proc_modules(proc_modules: &str) -> Vec<String> {
+    proc_modules
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .map(normalize_module_name)
+        .collect()
+}
+
+/// Extract the names of the built in modules from the content of modules.builtin, which lists
+/// them as paths, eg. 'kernel/drivers/block/loop.ko'
+fn parse_modules_builtin(modules_builtin: &str) -> Vec<String> {
+    modules_builtin
+        .lines()
+        .filter_map(|line| Path::new(line.trim()).file_stem())
+        .map(|stem| normalize_module_name(&stem.to_string_lossy()))
+        .collect()
+}
+
+/// The names of the modules that are loaded or built into the running kernel
+fn available_modules() -> Result<Vec<String>> {
+    // kernels without module support have no /proc/modules
+    let mut modules = if file_exists(PROC_MODULES) {
+        parse_proc_modules(
+            &read_to_string(PROC_MODULES)
+                .upstream_with_context(&format!("Failed to read '{}'", PROC_MODULES))?,
+        )
+    } else {
+        Vec::new()
+    };
+
+    let builtin_path = path_append(
+        path_append(LIB_MODULES_DIR, uname()?.get_release()),
+        MODULES_BUILTIN,
+    );
+    match read_to_string(&builtin_path) {
+        Ok(builtin) => modules.append(&mut parse_modules_builtin(&builtin)),
+        Err(why) => debug!(
+            "available_modules: failed to read '{}', error: {}",
+            builtin_path.display(),
+            why
+        ),
+    }
+    Ok(modules)
+}
+
+/// Make sure the kernel modules in mods are available, modules that are neither loaded nor
+/// built in are loaded with modprobe. Fails with a list of the modules that could not be loaded
+pub(crate) fn ensure_modules(mods: &[&str]) -> Result<()> {
+    trace!("ensure_modules: entered with {:?}", mods);
+    let available = available_modules()?;
+    let mut failed: Vec<&str> = Vec::new();
+    for module in mods {
+        if available.contains(&normalize_module_name(module)) {
+            debug!("ensure_modules: module {} is available", module);
+            continue;
+        }
+
+        match call(MODPROBE_CMD, &[module], true) {
+            Ok(cmd_res) if cmd_res.status.success() => info!("Loaded kernel module {}", module),
+            Ok(cmd_res) => {
+                warn!(
+                    "Failed to load kernel module {}, {}, stderr: {}",
+                    module,
+                    cmd_res.exit_info(),
+                    cmd_res.stderr.trim()
+                );
+                failed.push(module);
+            }
+            Err(why) => {
+                warn!("Failed to load kernel module {}, error: {}", module, why);
+                failed.push(module);
+            }
+        }
+    }
+
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::with_context(
+            ErrorKind::ExecProcess,
+            &format!(
+                "The required kernel modules {} could not be loaded",
+                failed.join(", ")
+            ),
+        ))
+    }
+}
+
+const LOOP_DEV_PREFIX: &str = "/dev/loop";
+
+/// Set up a loop device over the region of img starting at offset, size bytes long or up to
+/// the end of img, returns the path of the loop device. The loop device persists until
+/// detached with losetup_detach, it is recorded in mig_info for cleanup on abort
+#[allow(dead_code)]
+pub(crate) fn losetup(
+    img: &Path,
+    offset: u64,
+    size: Option<u64>,
+    mig_info: Option<&mut MigrateInfo>,
+) -> Result<PathBuf> {
+    trace!(
+        "losetup: entered with '{}', offset: {}, size: {:?}",
+        img.display(),
+        offset,
+        size
+    );
+    let loop_device = LoopDevice::for_file(img, Some(offset), size, None, false)?;
+    let loop_path = loop_device.get_path().to_path_buf();
+    info!(
+        "Set up loop device '{}' for '{}' at offset {}",
+        loop_path.display(),
+        img.display(),
+        offset
+    );
+    if let Some(mig_info) = mig_info {
+        mig_info.add_loop_device(&loop_path);
+    }
+    Ok(loop_path)
+}
+
+/// Detach a loop device set up by losetup
+pub(crate) fn losetup_detach(dev: &Path, mig_info: Option<&mut MigrateInfo>) -> Result<()> {
+    trace!("losetup_detach: entered with '{}'", dev.display());
+    let loop_index = dev
+        .to_str()
+        .and_then(|dev| dev.strip_prefix(LOOP_DEV_PREFIX))
+        .and_then(|index| index.parse::<u32>().ok())
+        .ok_or_else(|| {
+            Error::with_context(
+                ErrorKind::InvParam,
+                &format!("'{}' is not a loop device", dev.display()),
+            )
+        })?;
+
+    LoopDevice::from_index(loop_index, false)?.unset()?;
+    if let Some(mig_info) = mig_info {
+        mig_info.remove_loop_device(dev);
+    }
+    debug!("Detached loop device '{}'", dev.display());
+    Ok(())
+}
+
+/// Undo the recorded stage1 actions in reverse order so an aborted takeover leaves a bootable
+/// system: unmount the recorded mounts, detach recorded loop devices, remove the takeover
+/// directory, remount the root file system read-write with its original flags if it was
+/// writable before and re-enable the swaps disabled by swapoff_all
+pub(crate) fn rollback(mig_info: &mut MigrateInfo) -> Result<()> {
+    trace!("rollback: entered");
+    // a process holding a file in the takeover directory must not keep it mounted
+    let mut failures = mig_info.umount_all(true);
+
+    for loop_device in mig_info.loop_devices().iter().rev() {
+        if mig_info.dry_run() {
+            info!(
+                "Dry run - would detach loop device '{}'",
+                loop_device.display()
+            );
+            continue;
+        }
+        match losetup_detach(loop_device, None) {
+            Ok(_) => info!("Detached loop device '{}'", loop_device.display()),
+            Err(why) => {
+                warn!("{}", why);
+                failures += 1;
+            }
+        }
+    }
+
+    // the loop devices may be backed by files in the takeover directory
+    mig_info.remove_to_dir();
+
+    if let Some(orig_root) = mig_info.root_mount().filter(|root| !root.is_read_only()) {
+        if let Some(root_mount) = Mount::get_mount_at(Path::new("/"))? {
+            if root_mount.is_read_only() {
+                match mount(NIX_NONE, "/", NIX_NONE, orig_root.remount_flags(), NIX_NONE) {
+                    Ok(_) => info!("Remounted root file system read-write"),
+                    Err(why) => {
+                        warn!(
+                            "Failed to remount root file system read-write, error: {:?}",
+                            why
+                        );
+                        failures += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    for swap in mig_info.swaps().iter().rev() {
+        let swap_str = swap.to_string_lossy();
+        match call_command!(
+            SWAPON_CMD,
+            &[&*swap_str],
+            &format!("Failed to enable swap '{}'", swap.display())
+        ) {
+            Ok(_) => info!("Re-enabled swap '{}'", swap.display()),
+            Err(why) => {
+                warn!("{}", why);
+                failures += 1;
+            }
+        }
+    }
+
+    if failures > 0 {
+        Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!("rollback: {} actions could not be undone", failures),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
 pub(crate) struct ReadBuffer<'a> {
     buffer: &'a [u8],
     read_pos: usize,
@@ -238,11 +1298,223 @@ impl<'a> Read for ReadBuffer<'a> {
     }
 }
 
+const KERNEL_HOSTNAME: &str = "/proc/sys/kernel/hostname";
+const ETC_HOSTNAME: &str = "/etc/hostname";
+const HOSTNAME_MAX_LEN: usize = 256;
+
+/// Get the hostname from the first line of an /etc/hostname file that is not a comment
+fn parse_hostname(content: &str) -> Option<&str> {
+    content
+        .lines()
+        .map(|line| line.trim())
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+/// Read the hostname the kernel is running with from /proc/sys/kernel/hostname, falling back
+/// to gethostname and /etc/hostname, fails with ErrorKind::NotFound if the hostname is empty
+pub(crate) fn read_hostname() -> Result<String> {
+    trace!("read_hostname: entered");
+    let hostname = match read_to_string(KERNEL_HOSTNAME) {
+        Ok(content) => content.trim().to_string(),
+        Err(why) => {
+            debug!(
+                "read_hostname: failed to read '{}': {}",
+                KERNEL_HOSTNAME, why
+            );
+            let mut buffer = [0u8; HOSTNAME_MAX_LEN];
+            match nix::unistd::gethostname(&mut buffer) {
+                Ok(hostname) => hostname.to_string_lossy().trim().to_string(),
+                Err(why) => {
+                    debug!("read_hostname: gethostname failed: {}", why);
+                    read_to_string(ETC_HOSTNAME)
+                        .ok()
+                        .as_deref()
+                        .and_then(parse_hostname)
+                        .unwrap_or_default()
+                        .to_string()
+                }
+            }
+        }
+    };
+
+    if hostname.is_empty() {
+        Err(Error::with_context(
+            ErrorKind::NotFound,
+            "The hostname of the machine is empty",
+        ))
+    } else {
+        Ok(hostname)
+    }
+}
+
+// files only found on a running balenaOS, the boot partition config and the engine sockets
+// the balena-engine sockets are left out, balena-engine can be installed on any distro
+const BALENA_OS_CONFIG_JSON: &str = "/mnt/boot/config.json";
+
+/// Check whether the os-release ID is a balenaOS or resinOS id
+fn is_balena_os_release(release: &OsRelease) -> bool {
+    release.id.starts_with("balena") || release.id.starts_with("resin")
+}
+
+/// Check whether the device already runs balenaOS, in which case there is nothing to migrate
+pub(crate) fn is_already_balena() -> Result<bool> {
+    trace!("is_already_balena: entered");
+    match read_os_release() {
+        Ok(release) => {
+            if is_balena_os_release(&release) {
+                debug!("is_already_balena: found balenaOS id '{}'", release.id);
+                return Ok(true);
+            }
+        }
+        Err(why) if why.kind() == ErrorKind::NotFound => (),
+        Err(why) => return Err(why),
+    }
+
+    if file_exists(BALENA_OS_CONFIG_JSON) {
+        debug!("is_already_balena: found '{}'", BALENA_OS_CONFIG_JSON);
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+/// Copy the files and directories in paths to a staging directory in the takeover directory
+/// so stage2 can restore them to the data partition. Missing files are skipped with a warning.
+/// The hostname is always preserved as etc/hostname unless paths already contains it.
+/// Returns the staging directory
+pub(crate) fn stage_preserve_files(paths: &[PathBuf], mig_info: &MigrateInfo) -> Result<PathBuf> {
+    trace!("stage_preserve_files: entered with {:?}", paths);
+
+    let staging_dir = if let Some(takeover_dir) = mig_info.to_dir() {
+        mktemp(true, Some("preserve.XXXXXX"), None, Some(takeover_dir))?
+    } else {
+        return Err(Error::with_context(
+            ErrorKind::InvState,
+            "The takeover directory has not been set up",
+        ));
+    };
+
+    for source in paths {
+        let source_stat = match stat(source) {
+            Ok(source_stat) => source_stat,
+            Err(why) => {
+                warn!(
+                    "Not preserving '{}', failed to stat file: {}",
+                    source.display(),
+                    why
+                );
+                continue;
+            }
+        };
+
+        let target = path_append(&staging_dir, source);
+        if is_dir(&source_stat) {
+            create_dir_all(&target).upstream_with_context(&format!(
+                "Failed to create directory '{}'",
+                target.display()
+            ))?;
+            copy_dir(source, &target)?;
+        } else {
+            if let Some(parent) = target.parent() {
+                create_dir_all(parent).upstream_with_context(&format!(
+                    "Failed to create directory '{}'",
+                    parent.display()
+                ))?;
+            }
+            std::fs::copy(source, &target).upstream_with_context(&format!(
+                "Failed to copy '{}' to '{}'",
+                source.display(),
+                target.display()
+            ))?;
+        }
+        info!("Staged '{}' for preservation", source.display());
+    }
+
+    stage_hostname(&staging_dir)?;
+
+    Ok(staging_dir)
+}
+
+/// Write the hostname to etc/hostname in staging_dir unless that file was staged already
+fn stage_hostname(staging_dir: &Path) -> Result<()> {
+    let hostname_path = path_append(staging_dir, ETC_HOSTNAME);
+    if hostname_path.exists() {
+        return Ok(());
+    }
+
+    match read_hostname() {
+        Ok(hostname) => {
+            if let Some(parent) = hostname_path.parent() {
+                create_dir_all(parent).upstream_with_context(&format!(
+                    "Failed to create directory '{}'",
+                    parent.display()
+                ))?;
+            }
+            std::fs::write(&hostname_path, format!("{}\n", hostname))
+                .upstream_with_context(&format!("Failed to write '{}'", hostname_path.display()))?;
+            info!("Staged hostname '{}' for preservation", hostname);
+        }
+        Err(why) => warn!("Not preserving hostname: {}", why),
+    }
+    Ok(())
+}
+
+/// Set up stage1 logging with the given level, logging to stderr and optionally to logfile.
+/// The log file is written unbuffered so it is complete if the migration fails.
+pub(crate) fn init_logging(level: LevelFilter, logfile: Option<&Path>) -> Result<()> {
+    if let Some(level) = level.to_level() {
+        Logger::set_default_level(level);
+    }
+    Logger::set_brief_info(true);
+    Logger::set_color(true);
+
+    if let Some(logfile) = logfile {
+        Logger::set_log_file(&LogDestination::StreamStderr, logfile, false)
+            .upstream_with_context(&format!("Failed to set logging to '{}'", logfile.display()))?;
+    } else {
+        Logger::set_log_dest(&LogDestination::Stderr, NO_STREAM)
+            .upstream_with_context("Failed to set up logging")?;
+    }
+
+    if level == LevelFilter::Off {
+        log::set_max_level(LevelFilter::Off);
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::common::parse_os_release;
     use std::io::copy;
 
+    #[test]
+    fn check_endpoints_reports_each_endpoint() {
+        use std::net::TcpListener;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let open_port = listener.local_addr().unwrap().port();
+        let closed_port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let endpoints = vec![
+            (String::from("127.0.0.1"), closed_port),
+            (String::from("127.0.0.1"), open_port),
+        ];
+        let results = check_endpoints(&endpoints, 1).unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].1, closed_port);
+        assert!(results[0].2.is_err());
+        assert!(results[1].2.is_ok());
+
+        assert_eq!(
+            check_endpoints(&endpoints, 0).unwrap_err().kind(),
+            ErrorKind::InvParam
+        );
+    }
+
     #[test]
     fn test_read_buffer() {
         const BUFFER: [u8; 16] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
@@ -251,4 +1523,145 @@ mod tests {
         copy(&mut read_buffer, &mut buffer).unwrap();
         assert_eq!(&BUFFER[..], buffer.as_slice());
     }
+
+    #[test]
+    fn stages_hostname_unless_preserved() {
+        let staging_dir =
+            std::env::temp_dir().join(format!("takeover_hostname_{}", std::process::id()));
+        create_dir_all(&staging_dir).unwrap();
+        stage_hostname(&staging_dir).unwrap();
+        let staged = read_to_string(path_append(&staging_dir, ETC_HOSTNAME));
+
+        std::fs::write(path_append(&staging_dir, ETC_HOSTNAME), "preserved\n").unwrap();
+        stage_hostname(&staging_dir).unwrap();
+        let preserved = read_to_string(path_append(&staging_dir, ETC_HOSTNAME));
+        std::fs::remove_dir_all(&staging_dir).unwrap();
+
+        assert_eq!(staged.unwrap(), format!("{}\n", read_hostname().unwrap()));
+        assert_eq!(preserved.unwrap(), "preserved\n");
+    }
+
+    #[test]
+    fn test_parse_modules() {
+        const PROC_MODULES: &str = "nvme_core 110592 1 nvme, Live 0x0000000000000000\n\
+                                    loop 36864 0 - Live 0x0000000000000000\n";
+        assert_eq!(parse_proc_modules(PROC_MODULES), vec!["nvme_core", "loop"]);
+        assert_eq!(
+            parse_modules_builtin("kernel/fs/vfat/vfat.ko\nkernel/drivers/usb/usb-common.ko\n"),
+            vec!["vfat", "usb_common"]
+        );
+        assert_eq!(normalize_module_name("nvme-core"), "nvme_core");
+    }
+
+    #[test]
+    fn test_is_balena_os_release() {
+        assert!(is_balena_os_release(&parse_os_release(
+            "ID=\"balena-os\"\nNAME=\"balenaOS\"\nVERSION=\"2.50.1\"\n"
+        )));
+        assert!(is_balena_os_release(&parse_os_release(
+            "NAME=\"Resin OS\"\nID=resin-os\n"
+        )));
+        assert!(!is_balena_os_release(&parse_os_release(
+            "ID=raspbian\nID_LIKE=debian\nNAME=\"Raspbian GNU/Linux\"\n"
+        )));
+    }
+
+    #[test]
+    fn test_parse_hostname() {
+        assert_eq!(parse_hostname("fleet-device-7\n"), Some("fleet-device-7"));
+        assert_eq!(parse_hostname("# comment\n\n  host  \n"), Some("host"));
+        assert_eq!(parse_hostname(" \n\t\n"), None);
+    }
+
+    #[test]
+    fn test_parse_sb_state() {
+        assert_eq!(parse_sb_state("SecureBoot enabled\n"), Some(true));
+        assert_eq!(parse_sb_state("SecureBoot disabled"), Some(false));
+        assert_eq!(parse_sb_state("SecureBoot disabled  \n"), Some(false));
+        assert_eq!(
+            parse_sb_state("SecureBoot validation is enabled"),
+            Some(true)
+        );
+        assert_eq!(parse_sb_state("Secureboot Disabled"), Some(false));
+        assert_eq!(parse_sb_state("  SECUREBOOT ENABLED"), Some(true));
+        assert_eq!(
+            parse_sb_state("SecureBoot enabled\nSecureBoot validation is disabled in shim"),
+            Some(true)
+        );
+        assert_eq!(parse_sb_state("EFI variables are not supported"), None);
+        assert_eq!(parse_sb_state("SecureBoot state unknown"), None);
+    }
+
+    #[test]
+    fn test_cpuinfo_has_hard_float() {
+        const CPUINFO_RPI3: &str = "processor\t: 0\n\
+                                    model name\t: ARMv7 Processor rev 4 (v7l)\n\
+                                    Features\t: half thumb fastmult vfp edsp neon vfpv3 tls vfpv4 idiva idivt vfpd32 lpae evtstrm crc32\n";
+        const CPUINFO_SOFT: &str = "processor\t: 0\n\
+                                    Features\t: swp half thumb fastmult edsp java tls\n";
+        assert!(cpuinfo_has_hard_float(CPUINFO_RPI3));
+        assert!(!cpuinfo_has_hard_float(CPUINFO_SOFT));
+        assert!(!cpuinfo_has_hard_float("processor\t: 0\n"));
+    }
+
+    #[test]
+    fn test_elf_header_arch() {
+        let mut header = [0u8; ELF_HEADER_SIZE];
+        header[0..4].copy_from_slice(&ELF_MAGIC);
+        header[EI_DATA] = ELFDATA2LSB;
+        header[E_MACHINE] = EM_X86_64 as u8;
+        assert!(matches!(elf_header_arch(&header), Ok(OSArch::AMD64)));
+        header[E_MACHINE] = EM_AARCH64 as u8;
+        assert!(matches!(elf_header_arch(&header), Ok(OSArch::ARM64)));
+        header[EI_DATA] = ELFDATA2MSB;
+        header[E_MACHINE] = 0;
+        header[E_MACHINE + 1] = EM_ARM as u8;
+        assert!(matches!(elf_header_arch(&header), Ok(OSArch::ARMHF)));
+        header[E_MACHINE + 1] = 0xFF;
+        assert_eq!(
+            elf_header_arch(&header).unwrap_err().kind(),
+            ErrorKind::UnsupportedArch
+        );
+    }
+
+    #[test]
+    fn test_is_initramfs_cmdline() {
+        assert!(is_initramfs_cmdline(b"/init\0"));
+        assert!(is_initramfs_cmdline(b"/init\0--debug\0"));
+        assert!(!is_initramfs_cmdline(b"/sbin/init\0splash\0"));
+        assert!(!is_initramfs_cmdline(b"/lib/systemd/systemd\0"));
+        assert!(!is_initramfs_cmdline(b""));
+    }
+
+    #[test]
+    fn test_init_system_from_comm() {
+        assert_eq!(init_system_from_comm("systemd\n"), InitSystem::Systemd);
+        assert_eq!(init_system_from_comm("openrc-init\n"), InitSystem::OpenRC);
+        assert_eq!(init_system_from_comm("init\n"), InitSystem::SysV);
+        assert_eq!(init_system_from_comm("busybox\n"), InitSystem::Unknown);
+    }
+
+    #[test]
+    fn test_format_host_port() {
+        assert_eq!(format_host_port("::1", 443), "[::1]:443");
+        assert_eq!(format_host_port("[::1]", 443), "[::1]:443");
+        assert_eq!(format_host_port("fe80::1%eth0", 443), "[fe80::1%eth0]:443");
+        assert_eq!(format_host_port("localhost", 443), "localhost:443");
+    }
+
+    #[test]
+    fn test_resolve_host() {
+        use std::net::{IpAddr, Ipv6Addr};
+        let loopback = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        assert_eq!(resolve_host("::1", 443).unwrap()[0].ip(), loopback);
+        assert_eq!(resolve_host("[::1]", 443).unwrap()[0].ip(), loopback);
+        assert!(!resolve_host("fe80::1%lo", 443).unwrap().is_empty());
+        assert!(!resolve_host("localhost", 443).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_check_tcp_connect_zero_timeout() {
+        let res = check_tcp_connect("127.0.0.1", 80, 0);
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::InvParam);
+    }
 }
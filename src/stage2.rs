@@ -1,7 +1,8 @@
 use std::fs::{
-    copy, create_dir, create_dir_all, read_dir, read_to_string, remove_dir, File, OpenOptions,
+    copy, create_dir, create_dir_all, read_dir, read_to_string, remove_dir, remove_file, rename,
+    File, OpenOptions,
 };
-use std::io::{self, Read, Write};
+use std::io::{Read, Write};
 
 use std::os::unix::io::AsRawFd;
 use std::process::{exit, Command, Stdio};
@@ -9,14 +10,19 @@ use std::thread::sleep;
 use std::time::{Duration, Instant};
 
 use nix::{
-    mount::{mount, umount, MsFlags},
-    unistd::sync,
+    errno::Errno,
+    ioctl_none,
+    mount::{mount, umount, umount2, MntFlags, MsFlags},
+    unistd::{dup2, sync},
 };
 
 use std::path::{Path, PathBuf};
 
 use flate2::read::GzDecoder;
-use libc::{ioctl, LINUX_REBOOT_CMD_RESTART, MS_RDONLY, MS_REMOUNT, SIGKILL, SIGTERM};
+use libc::{
+    posix_fadvise, LINUX_REBOOT_CMD_RESTART, MS_RDONLY, MS_REMOUNT, POSIX_FADV_DONTNEED, SIGKILL,
+    SIGTERM, STDERR_FILENO, STDOUT_FILENO,
+};
 use log::{debug, error, info, trace, warn, Level};
 use mod_logger::{LogDestination, Logger, NO_STREAM};
 
@@ -24,21 +30,28 @@ use crate::common::stage2_config::LogDevice;
 use crate::common::{
     call,
     defs::{
-        IoctlReq, BACKUP_ARCH_NAME, BALENA_BOOT_FSTYPE, BALENA_BOOT_MP, BALENA_BOOT_PART,
-        BALENA_CONFIG_PATH, BALENA_DATA_FSTYPE, BALENA_DATA_PART, BALENA_IMAGE_NAME,
-        BALENA_IMAGE_PATH, BALENA_PART_MP, DD_CMD, DISK_BY_LABEL_PATH, EFIBOOTMGR_CMD, NIX_NONE,
-        OLD_ROOT_MP, STAGE2_CONFIG_NAME, SYSTEM_CONNECTIONS_DIR, SYS_EFI_DIR,
+        BACKUP_ARCH_NAME, BALENA_BOOT_FSTYPE, BALENA_BOOT_MP, BALENA_BOOT_PART, BALENA_CONFIG_PATH,
+        BALENA_DATA_FSTYPE, BALENA_DATA_PART, BALENA_IMAGE_NAME, BALENA_IMAGE_PATH, BALENA_PART_MP,
+        DD_CMD, DISK_BY_LABEL_PATH, EFIBOOTMGR_CMD, NIX_NONE, OLD_ROOT_MP, STAGE2_CONFIG_NAME,
+        SYSTEM_CONNECTIONS_DIR, SYS_EFI_DIR,
     },
+    device_lock::DeviceLock,
     dir_exists,
     disk_util::{Disk, PartInfo, PartitionIterator, DEF_BLOCK_SIZE},
     error::{Error, ErrorKind, Result, ToError},
     file_exists, format_size_with_unit, get_mem_info,
+    image_writer::{
+        check_direct_io, check_image, image_digest, log_progress, sync_device, verify_flash,
+        verify_image, wipe_signatures, write_image_to_device,
+    },
     loop_device::LoopDevice,
     options::Options,
+    partition::read_partitions,
     path_append,
     stage2_config::{Stage2Config, UmountPart},
-    system::{fuser, get_process_infos},
+    system::{copy_dir, fuser, get_process_infos},
 };
+use crate::stage1::block_device_info::mount::{mounts_on_disk, Mount};
 use regex::Regex;
 
 const DD_BLOCK_SIZE: usize = 128 * 1024; // 4_194_304;
@@ -47,9 +60,12 @@ const VALIDATE_MAX_ERR: usize = 20;
 const DO_VALIDATE: bool = false;
 const VALIDATE_BLOCK_SIZE: usize = 64 * 1024; // 4_194_304;
 
-const IOCTL_BLK_RRPART: IoctlReq = 0x1295;
+// boot, rootA, rootB, state, data, the extended container of MBR layouts is not counted
+const BALENA_MIN_PART_COUNT: usize = 5;
 
 const TRANSFER_DIR: &str = "/transfer";
+// directory on the balena data partition preserved files are restored to
+const PRESERVE_DIR: &str = "takeover-preserved";
 
 const S2_XTRA_FS_SIZE: u64 = 10 * 1024 * 1024;
 
@@ -265,6 +281,22 @@ pub(crate) fn read_stage2_config<P: AsRef<Path>>(path_prefix: Option<P>) -> Resu
     }
 }
 
+/// Send stage2 output to the console so failures are visible on the device, init has
+/// redirected it to log files in the takeover directory
+fn redirect_to_console(tty: &Path) -> Result<()> {
+    let console = OpenOptions::new()
+        .write(true)
+        .open(tty)
+        .upstream_with_context(&format!("Failed to open console '{}'", tty.display()))?;
+    for fd in &[STDOUT_FILENO, STDERR_FILENO] {
+        dup2(console.as_raw_fd(), *fd).upstream_with_context(&format!(
+            "Failed to redirect output to console '{}'",
+            tty.display()
+        ))?;
+    }
+    Ok(())
+}
+
 fn setup_logging(log_dev: Option<&LogDevice>) {
     if log_dev.is_some() {
         // Device should have been mounted by stage2-init
@@ -365,6 +397,46 @@ fn kill_procs(log_level: Level) -> Result<()> {
     }
 }
 
+/// Make sure nothing on the target disk is still mounted, lazily unmounting leftovers.
+/// Writing to a disk with mounted partitions corrupts the freshly written data. mounts are
+/// the device backed mounts read from mountinfo, no mounts means everything has been unmounted.
+fn ensure_disk_unmounted(disk: &Path, mounts: &[(PathBuf, Mount)]) -> Result<()> {
+    let mounts = mounts_on_disk(disk, mounts);
+    let mut still_mounted: Vec<String> = Vec::new();
+    for (device, mount) in mounts {
+        warn!(
+            "'{}' is still mounted on '{}', trying lazy unmount",
+            device.display(),
+            mount.get_mountpoint().display()
+        );
+        if let Err(why) = umount2(mount.get_mountpoint(), MntFlags::MNT_DETACH) {
+            warn!(
+                "Failed to lazily unmount '{}', error: {:?}",
+                mount.get_mountpoint().display(),
+                why
+            );
+            still_mounted.push(format!(
+                "'{}' on '{}'",
+                device.display(),
+                mount.get_mountpoint().display()
+            ));
+        }
+    }
+
+    if still_mounted.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!(
+                "Refusing to flash '{}', partitions are still mounted: {}",
+                disk.display(),
+                still_mounted.join(", ")
+            ),
+        ))
+    }
+}
+
 fn unmount_partitions(mountpoints: &[UmountPart]) -> Result<()> {
     for mpoint in mountpoints {
         let mountpoint = path_append(OLD_ROOT_MP, &mpoint.mountpoint);
@@ -418,9 +490,15 @@ fn unmount_partitions(mountpoints: &[UmountPart]) -> Result<()> {
     Ok(())
 }
 
-#[allow(dead_code)]
-fn part_reread(device: &Path) -> Result<()> {
-    // try ioctrl #define BLKRRPART  _IO(0x12,95)	/* re-read partition table */
+// #define BLKRRPART  _IO(0x12,95)	/* re-read partition table */
+ioctl_none!(blk_rrpart, 0x12, 95);
+
+/// Make the kernel re-read the partition table of the whole disk device
+fn reread_partition_table(device: &Path) -> Result<()> {
+    trace!(
+        "reread_partition_table: entered with '{}'",
+        device.display()
+    );
     let device_file = OpenOptions::new()
         .read(true)
         .write(true)
@@ -428,34 +506,150 @@ fn part_reread(device: &Path) -> Result<()> {
         .open(device)
         .upstream_with_context(&format!("Failed to open device '{}'", device.display(),))?;
 
-    let ioctl_res = unsafe { ioctl(device_file.as_raw_fd(), IOCTL_BLK_RRPART) };
-    if ioctl_res == 0 {
-        debug!(
-            "Device BLKRRPART IOCTRL to '{}' returned {}",
-            device.display(),
-            ioctl_res
-        );
+    match unsafe { blk_rrpart(device_file.as_raw_fd()) } {
+        Ok(ioctl_res) => {
+            debug!(
+                "Device BLKRRPART IOCTRL to '{}' returned {}",
+                device.display(),
+                ioctl_res
+            );
+            Ok(())
+        }
+        Err(nix::Error::Sys(Errno::EBUSY)) => Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!(
+                "Device BLKRRPART IOCTRL to '{}' failed, the device is busy, partitions might still be mounted",
+                device.display()
+            ),
+        )),
+        Err(nix::Error::Sys(Errno::EINVAL)) => Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!(
+                "Device BLKRRPART IOCTRL to '{}' failed, the device is not a whole disk device",
+                device.display()
+            ),
+        )),
+        Err(why) => Err(Error::with_all(
+            ErrorKind::Upstream,
+            &format!("Device BLKRRPART IOCTRL to '{}' failed", device.display()),
+            Box::new(why),
+        )),
+    }
+}
+
+/// Write config to config.json in the mounted boot partition dev_root. The file is written to
+/// a temporary file first and renamed so an interrupted write does not leave a truncated file.
+fn write_config_atomic(dev_root: &Path, config: &str) -> Result<()> {
+    if let Err(why) = serde_json::from_str::<serde_json::Value>(config) {
+        return Err(Error::with_all(
+            ErrorKind::InvParam,
+            "Refusing to write config.json, the content is not valid JSON",
+            Box::new(why),
+        ));
+    }
+
+    let target_path = path_append(dev_root, BALENA_CONFIG_PATH);
+    let tmp_path = path_append(dev_root, &format!("{}.tmp", BALENA_CONFIG_PATH));
+    let res = File::create(&tmp_path)
+        .and_then(|mut file| {
+            file.write_all(config.as_bytes())?;
+            file.sync_all()
+        })
+        .and_then(|_| rename(&tmp_path, &target_path))
+        .upstream_with_context(&format!(
+            "Failed to write config.json to '{}'",
+            target_path.display()
+        ));
+    if res.is_err() {
+        let _res = remove_file(&tmp_path);
+    }
+    res
+}
+
+/// Read config.json back from dev_root and compare it to config, fails with
+/// ErrorKind::InvState if the content differs. Call sync first so the content is read back
+/// from the device, not from the page cache
+fn verify_config_json(dev_root: &Path, config: &str) -> Result<()> {
+    let target_path = path_append(dev_root, BALENA_CONFIG_PATH);
+    let mut file = File::open(&target_path)
+        .upstream_with_context(&format!("Failed to open '{}'", target_path.display()))?;
+    // drop cached pages where supported, the data has been synced so this is safe
+    let _res = unsafe { posix_fadvise(file.as_raw_fd(), 0, 0, POSIX_FADV_DONTNEED) };
+    let mut written = Vec::new();
+    file.read_to_end(&mut written)
+        .upstream_with_context(&format!("Failed to read '{}'", target_path.display()))?;
+
+    if written == config.as_bytes() {
+        debug!("verify_config_json: '{}' verified", target_path.display());
         Ok(())
     } else {
         Err(Error::with_context(
-            ErrorKind::Upstream,
+            ErrorKind::InvState,
             &format!(
-                "Device BLKRRPART IOCTRL to '{}' failed with error: {}",
-                device.display(),
-                io::Error::last_os_error()
+                "The content of '{}' does not match what was written, read back {} of {} bytes",
+                target_path.display(),
+                written.len(),
+                config.len()
             ),
         ))
     }
 }
 
+/// Mount the freshly flashed boot partition and write config to its config.json so the device
+/// can register with the balena API
+#[allow(dead_code)]
+pub(crate) fn write_config_json(boot_partition: &Path, config: &str) -> Result<()> {
+    trace!(
+        "write_config_json: entered with '{}'",
+        boot_partition.display()
+    );
+    if !dir_exists(BALENA_BOOT_MP)? {
+        create_dir(BALENA_BOOT_MP).upstream_with_context(&format!(
+            "Failed to create balena-boot mountpoint: '{}'",
+            BALENA_BOOT_MP
+        ))?;
+    }
+
+    mount(
+        Some(boot_partition),
+        BALENA_BOOT_MP,
+        Some(BALENA_BOOT_FSTYPE.as_bytes()),
+        MsFlags::empty(),
+        NIX_NONE,
+    )
+    .upstream_with_context(&format!(
+        "Failed to mount '{}' to '{}'",
+        boot_partition.display(),
+        BALENA_BOOT_MP,
+    ))?;
+
+    let res = write_config_atomic(Path::new(BALENA_BOOT_MP), config).and_then(|_| {
+        sync();
+        verify_config_json(Path::new(BALENA_BOOT_MP), config)
+    });
+
+    umount(BALENA_BOOT_MP).upstream_with_context(&format!(
+        "Failed to unmount '{}' from '{}'",
+        boot_partition.display(),
+        BALENA_BOOT_MP
+    ))?;
+
+    if res.is_ok() {
+        info!(
+            "Successfully wrote config.json to '{}'",
+            boot_partition.display()
+        );
+    }
+    res
+}
+
 fn transfer_boot_files<P: AsRef<Path>>(dev_root: P) -> Result<()> {
     let src_path = path_append(TRANSFER_DIR, BALENA_CONFIG_PATH);
-    let target_path = path_append(dev_root.as_ref(), BALENA_CONFIG_PATH);
-    copy(&src_path, &target_path).upstream_with_context(&format!(
-        "Failed to copy {} to {}",
-        src_path.display(),
-        target_path.display()
-    ))?;
+    let config = read_to_string(&src_path)
+        .upstream_with_context(&format!("Failed to read '{}'", src_path.display()))?;
+    write_config_atomic(dev_root.as_ref(), &config)?;
+    sync();
+    verify_config_json(dev_root.as_ref(), &config)?;
 
     info!("Successfully copied config.json to boot partition",);
 
@@ -518,6 +712,39 @@ fn transfer_boot_files<P: AsRef<Path>>(dev_root: P) -> Result<()> {
     Ok(())
 }
 
+/// Re-read the partition table of the flashed device and check it has the balena OS layout,
+/// MBR or GPT with a FAT boot partition
+fn verify_partition_table(device: &Path) -> Result<()> {
+    trace!(
+        "verify_partition_table: entered with '{}'",
+        device.display()
+    );
+    reread_partition_table(device)?;
+
+    let partitions = read_partitions(device)?;
+    let has_fat_boot = partitions.iter().any(|partition| partition.is_fat());
+    if partitions.len() >= BALENA_MIN_PART_COUNT && has_fat_boot {
+        info!(
+            "Partition table on '{}' verified: {:?}",
+            device.display(),
+            partitions
+        );
+        Ok(())
+    } else {
+        Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!(
+                "Unexpected partition table on '{}', expected at least {} partitions with a FAT boot partition, found {} partitions {:?}, FAT boot partition: {}",
+                device.display(),
+                BALENA_MIN_PART_COUNT,
+                partitions.len(),
+                partitions,
+                has_fat_boot
+            ),
+        ))
+    }
+}
+
 fn get_partition_infos(device: &Path) -> Result<(PartInfo, PartInfo)> {
     let mut disk = Disk::from_drive_file(device, None)?;
     let part_iterator = PartitionIterator::new(&mut disk)?;
@@ -629,7 +856,7 @@ fn efi_setup(device: &Path) -> Result<()> {
     Ok(())
 }
 
-fn raw_mount_balena(device: &Path) -> Result<()> {
+fn raw_mount_balena(device: &Path, preserve_dir: Option<&Path>) -> Result<()> {
     debug!("raw_mount_balena called");
 
     if !dir_exists(BALENA_PART_MP)? {
@@ -695,7 +922,7 @@ fn raw_mount_balena(device: &Path) -> Result<()> {
 
     let backup_path = path_append(TRANSFER_DIR, BACKUP_ARCH_NAME);
 
-    if file_exists(&backup_path) {
+    if file_exists(&backup_path) || preserve_dir.is_some() {
         let byte_offset = data_part.start_lba * DEF_BLOCK_SIZE as u64;
         let size_limit = data_part.num_sectors * DEF_BLOCK_SIZE as u64;
 
@@ -728,20 +955,34 @@ fn raw_mount_balena(device: &Path) -> Result<()> {
             BALENA_PART_MP
         );
 
-        // TODO: copy files
-
-        let target_path = path_append(BALENA_PART_MP, BACKUP_ARCH_NAME);
-        copy(&backup_path, &target_path).upstream_with_context(&format!(
-            "Failed to copy '{}' to '{}'",
-            backup_path.display(),
-            target_path.display()
-        ))?;
+        if file_exists(&backup_path) {
+            let target_path = path_append(BALENA_PART_MP, BACKUP_ARCH_NAME);
+            copy(&backup_path, &target_path).upstream_with_context(&format!(
+                "Failed to copy '{}' to '{}'",
+                backup_path.display(),
+                target_path.display()
+            ))?;
+
+            info!(
+                "copied '{}' to '{}'",
+                backup_path.display(),
+                target_path.display()
+            );
+        }
 
-        info!(
-            "copied '{}' to '{}'",
-            backup_path.display(),
-            target_path.display()
-        );
+        if let Some(preserve_dir) = preserve_dir {
+            let target_path = path_append(BALENA_PART_MP, PRESERVE_DIR);
+            create_dir_all(&target_path).upstream_with_context(&format!(
+                "Failed to create directory '{}'",
+                target_path.display()
+            ))?;
+            copy_dir(preserve_dir, &target_path)?;
+            info!(
+                "copied preserved files from '{}' to '{}'",
+                preserve_dir.display(),
+                target_path.display()
+            );
+        }
 
         sync();
 
@@ -925,9 +1166,29 @@ fn validate(target_path: &Path, image_path: &Path) -> Result<bool> {
     Ok(err_count == 0)
 }
 
-fn flash_external(target_path: &Path, image_path: &Path, dd_cmd: &str) -> FlashState {
+/// Write the image to target_path by piping it to dd. With wipe the signatures of target_path
+/// are removed first, once the image was opened, any failure after that is not recoverable
+/// Remove the signatures from target_path, the caller is expected to hold its DeviceLock
+fn wipe_device(target_path: &Path) -> Result<()> {
+    info!("Wiping signatures from '{}'", target_path.display());
+    wipe_signatures(target_path).upstream_with_context(&format!(
+        "Failed to wipe signatures from '{}'",
+        target_path.display()
+    ))
+}
+
+fn flash_external(target_path: &Path, image_path: &Path, dd_cmd: &str, wipe: bool) -> FlashState {
     let mut fail_res = FlashState::FailRecoverable;
 
+    // held until flashing is done
+    let _lock = match DeviceLock::acquire(target_path) {
+        Ok(lock) => lock,
+        Err(why) => {
+            error!("Flash: {}", why);
+            return fail_res;
+        }
+    };
+
     let mut decoder = GzDecoder::new(match File::open(&image_path) {
         Ok(file) => file,
         Err(why) => {
@@ -940,6 +1201,23 @@ fn flash_external(target_path: &Path, image_path: &Path, dd_cmd: &str) -> FlashS
         }
     });
 
+    if decoder.header().is_none() {
+        error!(
+            "Flash: Image file '{}' is not a valid gzip file",
+            image_path.display()
+        );
+        return fail_res;
+    }
+
+    if wipe {
+        // the partition table is gone from here on
+        fail_res = FlashState::FailNonRecoverable;
+        if let Err(why) = wipe_device(target_path) {
+            error!("{}", why);
+            return fail_res;
+        }
+    }
+
     debug!("invoking dd");
     match Command::new(dd_cmd)
         .args(&[
@@ -1003,13 +1281,17 @@ fn flash_external(target_path: &Path, image_path: &Path, dd_cmd: &str) -> FlashS
                 );
             } else {
                 error!("Failed to retrieve dd stdin");
-                return FlashState::FailRecoverable;
+                return fail_res;
             }
 
             match dd_cmd.wait() {
                 Ok(status) => {
                     if status.success() {
                         info!("dd terminated successfully");
+                        if let Err(why) = sync_device(target_path) {
+                            error!("Failed to sync flashed device, error: {}", why);
+                            return FlashState::FailNonRecoverable;
+                        }
                         FlashState::Success
                     } else {
                         error!("dd terminated with exit code: {:?}", status.code());
@@ -1032,6 +1314,73 @@ fn flash_external(target_path: &Path, image_path: &Path, dd_cmd: &str) -> FlashS
     }
 }
 
+/// Write the image in process with O_DIRECT instead of piping it to dd, the SHA-256 of the
+/// written data is checked against expected_sha256 if given. Fails recoverable if the device
+/// can not be opened for direct IO or the image is unusable, with wipe the signatures of
+/// target_path are removed after these checks
+fn flash_direct(
+    target_path: &Path,
+    image_path: &Path,
+    expected_sha256: Option<&str>,
+    image_size: Option<u64>,
+    wipe: bool,
+) -> FlashState {
+    // held until flashing is done
+    let _lock = match DeviceLock::acquire(target_path) {
+        Ok(lock) => lock,
+        Err(why) => {
+            error!("Flash: {}", why);
+            return FlashState::FailRecoverable;
+        }
+    };
+
+    if let Err(why) = check_direct_io(target_path) {
+        warn!("{}", why);
+        return FlashState::FailRecoverable;
+    }
+
+    if let Err(why) = check_image(image_path) {
+        error!("Flash: {}", why);
+        return FlashState::FailRecoverable;
+    }
+
+    if wipe {
+        if let Err(why) = wipe_device(target_path) {
+            error!("{}", why);
+            return FlashState::FailNonRecoverable;
+        }
+    }
+
+    info!("Flashing '{}' with direct IO", target_path.display());
+    let start_time = Instant::now();
+    match write_image_to_device(
+        image_path,
+        target_path,
+        expected_sha256,
+        image_size,
+        true,
+        log_progress,
+    ) {
+        Ok(bytes_written) => {
+            info!(
+                "Wrote {} to '{}' in {} seconds",
+                format_size_with_unit(bytes_written),
+                target_path.display(),
+                start_time.elapsed().as_secs()
+            );
+            FlashState::Success
+        }
+        Err(why) => {
+            error!(
+                "Failed to flash '{}', error: {}",
+                target_path.display(),
+                why
+            );
+            FlashState::FailNonRecoverable
+        }
+    }
+}
+
 #[allow(clippy::cognitive_complexity)]
 pub fn stage2(opts: &Options) -> ! {
     Logger::set_default_level(opts.s2_log_level());
@@ -1056,6 +1405,15 @@ pub fn stage2(opts: &Options) -> ! {
 
     info!("Stage 2 config was read successfully");
 
+    Logger::flush();
+    match redirect_to_console(&s2_config.tty) {
+        Ok(_) => info!(
+            "Stage 2 output goes to console '{}'",
+            s2_config.tty.display()
+        ),
+        Err(why) => warn!("{}", why),
+    }
+
     setup_logging(s2_config.log_dev());
 
     match kill_procs(opts.s2_log_level()) {
@@ -1087,15 +1445,54 @@ pub fn stage2(opts: &Options) -> ! {
         reboot();
     }
 
-    sync();
+    if let Err(why) = Mount::device_mounts()
+        .and_then(|mounts| ensure_disk_unmounted(&s2_config.flash_dev, &mounts))
+    {
+        error!("{}", why);
+        reboot();
+    }
 
     let image_path = path_append(TRANSFER_DIR, BALENA_IMAGE_PATH);
 
-    match flash_external(
-        &s2_config.flash_dev,
-        &image_path,
-        &format!("/bin/{}", DD_CMD),
-    ) {
+    if let Some(image_sha256) = &s2_config.image_sha256 {
+        if let Err(why) = verify_image(&image_path, image_sha256) {
+            error!("Refusing to flash, error: {}", why);
+            sleep(Duration::from_secs(10));
+            reboot();
+        }
+    }
+
+    sync();
+
+    let flash_state = if s2_config.direct_io {
+        match flash_direct(
+            &s2_config.flash_dev,
+            &image_path,
+            s2_config.image_sha256.as_deref(),
+            s2_config.image_size,
+            s2_config.wipe_signatures,
+        ) {
+            // nothing has been written yet
+            FlashState::FailRecoverable => {
+                warn!("Direct IO failed, flashing with dd");
+                flash_external(
+                    &s2_config.flash_dev,
+                    &image_path,
+                    &format!("/bin/{}", DD_CMD),
+                    s2_config.wipe_signatures,
+                )
+            }
+            flash_state => flash_state,
+        }
+    } else {
+        flash_external(
+            &s2_config.flash_dev,
+            &image_path,
+            &format!("/bin/{}", DD_CMD),
+            s2_config.wipe_signatures,
+        )
+    };
+    match flash_state {
         FlashState::Success => (),
         _ => {
             sleep(Duration::from_secs(10));
@@ -1106,6 +1503,20 @@ pub fn stage2(opts: &Options) -> ! {
     sync();
     sleep(Duration::from_secs(5));
 
+    if s2_config.verify_flash {
+        let digest = match (&s2_config.image_sha256, s2_config.image_size) {
+            (Some(image_sha256), Some(image_size)) => Ok((image_sha256.clone(), image_size)),
+            _ => image_digest(&image_path),
+        };
+        if let Err(why) =
+            digest.and_then(|(digest, size)| verify_flash(&s2_config.flash_dev, &digest, size))
+        {
+            error!("Failed to verify the flashed image, error: {}", why);
+            sleep(Duration::from_secs(10));
+            reboot();
+        }
+    }
+
     if DO_VALIDATE {
         match validate(&s2_config.flash_dev, &image_path) {
             Ok(res) => {
@@ -1128,7 +1539,23 @@ pub fn stage2(opts: &Options) -> ! {
         check_loop_control("Stage2 after flash", "/dev");
     }
 
-    if let Err(why) = raw_mount_balena(&s2_config.flash_dev) {
+    if let Err(why) = verify_partition_table(&s2_config.flash_dev) {
+        if s2_config.ignore_partition_check {
+            warn!(
+                "Failed to verify the flashed partition table, provisioning anyway, error: {}",
+                why
+            );
+        } else {
+            error!(
+                "Failed to verify the flashed partition table, error: {}",
+                why
+            );
+            sleep(Duration::from_secs(10));
+            reboot();
+        }
+    }
+
+    if let Err(why) = raw_mount_balena(&s2_config.flash_dev, s2_config.preserve_dir.as_deref()) {
         error!("Failed to transfer files to balena OS, error: {:?}", why);
     } else {
         info!("Migration succeded successfully");
@@ -1138,3 +1565,58 @@ pub fn stage2(opts: &Options) -> ! {
 
     reboot();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{ensure_disk_unmounted, verify_config_json, write_config_atomic};
+    use crate::common::ErrorKind;
+    use crate::stage1::block_device_info::mount::Mount;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn disk_unmounted_without_device_mounts() {
+        assert!(ensure_disk_unmounted(Path::new("/dev/sda"), &[]).is_ok());
+
+        const MOUNTINFO: &str = "20 1 0:19 / / rw,relatime - tmpfs tmpfs rw\n\
+             21 20 0:4 / /proc rw,nosuid - proc proc rw\n\
+             22 20 0:20 / /sys rw,nosuid - sysfs sysfs rw\n\
+             23 20 8:17 / /mnt/other rw - ext4 /dev/sdb1 rw\n";
+        let mounts: Vec<(PathBuf, Mount)> = Mount::parse_device_mountinfo(MOUNTINFO)
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(mounts.len(), 1);
+        assert!(ensure_disk_unmounted(Path::new("/dev/sda"), &mounts).is_ok());
+    }
+
+    #[test]
+    fn writes_only_valid_config_json() {
+        let dir = std::env::temp_dir().join(format!("takeover_cfg_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let invalid = write_config_atomic(&dir, "{\"deviceType\": ");
+        let valid = write_config_atomic(&dir, "{\"deviceType\":\"raspberrypi4-64\"}");
+        let written = std::fs::read_to_string(dir.join("config.json"));
+        let leftover = dir.join("config.json.tmp").exists();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(invalid.unwrap_err().kind(), ErrorKind::InvParam);
+        assert!(valid.is_ok());
+        assert_eq!(written.unwrap(), "{\"deviceType\":\"raspberrypi4-64\"}");
+        assert!(!leftover);
+    }
+
+    #[test]
+    fn detects_config_json_mismatch() {
+        let dir = std::env::temp_dir().join(format!("takeover_verify_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        write_config_atomic(&dir, "{\"deviceType\":\"raspberrypi4-64\"}").unwrap();
+        let same = verify_config_json(&dir, "{\"deviceType\":\"raspberrypi4-64\"}");
+        let other = verify_config_json(&dir, "{\"deviceType\":\"raspberrypi3\"}");
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(same.is_ok());
+        assert_eq!(other.unwrap_err().kind(), ErrorKind::InvState);
+    }
+}